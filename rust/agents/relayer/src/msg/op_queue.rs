@@ -1,7 +1,9 @@
-use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc};
+use std::{cmp::Reverse, collections::BinaryHeap, sync::Arc, time::Instant};
 
 use derive_new::new;
-use hyperlane_core::{PendingOperation, QueueOperation};
+use hyperlane_core::{
+    InFlightLimiter, OperationStage, PendingOperation, QueueOperation, WriteAheadLog,
+};
 use prometheus::{IntGauge, IntGaugeVec};
 use tokio::sync::{broadcast::Receiver, Mutex};
 use tracing::{debug, info, instrument};
@@ -17,18 +19,77 @@ pub struct OpQueue {
     retry_rx: Arc<Mutex<Receiver<MessageRetryRequest>>>,
     #[new(default)]
     queue: Arc<Mutex<BinaryHeap<Reverse<QueueOperation>>>>,
+    #[new(default)]
+    write_ahead_log: Option<(Arc<WriteAheadLog>, OperationStage)>,
+    #[new(default)]
+    in_flight_limiter: Option<Arc<InFlightLimiter>>,
 }
 
 impl OpQueue {
-    /// Push an element onto the queue and update metrics
+    /// Record operations in `write_ahead_log` as waiting for `stage` when they
+    /// are pushed onto this queue, so they can be recovered after a crash.
+    pub fn with_write_ahead_log(
+        mut self,
+        write_ahead_log: Arc<WriteAheadLog>,
+        stage: OperationStage,
+    ) -> Self {
+        self.write_ahead_log = Some((write_ahead_log, stage));
+        self
+    }
+
+    /// Track the operations in flight to their destination in
+    /// `in_flight_limiter`, which should be shared by the queues of every
+    /// stage. Without one, any number of operations may be in flight.
+    pub fn with_in_flight_limiter(mut self, in_flight_limiter: Arc<InFlightLimiter>) -> Self {
+        self.in_flight_limiter = Some(in_flight_limiter);
+        self
+    }
+
+    /// Take an in-flight slot on `op`'s destination, returning whether it
+    /// holds one afterwards.
+    pub fn try_acquire_in_flight(&self, op: &dyn PendingOperation) -> bool {
+        self.in_flight_limiter.as_ref().map_or(true, |limiter| {
+            limiter.try_acquire(op.destination_domain(), op.id())
+        })
+    }
+
+    /// Give back the in-flight slot held by `op`, if any, e.g. because it
+    /// went back to being prepared.
+    pub fn release_in_flight(&self, op: &dyn PendingOperation) {
+        if let Some(limiter) = &self.in_flight_limiter {
+            limiter.release(op.destination_domain(), op.id());
+        }
+    }
+
+    /// Push an element onto the queue and update metrics. The operation's
+    /// `effective_priority` is fixed as of now until it is next pushed.
     #[instrument(skip(self), ret, fields(queue_label=%self.queue_metrics_label), level = "debug")]
-    pub async fn push(&self, op: QueueOperation) {
+    pub async fn push(&self, mut op: QueueOperation) {
+        op.set_queued_priority(op.effective_priority(Instant::now()));
+        if let Some((write_ahead_log, stage)) = &self.write_ahead_log {
+            write_ahead_log.record(op.as_ref(), Some(*stage)).await;
+        }
         // increment the metric before pushing onto the queue, because we lose ownership afterwards
         self.get_operation_metric(op.as_ref()).inc();
 
         self.queue.lock().await.push(Reverse(op));
     }
 
+    /// Record in the write-ahead log that `op` has left the submitter, e.g.
+    /// because it was confirmed or dropped, so that it isn't recovered, and
+    /// give back its in-flight slot.
+    pub async fn record_finished(&self, op: &dyn PendingOperation) {
+        if let Some((write_ahead_log, _)) = &self.write_ahead_log {
+            write_ahead_log.record(op, None).await;
+        }
+        self.release_in_flight(op);
+    }
+
+    /// The number of operations in the queue
+    pub async fn len(&self) -> usize {
+        self.queue.lock().await.len()
+    }
+
     /// Pop an element from the queue and update metrics
     #[instrument(skip(self), ret, fields(queue_label=%self.queue_metrics_label), level = "debug")]
     pub async fn pop(&mut self) -> Option<QueueOperation> {
@@ -107,8 +168,8 @@ impl OpQueue {
 mod test {
     use super::*;
     use hyperlane_core::{
-        HyperlaneDomain, HyperlaneMessage, KnownHyperlaneDomain, PendingOperationResult,
-        TryBatchAs, TxOutcome, H256, U256,
+        AppContext, ChainResult, HyperlaneDomain, HyperlaneMessage, KnownHyperlaneDomain,
+        OperationKind, PendingOperationResult, TryBatchAs, TxOutcome, H256, U256,
     };
     use std::{
         collections::VecDeque,
@@ -141,6 +202,10 @@ mod test {
             self.id
         }
 
+        fn kind(&self) -> OperationKind {
+            OperationKind::MessageDelivery
+        }
+
         fn reset_attempts(&mut self) {
             self.seconds_to_next_attempt = 0;
         }
@@ -161,7 +226,7 @@ mod test {
             &self.destination_domain
         }
 
-        fn app_context(&self) -> Option<String> {
+        fn app_context(&self) -> Option<AppContext> {
             todo!()
         }
 
@@ -171,7 +236,7 @@ mod test {
 
         /// Submit this operation to the blockchain and report if it was successful
         /// or not.
-        async fn submit(&mut self) {
+        async fn submit(&mut self) -> ChainResult<Option<TxOutcome>> {
             todo!()
         }
 
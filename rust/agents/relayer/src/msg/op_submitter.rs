@@ -1,11 +1,18 @@
+use std::collections::HashSet;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use derive_new::new;
 use futures::future::join_all;
 use futures_util::future::try_join_all;
-use hyperlane_core::total_estimated_cost;
-use prometheus::{IntCounter, IntGaugeVec};
+use hyperlane_core::{
+    batch_is_atomic, check_prepare_gate, dead_letter, drain_ready, partition_batchable,
+    prepare_or_warmup, refresh_all_estimates, resolve_dependencies, submission_result,
+    total_estimated_cost, AppContextRateLimiter, DeadLetterStore, DomainCircuitBreaker,
+    InFlightLimiter, OperationObserver, OperationObservers, ReplayLogRecorder, SelfCheckSampler,
+    WriteAheadLog,
+};
+use prometheus::{Histogram, IntCounter, IntGaugeVec};
 use tokio::sync::broadcast::Sender;
 use tokio::sync::mpsc;
 use tokio::sync::Mutex;
@@ -13,12 +20,13 @@ use tokio::task::JoinHandle;
 use tokio::time::sleep;
 use tokio_metrics::TaskMonitor;
 use tracing::{debug, info_span, instrument, instrument::Instrumented, trace, Instrument};
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
 use hyperlane_base::CoreMetrics;
 use hyperlane_core::{
     BatchItem, ChainCommunicationError, ChainResult, HyperlaneDomain, HyperlaneDomainProtocol,
-    HyperlaneMessage, PendingOperationResult, QueueOperation, TxOutcome,
+    HyperlaneMessage, LogVerbosity, OperationStage, PendingOperation, PendingOperationResult,
+    QueueOperation, TxOutcome, DEFAULT_CONFIRM_POLL_INTERVAL, H256,
 };
 
 use crate::msg::pending_message::CONFIRM_DELAY;
@@ -87,9 +95,84 @@ pub struct SerialSubmitter {
     max_batch_size: u32,
     /// tokio task monitor
     task_monitor: TaskMonitor,
+    /// Where operations that were given up on are recorded
+    dead_letter_store: Arc<dyn DeadLetterStore>,
+    /// Where operation state transitions are recorded for crash recovery
+    write_ahead_log: Arc<WriteAheadLog>,
+    /// Notified of every operation lifecycle transition
+    #[new(default)]
+    observers: OperationObservers,
+    /// Max operations in the submit and confirm stages at once, unlimited if
+    /// `None`
+    #[new(default)]
+    max_in_flight: Option<usize>,
+    /// Max submissions of operations with the same app context per
+    /// `APP_CONTEXT_RATE_LIMIT_WINDOW`, unlimited if `None`
+    #[new(default)]
+    max_submissions_per_app_context: Option<usize>,
+    /// Operations left in flight when the relayer last stopped, by the stage
+    /// they were queued for
+    #[new(default)]
+    recovered: Vec<(QueueOperation, OperationStage)>,
+    /// Whether operations stuck cycling between stages are dead-lettered
+    /// rather than reprepared with their usual backoff
+    #[new(default)]
+    dead_letter_oscillating: bool,
 }
 
 impl SerialSubmitter {
+    /// Notify `observer` of the lifecycle transitions of every operation
+    /// this submitter runs.
+    pub fn with_observer(mut self, observer: Arc<dyn OperationObserver>) -> Self {
+        self.observers.register(observer);
+        self
+    }
+
+    /// Record every prepare, submit and confirm decision of this submitter
+    /// in `recorder`'s `ReplayLog`.
+    pub fn with_replay_log(self, recorder: Arc<ReplayLogRecorder>) -> Self {
+        self.with_observer(recorder)
+    }
+
+    /// Allow at most `max_in_flight` operations to be submitted but not yet
+    /// confirmed at once. Operations over the limit wait in the submit queue.
+    pub fn with_max_in_flight(mut self, max_in_flight: usize) -> Self {
+        self.max_in_flight = Some(max_in_flight);
+        self
+    }
+
+    /// Submit at most `max_submissions` operations of each app context per
+    /// `APP_CONTEXT_RATE_LIMIT_WINDOW`, so that a single application can't
+    /// monopolize the destination. Operations without an app context aren't
+    /// limited.
+    pub fn with_app_context_rate_limit(mut self, max_submissions: usize) -> Self {
+        self.max_submissions_per_app_context = Some(max_submissions);
+        self
+    }
+
+    /// Dead-letter operations that are stuck cycling between stages, see
+    /// `PendingOperation::oscillation_score`, instead of repreparing them
+    /// with their usual backoff.
+    pub fn with_oscillation_dead_lettering(mut self) -> Self {
+        self.dead_letter_oscillating = true;
+        self
+    }
+
+    /// Pick `operations` back up where they were left when the relayer last
+    /// stopped, by the stage they were queued for, e.g. as recovered from the
+    /// write-ahead log. Operations that were waiting to be confirmed go
+    /// straight back to the confirm queue, so they aren't submitted twice.
+    /// The rest are prepared again, since prepared data isn't persisted. The
+    /// first copy of each operation received afterwards, e.g. from a message
+    /// processor rescanning its database, is ignored.
+    pub fn with_recovered_operations(
+        mut self,
+        operations: Vec<(QueueOperation, OperationStage)>,
+    ) -> Self {
+        self.recovered = operations;
+        self
+    }
+
     pub fn spawn(self) -> Instrumented<JoinHandle<()>> {
         let span = info_span!("SerialSubmitter", destination=%self.domain);
         let task_monitor = self.task_monitor.clone();
@@ -107,27 +190,61 @@ impl SerialSubmitter {
             retry_tx,
             max_batch_size,
             task_monitor,
+            dead_letter_store,
+            write_ahead_log,
+            observers,
+            max_in_flight,
+            max_submissions_per_app_context,
+            recovered,
+            dead_letter_oscillating,
         } = self;
+        let in_flight_limiter = Arc::new(InFlightLimiter::new(max_in_flight.unwrap_or(usize::MAX)));
         let prepare_queue = OpQueue::new(
             metrics.submitter_queue_length.clone(),
             "prepare_queue".to_string(),
             Arc::new(Mutex::new(retry_tx.subscribe())),
-        );
+        )
+        .with_write_ahead_log(write_ahead_log.clone(), OperationStage::Prepare)
+        .with_in_flight_limiter(in_flight_limiter.clone());
         let submit_queue = OpQueue::new(
             metrics.submitter_queue_length.clone(),
             "submit_queue".to_string(),
             Arc::new(Mutex::new(retry_tx.subscribe())),
-        );
+        )
+        .with_write_ahead_log(write_ahead_log.clone(), OperationStage::Submit)
+        .with_in_flight_limiter(in_flight_limiter.clone());
         let confirm_queue = OpQueue::new(
             metrics.submitter_queue_length.clone(),
             "confirm_queue".to_string(),
             Arc::new(Mutex::new(retry_tx.subscribe())),
-        );
+        )
+        .with_write_ahead_log(write_ahead_log, OperationStage::Confirm)
+        .with_in_flight_limiter(in_flight_limiter);
+
+        let mut recovered_ids = HashSet::with_capacity(recovered.len());
+        if !recovered.is_empty() {
+            info!(
+                count = recovered.len(),
+                "Recovering operations left in flight"
+            );
+        }
+        for (op, stage) in recovered {
+            recovered_ids.insert(op.id());
+            match stage {
+                OperationStage::Confirm => confirm_queue.push(op).await,
+                OperationStage::Prepare | OperationStage::Submit => prepare_queue.push(op).await,
+            }
+        }
 
         let tasks = [
             tokio::spawn(TaskMonitor::instrument(
                 &task_monitor,
-                receive_task(domain.clone(), rx_prepare, prepare_queue.clone()),
+                receive_task(
+                    domain.clone(),
+                    rx_prepare,
+                    prepare_queue.clone(),
+                    recovered_ids,
+                ),
             )),
             tokio::spawn(TaskMonitor::instrument(
                 &task_monitor,
@@ -138,16 +255,25 @@ impl SerialSubmitter {
                     confirm_queue.clone(),
                     max_batch_size,
                     metrics.clone(),
+                    dead_letter_store.clone(),
+                    observers.clone(),
+                    dead_letter_oscillating,
                 ),
             )),
             tokio::spawn(TaskMonitor::instrument(
                 &task_monitor,
                 submit_task(
                     domain.clone(),
+                    prepare_queue.clone(),
                     submit_queue,
                     confirm_queue.clone(),
                     max_batch_size,
                     metrics.clone(),
+                    dead_letter_store.clone(),
+                    observers.clone(),
+                    max_submissions_per_app_context.map(|max_submissions| {
+                        AppContextRateLimiter::new(max_submissions, APP_CONTEXT_RATE_LIMIT_WINDOW)
+                    }),
                 ),
             )),
             tokio::spawn(TaskMonitor::instrument(
@@ -158,6 +284,8 @@ impl SerialSubmitter {
                     confirm_queue,
                     max_batch_size,
                     metrics,
+                    dead_letter_store,
+                    observers,
                 ),
             )),
         ];
@@ -177,10 +305,26 @@ async fn receive_task(
     domain: HyperlaneDomain,
     mut rx: mpsc::UnboundedReceiver<QueueOperation>,
     prepare_queue: OpQueue,
+    mut recovered_ids: HashSet<H256>,
 ) {
+    let mut self_checks = SelfCheckSampler::default();
     // Pull any messages sent to this submitter
-    while let Some(op) = rx.recv().await {
+    while let Some(mut op) = rx.recv().await {
         trace!(?op, "Received new operation");
+        if recovered_ids.remove(&op.id()) {
+            debug!(?op, "Operation was already recovered, ignoring it");
+            continue;
+        }
+        if let Some(Err(err)) = self_checks.check(op.as_ref()).await {
+            error!(
+                ?err,
+                ?op,
+                kind = ?op.kind(),
+                "Operation self-check failed, the relayer may be misconfigured for this destination"
+            );
+        }
+        let queue_len = prepare_queue.len().await;
+        op.recompute_priority(queue_len.try_into().unwrap_or(u32::MAX));
         // make sure things are getting wired up correctly; if this works in testing it
         // should also be valid in production.
         debug_assert_eq!(*op.destination_domain(), domain);
@@ -188,6 +332,53 @@ async fn receive_task(
     }
 }
 
+/// Consecutive submission failures after which the submitter stops submitting
+/// to its destination for `CIRCUIT_BREAKER_COOLDOWN`.
+const CIRCUIT_BREAKER_FAILURE_THRESHOLD: u32 = 5;
+const CIRCUIT_BREAKER_COOLDOWN: Duration = Duration::from_secs(60);
+
+/// The window over which submissions are counted towards an app context's
+/// rate limit, see `SerialSubmitter::with_app_context_rate_limit`.
+const APP_CONTEXT_RATE_LIMIT_WINDOW: Duration = Duration::from_secs(60);
+
+/// The app context `op` is rate limited by, or `None` if it doesn't have one,
+/// since operations without an app context would otherwise all share a
+/// single limit.
+fn rate_limited_app_context(op: &dyn PendingOperation) -> Option<String> {
+    op.app_context()
+        .is_some()
+        .then(|| op.normalized_app_context())
+}
+
+/// Count a successful submission of `op` towards its app context's rate limit.
+fn record_app_context_submission(
+    rate_limiter: &mut Option<AppContextRateLimiter>,
+    op: &dyn PendingOperation,
+) {
+    if let (Some(rate_limiter), Some(app_context)) =
+        (rate_limiter.as_mut(), rate_limited_app_context(op))
+    {
+        rate_limiter.record_submission(&app_context, Instant::now());
+    }
+}
+
+/// Operations whose `oscillation_score` is above `MAX_OSCILLATION_SCORE` over at
+/// least `MIN_ATTEMPTS_FOR_OSCILLATION` failed attempts are dead-lettered, if
+/// the submitter was configured to with
+/// `SerialSubmitter::with_oscillation_dead_lettering`.
+const MAX_OSCILLATION_SCORE: f64 = 0.8;
+const MIN_ATTEMPTS_FOR_OSCILLATION: usize = 8;
+
+fn is_oscillating(op: &dyn PendingOperation) -> bool {
+    op.attempt_history().len() >= MIN_ATTEMPTS_FOR_OSCILLATION
+        && op.oscillation_score() > MAX_OSCILLATION_SCORE
+}
+
+/// Cost estimates older than this are refreshed by repreparing the operation
+/// before it is submitted.
+const MAX_COST_ESTIMATE_AGE: Duration = Duration::from_secs(5 * 60);
+
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all, fields(%domain))]
 async fn prepare_task(
     domain: HyperlaneDomain,
@@ -196,23 +387,102 @@ async fn prepare_task(
     confirm_queue: OpQueue,
     max_batch_size: u32,
     metrics: SerialSubmitterMetrics,
+    dead_letter_store: Arc<dyn DeadLetterStore>,
+    observers: OperationObservers,
+    dead_letter_oscillating: bool,
 ) {
     // Prepare at most `max_batch_size` ops at a time to avoid getting rate-limited
     let ops_to_prepare = max_batch_size as usize;
     loop {
         // Pop messages here according to the configured batch.
-        let mut batch = prepare_queue.pop_many(ops_to_prepare).await;
+        let batch = prepare_queue.pop_many(ops_to_prepare).await;
         if batch.is_empty() {
             // queue is empty so give some time before checking again to prevent burning CPU
             sleep(Duration::from_millis(100)).await;
             continue;
         }
+        // Operations sent back to be prepared are no longer in flight
+        for op in &batch {
+            prepare_queue.release_in_flight(op.as_ref());
+        }
+        let now = Instant::now();
+        let (expired, batch): (Vec<_>, Vec<_>) =
+            batch.into_iter().partition(|op| op.is_expired(now));
+        for op in expired {
+            info!(?op, "Dropping operation past its hard deadline");
+            observers.on_drop(op.id(), OperationStage::Prepare, "past its hard deadline");
+            prepare_queue.record_finished(op.as_ref()).await;
+            metrics.record_dropped(op.as_ref());
+        }
+        let (oscillating, mut batch): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|op| dead_letter_oscillating && is_oscillating(op.as_ref()));
+        for op in oscillating {
+            dead_letter_operation(
+                op,
+                "stuck oscillating between stages".to_owned(),
+                dead_letter_store.as_ref(),
+                &prepare_queue,
+                &metrics,
+                &observers,
+                OperationStage::Prepare,
+            )
+            .await;
+        }
+        if batch.is_empty() {
+            continue;
+        }
+        let gate_checks = join_all(batch.iter().map(|op| check_prepare_gate(op.as_ref()))).await;
+        let mut ungated = Vec::with_capacity(batch.len());
+        for (op, gate_check) in batch.into_iter().zip(gate_checks) {
+            if matches!(gate_check, PendingOperationResult::NotReady) {
+                trace!(
+                    ?op,
+                    "Operation is waiting for its prepare gate to be delivered"
+                );
+                prepare_queue.push(op).await;
+            } else {
+                ungated.push(op);
+            }
+        }
+        batch = ungated;
+        let resolutions = resolve_dependencies(&mut batch);
+        let mut unblocked = Vec::with_capacity(batch.len());
+        for (op, resolution) in batch.into_iter().zip(resolutions) {
+            match resolution {
+                PendingOperationResult::Drop => {
+                    warn!(?op, "Dropping operation in a dependency cycle");
+                    observers.on_drop(op.id(), OperationStage::Prepare, "in a dependency cycle");
+                    prepare_queue.record_finished(op.as_ref()).await;
+                    metrics.record_dropped(op.as_ref());
+                }
+                PendingOperationResult::NotReady => {
+                    trace!(?op, "Operation is waiting for its dependency");
+                    prepare_queue.push(op).await;
+                }
+                _ => unblocked.push(op),
+            }
+        }
+        batch = unblocked;
+        if batch.is_empty() {
+            // everything is waiting on a dependency or gate, so wait for a little bit
+            sleep(Duration::from_millis(500)).await;
+            continue;
+        }
         let mut task_prep_futures = vec![];
         let op_refs = batch.iter_mut().map(|op| op.as_mut()).collect::<Vec<_>>();
+        let observers_ref = &observers;
         for op in op_refs {
             trace!(?op, "Preparing operation");
             debug_assert_eq!(*op.destination_domain(), domain);
-            task_prep_futures.push(op.prepare());
+            task_prep_futures.push(async move {
+                observers_ref.on_stage_start(op, OperationStage::Prepare);
+                let start = Instant::now();
+                let result = prepare_or_warmup(op, now).await;
+                op.record_stage_timing(OperationStage::Prepare, start.elapsed());
+                op.record_stage_result(OperationStage::Prepare, result.clone());
+                result
+            });
         }
         let res = join_all(task_prep_futures).await;
         let not_ready_count = res
@@ -220,15 +490,18 @@ async fn prepare_task(
             .filter(|r| {
                 matches!(
                     r,
-                    PendingOperationResult::NotReady | PendingOperationResult::Reprepare
+                    PendingOperationResult::NotReady
+                        | PendingOperationResult::Reprepare
+                        | PendingOperationResult::Reschedule(_)
                 )
             })
             .count();
         let batch_len = batch.len();
-        for (op, prepare_result) in batch.into_iter().zip(res.into_iter()) {
+        for (mut op, prepare_result) in batch.into_iter().zip(res.into_iter()) {
+            observers.on_prepare(op.id(), &prepare_result);
             match prepare_result {
                 PendingOperationResult::Success => {
-                    debug!(?op, "Operation prepared");
+                    log_stage_event(&op, "Operation prepared");
                     metrics.ops_prepared.inc();
                     // TODO: push multiple messages at once
                     submit_queue.push(op).await;
@@ -236,17 +509,43 @@ async fn prepare_task(
                 PendingOperationResult::NotReady => {
                     prepare_queue.push(op).await;
                 }
+                PendingOperationResult::Reschedule(at) => {
+                    op.set_next_attempt_at(at);
+                    prepare_queue.push(op).await;
+                }
                 PendingOperationResult::Reprepare => {
                     metrics.ops_failed.inc();
                     prepare_queue.push(op).await;
                 }
+                PendingOperationResult::ReprepareNow(reason) => {
+                    reprepare_now(op, OperationStage::Prepare, reason, &prepare_queue).await;
+                }
                 PendingOperationResult::Drop => {
-                    metrics.ops_dropped.inc();
+                    observers.on_drop(op.id(), OperationStage::Prepare, "dropped on prepare");
+                    prepare_queue.record_finished(op.as_ref()).await;
+                    metrics.record_dropped(op.as_ref());
+                }
+                PendingOperationResult::DeadLetter(reason) => {
+                    dead_letter_operation(
+                        op,
+                        reason,
+                        dead_letter_store.as_ref(),
+                        &prepare_queue,
+                        &metrics,
+                        &observers,
+                        OperationStage::Prepare,
+                    )
+                    .await;
                 }
                 PendingOperationResult::Confirm => {
                     debug!(?op, "Pushing operation to confirm queue");
                     confirm_queue.push(op).await;
                 }
+                PendingOperationResult::ConfirmAfter(blocks) => {
+                    debug!(?op, blocks, "Pushing operation to confirm queue");
+                    op.delay_confirmation_by_blocks(blocks);
+                    confirm_queue.push(op).await;
+                }
             }
         }
         if not_ready_count == batch_len {
@@ -256,49 +555,266 @@ async fn prepare_task(
     }
 }
 
+#[allow(clippy::too_many_arguments)]
 #[instrument(skip_all, fields(%domain))]
 async fn submit_task(
     domain: HyperlaneDomain,
+    prepare_queue: OpQueue,
     mut submit_queue: OpQueue,
     mut confirm_queue: OpQueue,
     max_batch_size: u32,
     metrics: SerialSubmitterMetrics,
+    dead_letter_store: Arc<dyn DeadLetterStore>,
+    observers: OperationObservers,
+    mut rate_limiter: Option<AppContextRateLimiter>,
 ) {
     let recv_limit = max_batch_size as usize;
+    let mut circuit_breaker =
+        DomainCircuitBreaker::new(CIRCUIT_BREAKER_FAILURE_THRESHOLD, CIRCUIT_BREAKER_COOLDOWN);
     loop {
-        let mut batch = submit_queue.pop_many(recv_limit).await;
+        if circuit_breaker.is_open(&domain, Instant::now()) {
+            // Leave the operations in the queue until the destination recovers
+            sleep(Duration::from_secs(1)).await;
+            continue;
+        }
+        // Only a single probe is sent to a destination that just recovered
+        let pop_limit = if circuit_breaker.is_half_open(&domain) {
+            1
+        } else {
+            recv_limit
+        };
+        let popped = submit_queue.pop_many(pop_limit).await;
+        // Operations that were deferred, e.g. because the destination was
+        // congested, wait in the queue until their backoff is over
+        let (mut batch, waiting) = drain_ready(popped, Instant::now());
+        for op in waiting {
+            submit_queue.push(op).await;
+        }
+        let (stale, fresh): (Vec<_>, Vec<_>) = batch
+            .into_iter()
+            .partition(|op| op.is_cost_estimate_stale(MAX_COST_ESTIMATE_AGE));
+        batch = fresh;
+        for op in stale {
+            debug!(?op, "Cost estimate is stale, repreparing operation");
+            prepare_queue.push(op).await;
+        }
+
+        let now = Instant::now();
+        let mut allowed = Vec::with_capacity(batch.len());
+        for mut op in batch {
+            let app_context = rate_limited_app_context(op.as_ref());
+            let is_rate_limited = match (&rate_limiter, &app_context) {
+                (Some(rate_limiter), Some(app_context)) => {
+                    !rate_limiter.should_allow(app_context, now)
+                }
+                _ => false,
+            };
+            if is_rate_limited {
+                debug!(
+                    ?op,
+                    ?app_context,
+                    "App context is rate limited, deferring operation"
+                );
+                op.set_next_attempt_after(APP_CONTEXT_RATE_LIMIT_WINDOW);
+                submit_queue.push(op).await;
+            } else {
+                allowed.push(op);
+            }
+        }
+        batch = allowed;
+
+        // Our own submitted but unconfirmed transactions are the best view of
+        // the destination's mempool that the submitter has
+        let pending_tx_count = confirm_queue.len().await as u64;
+        let mut uncongested = Vec::with_capacity(batch.len());
+        for mut op in batch {
+            match op.congestion_backoff(pending_tx_count) {
+                Some(backoff) => {
+                    debug!(
+                        ?op,
+                        pending_tx_count,
+                        ?backoff,
+                        "Destination is congested, deferring operation"
+                    );
+                    op.set_next_attempt_after(backoff);
+                    submit_queue.push(op).await;
+                }
+                None => uncongested.push(op),
+            }
+        }
+        batch = uncongested;
+
+        let mut within_limit = Vec::with_capacity(batch.len());
+        for op in batch {
+            if submit_queue.try_acquire_in_flight(op.as_ref()) {
+                within_limit.push(op);
+            } else {
+                debug!(?op, "Too many operations in flight, deferring operation");
+                submit_queue.push(op).await;
+            }
+        }
+        batch = within_limit;
+
+        // Operations without a cost estimate are handled according to their
+        // `on_missing_estimate` policy instead of being revalidated, and only
+        // operations that pass revalidation are simulated
+        let revalidations = join_all(batch.iter_mut().map(|op| async move {
+            match op.check_cost_estimate() {
+                PendingOperationResult::Success => match op.revalidate_before_submit().await {
+                    PendingOperationResult::Success => op.check_simulation().await,
+                    result => result,
+                },
+                result => result,
+            }
+        }))
+        .await;
+        let mut revalidated = Vec::with_capacity(batch.len());
+        for (mut op, revalidation) in batch.into_iter().zip(revalidations) {
+            match revalidation {
+                PendingOperationResult::Success => revalidated.push(op),
+                PendingOperationResult::NotReady => {
+                    submit_queue.push(op).await;
+                }
+                PendingOperationResult::Reschedule(at) => {
+                    op.set_next_attempt_at(at);
+                    submit_queue.push(op).await;
+                }
+                PendingOperationResult::Reprepare => {
+                    metrics.ops_failed.inc();
+                    prepare_queue.push(op).await;
+                }
+                PendingOperationResult::ReprepareNow(reason) => {
+                    reprepare_now(op, OperationStage::Submit, reason, &prepare_queue).await;
+                }
+                PendingOperationResult::Drop => {
+                    observers.on_drop(op.id(), OperationStage::Submit, "dropped on revalidation");
+                    submit_queue.record_finished(op.as_ref()).await;
+                    metrics.record_dropped(op.as_ref());
+                }
+                PendingOperationResult::DeadLetter(reason) => {
+                    dead_letter_operation(
+                        op,
+                        reason,
+                        dead_letter_store.as_ref(),
+                        &submit_queue,
+                        &metrics,
+                        &observers,
+                        OperationStage::Submit,
+                    )
+                    .await;
+                }
+                PendingOperationResult::Confirm => {
+                    debug!(?op, "Pushing operation to confirm queue");
+                    confirm_queue.push(op).await;
+                }
+                PendingOperationResult::ConfirmAfter(blocks) => {
+                    debug!(?op, blocks, "Pushing operation to confirm queue");
+                    op.delay_confirmation_by_blocks(blocks);
+                    confirm_queue.push(op).await;
+                }
+            }
+        }
+
+        // Operations that can't be batched are submitted on their own
+        let (mut batch, standalone) = partition_batchable(revalidated);
+        let submitted_standalone = !standalone.is_empty();
+        for op in standalone {
+            submit_single_operation(
+                op,
+                &submit_queue,
+                &mut confirm_queue,
+                &mut circuit_breaker,
+                &mut rate_limiter,
+                &metrics,
+                &observers,
+            )
+            .await;
+        }
 
         match batch.len().cmp(&1) {
             std::cmp::Ordering::Less => {
-                // The queue is empty, so give some time before checking again to prevent burning CPU
-                sleep(Duration::from_millis(100)).await;
+                if !submitted_standalone {
+                    // The queue is empty, so give some time before checking again to prevent burning CPU
+                    sleep(Duration::from_millis(100)).await;
+                }
                 continue;
             }
             std::cmp::Ordering::Equal => {
                 let op = batch.pop().unwrap();
-                submit_single_operation(op, &mut confirm_queue, &metrics).await;
+                submit_single_operation(
+                    op,
+                    &submit_queue,
+                    &mut confirm_queue,
+                    &mut circuit_breaker,
+                    &mut rate_limiter,
+                    &metrics,
+                    &observers,
+                )
+                .await;
             }
             std::cmp::Ordering::Greater => {
                 OperationBatch::new(batch, domain.clone())
-                    .submit(&mut confirm_queue, &metrics)
+                    .submit(
+                        &prepare_queue,
+                        &submit_queue,
+                        &mut confirm_queue,
+                        &mut circuit_breaker,
+                        &mut rate_limiter,
+                        &metrics,
+                        &observers,
+                    )
                     .await;
             }
         }
     }
 }
 
-#[instrument(skip(confirm_queue, metrics), ret, level = "debug")]
+#[instrument(
+    skip(submit_queue, confirm_queue, circuit_breaker, rate_limiter, metrics),
+    ret,
+    level = "debug"
+)]
 async fn submit_single_operation(
     mut op: QueueOperation,
+    submit_queue: &OpQueue,
     confirm_queue: &mut OpQueue,
+    circuit_breaker: &mut DomainCircuitBreaker,
+    rate_limiter: &mut Option<AppContextRateLimiter>,
     metrics: &SerialSubmitterMetrics,
+    observers: &OperationObservers,
 ) {
     let destination = op.destination_domain().clone();
-    op.submit().await;
-    debug!(?op, "Operation submitted");
-    op.set_next_attempt_after(CONFIRM_DELAY);
-    confirm_queue.push(op).await;
+    observers.on_stage_start(op.as_ref(), OperationStage::Submit);
+    let start = Instant::now();
+    let submission = op.submit().await;
+    op.record_stage_timing(OperationStage::Submit, start.elapsed());
+    match submission {
+        Ok(Some(outcome)) => {
+            observers.on_submit(op.id(), Some(&outcome));
+            op.record_stage_result(OperationStage::Submit, submission_result(Some(&outcome)));
+            // Single submissions are their own batch, so the tx estimate is the operation's
+            let estimated_cost = op.get_tx_cost_estimate().unwrap_or_default();
+            op.set_operation_outcome(outcome, estimated_cost);
+            circuit_breaker.record_success(&destination);
+            record_app_context_submission(rate_limiter, op.as_ref());
+            log_stage_event(&op, "Operation submitted");
+        }
+        Ok(None) => {
+            // an idempotent re-submit, which says nothing about the destination's health
+            debug!(
+                ?op,
+                "Operation was already submitted, moving on to confirmation"
+            );
+        }
+        Err(e) => {
+            observers.on_submit(op.id(), None);
+            op.record_stage_result(OperationStage::Submit, submission_result(None));
+            circuit_breaker.record_failure(&destination, Instant::now());
+            error!(error=?e, ?op, "Error when submitting operation");
+        }
+    }
     metrics.ops_submitted.inc();
+    push_after_submission(op, submit_queue, confirm_queue).await;
 
     if matches!(
         destination.domain_protocol(),
@@ -311,6 +827,34 @@ async fn submit_single_operation(
     }
 }
 
+/// Send a submitted operation on to be confirmed, or back to the submit queue if
+/// it still has transactions to send. Operations that skip confirmation are
+/// done once submitted.
+async fn push_after_submission(
+    mut op: QueueOperation,
+    submit_queue: &OpQueue,
+    confirm_queue: &mut OpQueue,
+) {
+    match op.stage_after_submission() {
+        Some(OperationStage::Submit) => {
+            debug!(
+                ?op,
+                remaining_submissions = op.remaining_submissions(),
+                "Operation needs further submissions"
+            );
+            submit_queue.push(op).await;
+        }
+        Some(_) => {
+            op.set_next_attempt_after(CONFIRM_DELAY);
+            confirm_queue.push(op).await;
+        }
+        None => {
+            debug!(?op, "Operation skips confirmation, done after submission");
+            submit_queue.record_finished(op.as_ref()).await;
+        }
+    }
+}
+
 #[instrument(skip_all, fields(%domain))]
 async fn confirm_task(
     domain: HyperlaneDomain,
@@ -318,6 +862,8 @@ async fn confirm_task(
     mut confirm_queue: OpQueue,
     max_batch_size: u32,
     metrics: SerialSubmitterMetrics,
+    dead_letter_store: Arc<dyn DeadLetterStore>,
+    observers: OperationObservers,
 ) {
     let recv_limit = max_batch_size as usize;
     loop {
@@ -337,18 +883,23 @@ async fn confirm_task(
                 prepare_queue.clone(),
                 confirm_queue.clone(),
                 metrics.clone(),
+                dead_letter_store.clone(),
+                &observers,
             )
         });
         let op_results = join_all(futures).await;
         if op_results.iter().all(|op| {
             matches!(
                 op,
-                PendingOperationResult::NotReady | PendingOperationResult::Confirm
+                PendingOperationResult::NotReady
+                    | PendingOperationResult::Confirm
+                    | PendingOperationResult::Reschedule(_)
+                    | PendingOperationResult::ConfirmAfter(_)
             )
         }) {
             // None of the operations are ready, so wait for a little bit
             // before checking again to prevent burning CPU
-            sleep(Duration::from_millis(500)).await;
+            sleep(DEFAULT_CONFIRM_POLL_INTERVAL).await;
         }
     }
 }
@@ -359,31 +910,106 @@ async fn confirm_operation(
     prepare_queue: OpQueue,
     confirm_queue: OpQueue,
     metrics: SerialSubmitterMetrics,
+    dead_letter_store: Arc<dyn DeadLetterStore>,
+    observers: &OperationObservers,
 ) -> PendingOperationResult {
     trace!(?op, "Confirming operation");
     debug_assert_eq!(*op.destination_domain(), domain);
 
-    let operation_result = op.confirm().await;
-    match operation_result {
+    observers.on_stage_start(op.as_ref(), OperationStage::Confirm);
+    let start = Instant::now();
+    let operation_result = op.confirm_with_deadline(start).await;
+    op.record_stage_timing(OperationStage::Confirm, start.elapsed());
+    op.record_stage_result(OperationStage::Confirm, operation_result.clone());
+    observers.on_confirm(op.id(), &operation_result);
+    match &operation_result {
         PendingOperationResult::Success => {
-            debug!(?op, "Operation confirmed");
-            metrics.ops_confirmed.inc();
+            log_stage_event(&op, "Operation confirmed");
+            confirm_queue.record_finished(op.as_ref()).await;
+            metrics.record_confirmed(op.as_ref());
         }
         PendingOperationResult::NotReady | PendingOperationResult::Confirm => {
+            op.schedule_next_confirm(start);
             // TODO: push multiple messages at once
             confirm_queue.push(op).await;
         }
+        PendingOperationResult::Reschedule(at) => {
+            op.set_next_attempt_at(*at);
+            confirm_queue.push(op).await;
+        }
+        PendingOperationResult::ConfirmAfter(blocks) => {
+            op.delay_confirmation_by_blocks(*blocks);
+            confirm_queue.push(op).await;
+        }
         PendingOperationResult::Reprepare => {
             metrics.ops_failed.inc();
             prepare_queue.push(op).await;
         }
+        PendingOperationResult::ReprepareNow(reason) => {
+            reprepare_now(op, OperationStage::Confirm, reason.clone(), &prepare_queue).await;
+        }
         PendingOperationResult::Drop => {
-            metrics.ops_dropped.inc();
+            observers.on_drop(op.id(), OperationStage::Confirm, "dropped on confirm");
+            confirm_queue.record_finished(op.as_ref()).await;
+            metrics.record_dropped(op.as_ref());
+        }
+        PendingOperationResult::DeadLetter(reason) => {
+            dead_letter_operation(
+                op,
+                reason.clone(),
+                dead_letter_store.as_ref(),
+                &confirm_queue,
+                &metrics,
+                observers,
+                OperationStage::Confirm,
+            )
+            .await;
         }
     }
     operation_result
 }
 
+/// Send `op` back from `stage` to be prepared again, ahead of operations that
+/// are backing off.
+async fn reprepare_now(
+    mut op: QueueOperation,
+    stage: OperationStage,
+    reason: String,
+    prepare_queue: &OpQueue,
+) {
+    debug!(?op, reason, "Repreparing operation right away");
+    op.push_attempt(stage, reason);
+    op.clear_next_attempt();
+    prepare_queue.push(op).await;
+}
+
+/// Log that `op` completed a stage, at a level depending on its `log_verbosity`.
+fn log_stage_event(op: &QueueOperation, event: &str) {
+    match op.log_verbosity() {
+        LogVerbosity::Quiet => {}
+        LogVerbosity::Normal => debug!(?op, "{event}"),
+        LogVerbosity::Verbose => info!(?op, "{event}"),
+    }
+}
+
+/// Record an operation that was given up on in the dead-letter store, then drop it
+/// from `queue`, the one it was popped from.
+async fn dead_letter_operation(
+    op: QueueOperation,
+    reason: String,
+    store: &dyn DeadLetterStore,
+    queue: &OpQueue,
+    metrics: &SerialSubmitterMetrics,
+    observers: &OperationObservers,
+    stage: OperationStage,
+) {
+    observers.on_drop(op.id(), stage, &reason);
+    let record = dead_letter(op.as_ref(), reason, store);
+    warn!(?record, "Moved operation to the dead-letter store");
+    queue.record_finished(op.as_ref()).await;
+    metrics.record_dropped(op.as_ref());
+}
+
 #[derive(Debug, Clone)]
 pub struct SerialSubmitterMetrics {
     submitter_queue_length: IntGaugeVec,
@@ -392,6 +1018,8 @@ pub struct SerialSubmitterMetrics {
     ops_confirmed: IntCounter,
     ops_failed: IntCounter,
     ops_dropped: IntCounter,
+    confirmed_time_in_queue: Histogram,
+    dropped_time_in_queue: Histogram,
 }
 
 impl SerialSubmitterMetrics {
@@ -414,27 +1042,99 @@ impl SerialSubmitterMetrics {
             ops_dropped: metrics
                 .operations_processed_count()
                 .with_label_values(&["dropped", destination]),
+            confirmed_time_in_queue: metrics
+                .operation_time_in_queue()
+                .with_label_values(&["confirmed", destination]),
+            dropped_time_in_queue: metrics
+                .operation_time_in_queue()
+                .with_label_values(&["dropped", destination]),
         }
     }
+
+    /// Count an operation that was confirmed, and how long it was queued for.
+    fn record_confirmed(&self, op: &dyn PendingOperation) {
+        self.ops_confirmed.inc();
+        self.confirmed_time_in_queue
+            .observe(op.time_in_queue(Instant::now()).as_secs_f64());
+    }
+
+    /// Count an operation that was dropped, and how long it was queued for.
+    fn record_dropped(&self, op: &dyn PendingOperation) {
+        self.ops_dropped.inc();
+        self.dropped_time_in_queue
+            .observe(op.time_in_queue(Instant::now()).as_secs_f64());
+    }
 }
 
 #[derive(new, Debug)]
 struct OperationBatch {
     operations: Vec<QueueOperation>,
-    #[allow(dead_code)]
     domain: HyperlaneDomain,
 }
 
 impl OperationBatch {
-    async fn submit(self, confirm_queue: &mut OpQueue, metrics: &SerialSubmitterMetrics) {
-        match self.try_submit_as_batch(metrics).await {
+    async fn submit(
+        mut self,
+        prepare_queue: &OpQueue,
+        submit_queue: &OpQueue,
+        confirm_queue: &mut OpQueue,
+        circuit_breaker: &mut DomainCircuitBreaker,
+        rate_limiter: &mut Option<AppContextRateLimiter>,
+        metrics: &SerialSubmitterMetrics,
+        observers: &OperationObservers,
+    ) {
+        // Refresh estimates so the batch's total estimated cost is accurate
+        let refreshed_estimates = refresh_all_estimates(&mut self.operations).await;
+        for (op, refreshed) in self.operations.iter().zip(refreshed_estimates) {
+            if let Err(e) = refreshed {
+                warn!(error=?e, ?op, "Error when refreshing cost estimate");
+            }
+        }
+        let is_atomic = batch_is_atomic(&self.operations);
+        for op in &self.operations {
+            observers.on_stage_start(op.as_ref(), OperationStage::Submit);
+        }
+        let start = Instant::now();
+        let submission = self.try_submit_as_batch(metrics).await;
+        let elapsed = start.elapsed();
+        match &submission {
+            Ok(_) => circuit_breaker.record_success(&self.domain),
+            Err(_) => circuit_breaker.record_failure(&self.domain, Instant::now()),
+        }
+        match submission {
             Ok(outcome) => {
                 info!(outcome=?outcome, batch_size=self.operations.len(), batch=?self.operations, "Submitted transaction batch");
                 let total_estimated_cost = total_estimated_cost(&self.operations);
+                let reverted_atomically = is_atomic && !outcome.executed;
+                if reverted_atomically {
+                    warn!(outcome=?outcome, batch=?self.operations, "Atomic batch reverted. Repreparing all operations in the batch.");
+                }
                 for mut op in self.operations {
+                    op.record_stage_timing(OperationStage::Submit, elapsed);
+                    observers.on_submit(op.id(), Some(&outcome));
+                    op.record_stage_result(
+                        OperationStage::Submit,
+                        submission_result(Some(&outcome)),
+                    );
+                    record_app_context_submission(rate_limiter, op.as_ref());
                     op.set_operation_outcome(outcome.clone(), total_estimated_cost);
-                    op.set_next_attempt_after(CONFIRM_DELAY);
-                    confirm_queue.push(op).await;
+                    if reverted_atomically {
+                        metrics.ops_failed.inc();
+                        prepare_queue.push(op).await;
+                    } else {
+                        push_after_submission(op, submit_queue, confirm_queue).await;
+                    }
+                }
+                return;
+            }
+            Err(e) if is_atomic => {
+                // Submitting the operations one by one would break atomicity
+                warn!(error=?e, batch=?self.operations, "Error when submitting atomic batch. Repreparing all operations in the batch.");
+                for mut op in self.operations {
+                    observers.on_submit(op.id(), None);
+                    op.record_stage_result(OperationStage::Submit, submission_result(None));
+                    metrics.ops_failed.inc();
+                    prepare_queue.push(op).await;
                 }
                 return;
             }
@@ -442,7 +1142,15 @@ impl OperationBatch {
                 warn!(error=?e, batch=?self.operations, "Error when submitting batch. Falling back to serial submission.");
             }
         }
-        self.submit_serially(confirm_queue, metrics).await;
+        self.submit_serially(
+            submit_queue,
+            confirm_queue,
+            circuit_breaker,
+            rate_limiter,
+            metrics,
+            observers,
+        )
+        .await;
     }
 
     #[instrument(skip(metrics), ret, level = "debug")]
@@ -467,9 +1175,821 @@ impl OperationBatch {
         Ok(outcome)
     }
 
-    async fn submit_serially(self, confirm_queue: &mut OpQueue, metrics: &SerialSubmitterMetrics) {
+    async fn submit_serially(
+        self,
+        submit_queue: &OpQueue,
+        confirm_queue: &mut OpQueue,
+        circuit_breaker: &mut DomainCircuitBreaker,
+        rate_limiter: &mut Option<AppContextRateLimiter>,
+        metrics: &SerialSubmitterMetrics,
+        observers: &OperationObservers,
+    ) {
         for op in self.operations.into_iter() {
-            submit_single_operation(op, confirm_queue, metrics).await;
+            submit_single_operation(
+                op,
+                submit_queue,
+                confirm_queue,
+                circuit_breaker,
+                rate_limiter,
+                metrics,
+                observers,
+            )
+            .await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicU32, Ordering};
+
+    use super::*;
+    use hyperlane_core::{
+        AppContext, AttemptHistory, DeadLetterRecord, FixedPointNumber, KnownHyperlaneDomain,
+        OperationKind, TryBatchAs, DEFAULT_BLOCK_TIME, H512, U256,
+    };
+    use prometheus::Registry;
+
+    #[derive(Debug)]
+    struct MockOperation {
+        id: H256,
+        destination_domain: HyperlaneDomain,
+        next_attempt_after: Option<Instant>,
+        retries: u32,
+        reorg_depth_required: u32,
+        confirmations: u32,
+        already_submitted: bool,
+        prepare_result: PendingOperationResult,
+        revalidation_result: PendingOperationResult,
+        simulation_passes: bool,
+        /// Returned by `confirm` instead of observing another block, if set
+        confirm_result: Option<PendingOperationResult>,
+        /// Shared with the test, since the operation moves into the queues
+        submissions: Arc<AtomicU32>,
+        submissions_required: u32,
+        skip_confirmation: bool,
+        backoff_per_pending_tx: Option<Duration>,
+        app_context: Option<AppContext>,
+        attempt_history: AttemptHistory,
+    }
+
+    impl MockOperation {
+        fn new() -> Self {
+            Self {
+                id: H256::random(),
+                destination_domain: KnownHyperlaneDomain::Arbitrum.into(),
+                next_attempt_after: None,
+                retries: 0,
+                reorg_depth_required: 1,
+                confirmations: 0,
+                already_submitted: false,
+                prepare_result: PendingOperationResult::Success,
+                revalidation_result: PendingOperationResult::Success,
+                simulation_passes: true,
+                confirm_result: None,
+                submissions: Default::default(),
+                submissions_required: 1,
+                skip_confirmation: false,
+                backoff_per_pending_tx: None,
+                app_context: None,
+                attempt_history: AttemptHistory::default(),
+            }
+        }
+    }
+
+    impl TryBatchAs<HyperlaneMessage> for MockOperation {}
+
+    #[async_trait::async_trait]
+    impl PendingOperation for MockOperation {
+        fn id(&self) -> H256 {
+            self.id
+        }
+
+        fn kind(&self) -> OperationKind {
+            OperationKind::MessageDelivery
+        }
+
+        fn priority(&self) -> u32 {
+            0
+        }
+
+        fn origin_domain_id(&self) -> u32 {
+            KnownHyperlaneDomain::Ethereum as u32
+        }
+
+        fn destination_domain(&self) -> &HyperlaneDomain {
+            &self.destination_domain
+        }
+
+        fn app_context(&self) -> Option<AppContext> {
+            self.app_context.clone()
+        }
+
+        async fn prepare(&mut self) -> PendingOperationResult {
+            self.prepare_result.clone()
+        }
+
+        async fn revalidate_before_submit(&mut self) -> PendingOperationResult {
+            self.revalidation_result.clone()
+        }
+
+        async fn simulate(&mut self) -> ChainResult<bool> {
+            Ok(self.simulation_passes)
+        }
+
+        async fn submit(&mut self) -> ChainResult<Option<TxOutcome>> {
+            if self.already_submitted {
+                return Ok(None);
+            }
+            self.submissions.fetch_add(1, Ordering::SeqCst);
+            Ok(Some(TxOutcome {
+                transaction_id: H512::random(),
+                executed: true,
+                gas_used: U256::zero(),
+                gas_price: FixedPointNumber::zero(),
+            }))
+        }
+
+        fn remaining_submissions(&self) -> u32 {
+            self.submissions_required
+                .saturating_sub(self.submissions.load(Ordering::SeqCst))
+        }
+
+        fn skip_confirmation(&self) -> bool {
+            self.skip_confirmation
+        }
+
+        fn set_submission_outcome(&mut self, _outcome: TxOutcome) {}
+
+        fn get_tx_cost_estimate(&self) -> Option<U256> {
+            Some(U256::zero())
+        }
+
+        fn congestion_backoff(&self, pending_tx_count: u64) -> Option<Duration> {
+            let per_tx = self.backoff_per_pending_tx?;
+            (pending_tx_count > 0).then(|| per_tx * pending_tx_count as u32)
+        }
+
+        /// Each call observes one more block on top of the submission.
+        async fn confirm(&mut self) -> PendingOperationResult {
+            if let Some(result) = &self.confirm_result {
+                return result.clone();
+            }
+            self.confirmations += 1;
+            PendingOperationResult::Success
+        }
+
+        fn reorg_depth_required(&self) -> u32 {
+            self.reorg_depth_required
+        }
+
+        fn current_confirmation_depth(&self) -> Option<u32> {
+            (self.confirmations > 0).then_some(self.confirmations)
+        }
+
+        fn set_operation_outcome(
+            &mut self,
+            _submission_outcome: TxOutcome,
+            _submission_estimated_cost: U256,
+        ) {
+        }
+
+        fn next_attempt_after(&self) -> Option<Instant> {
+            self.next_attempt_after
+        }
+
+        fn set_next_attempt_after(&mut self, delay: Duration) {
+            self.next_attempt_after = Some(Instant::now() + delay);
+        }
+
+        fn reset_attempts(&mut self) {
+            self.retries = 0;
+            self.next_attempt_after = None;
+        }
+
+        fn retry_count(&self) -> u32 {
+            self.retries
+        }
+
+        fn attempt_history(&self) -> &[(Instant, OperationStage, String)] {
+            self.attempt_history.entries()
+        }
+
+        fn push_attempt(&mut self, stage: OperationStage, reason: String) {
+            self.attempt_history.push(Instant::now(), stage, reason);
+        }
+
+        fn set_retries(&mut self, retries: u32) {
+            self.retries = retries;
+        }
+    }
+
+    #[derive(Debug, Default)]
+    struct MockDeadLetterStore(std::sync::Mutex<Vec<DeadLetterRecord>>);
+
+    impl DeadLetterStore for MockDeadLetterStore {
+        fn store_dead_letter(&self, record: &DeadLetterRecord) -> eyre::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    /// The state shared by the tasks of a `SerialSubmitter`, for driving its
+    /// stages one at a time.
+    struct TestSubmitter {
+        domain: HyperlaneDomain,
+        metrics: SerialSubmitterMetrics,
+        prepare_queue: OpQueue,
+        submit_queue: OpQueue,
+        confirm_queue: OpQueue,
+        dead_letter_store: Arc<MockDeadLetterStore>,
+        observers: OperationObservers,
+        rate_limiter: Option<AppContextRateLimiter>,
+        dead_letter_oscillating: bool,
+    }
+
+    impl TestSubmitter {
+        fn new() -> Self {
+            let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+            let core_metrics = CoreMetrics::new("dummy_relayer", 37582, Registry::new()).unwrap();
+            let metrics = SerialSubmitterMetrics::new(&core_metrics, &domain);
+            let queue = |label: &str| {
+                let (_, retry_rx) = tokio::sync::broadcast::channel(1);
+                OpQueue::new(
+                    metrics.submitter_queue_length.clone(),
+                    label.to_owned(),
+                    Arc::new(Mutex::new(retry_rx)),
+                )
+            };
+            Self {
+                prepare_queue: queue("prepare_queue"),
+                submit_queue: queue("submit_queue"),
+                confirm_queue: queue("confirm_queue"),
+                domain,
+                metrics,
+                dead_letter_store: Default::default(),
+                observers: Default::default(),
+                rate_limiter: None,
+                dead_letter_oscillating: false,
+            }
+        }
+
+        fn spawn_prepare_task(&self) -> JoinHandle<()> {
+            tokio::spawn(prepare_task(
+                self.domain.clone(),
+                self.prepare_queue.clone(),
+                self.submit_queue.clone(),
+                self.confirm_queue.clone(),
+                1,
+                self.metrics.clone(),
+                self.dead_letter_store.clone(),
+                self.observers.clone(),
+                self.dead_letter_oscillating,
+            ))
+        }
+
+        fn spawn_submit_task(&self) -> JoinHandle<()> {
+            tokio::spawn(submit_task(
+                self.domain.clone(),
+                self.prepare_queue.clone(),
+                self.submit_queue.clone(),
+                self.confirm_queue.clone(),
+                1,
+                self.metrics.clone(),
+                self.dead_letter_store.clone(),
+                self.observers.clone(),
+                self.rate_limiter.clone(),
+            ))
+        }
+
+        async fn submit(&mut self, op: QueueOperation) {
+            let mut circuit_breaker = DomainCircuitBreaker::new(
+                CIRCUIT_BREAKER_FAILURE_THRESHOLD,
+                CIRCUIT_BREAKER_COOLDOWN,
+            );
+            submit_single_operation(
+                op,
+                &self.submit_queue,
+                &mut self.confirm_queue,
+                &mut circuit_breaker,
+                &mut self.rate_limiter,
+                &self.metrics,
+                &self.observers,
+            )
+            .await;
+        }
+
+        async fn confirm(&self, op: QueueOperation) -> PendingOperationResult {
+            confirm_operation(
+                op,
+                self.domain.clone(),
+                self.prepare_queue.clone(),
+                self.confirm_queue.clone(),
+                self.metrics.clone(),
+                self.dead_letter_store.clone(),
+                &self.observers,
+            )
+            .await
+        }
+    }
+
+    /// Wait for a task to push an operation onto `queue`, and pop it.
+    async fn next_pushed(queue: &mut OpQueue) -> QueueOperation {
+        tokio::time::timeout(Duration::from_secs(10), async {
+            loop {
+                // let the spawned tasks run first
+                sleep(Duration::from_millis(10)).await;
+                if let Some(op) = queue.pop().await {
+                    return op;
+                }
+            }
+        })
+        .await
+        .expect("no operation was pushed")
+    }
+
+    #[tokio::test]
+    async fn test_confirm_operation_waits_for_reorg_depth() {
+        let mut submitter = TestSubmitter::new();
+        let mut op = MockOperation::new();
+        op.reorg_depth_required = 2;
+
+        let result = submitter.confirm(Box::new(op)).await;
+        assert_eq!(result, PendingOperationResult::NotReady);
+        let op = submitter.confirm_queue.pop().await.unwrap();
+        assert_eq!(op.current_confirmation_depth(), Some(1));
+
+        let result = submitter.confirm(op).await;
+        assert_eq!(result, PendingOperationResult::Success);
+        assert!(submitter.confirm_queue.pop().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_resubmitting_does_not_trip_circuit_breaker() {
+        let mut submitter = TestSubmitter::new();
+        let mut circuit_breaker = DomainCircuitBreaker::new(1, CIRCUIT_BREAKER_COOLDOWN);
+        let mut op = MockOperation::new();
+        op.already_submitted = true;
+
+        submit_single_operation(
+            Box::new(op),
+            &submitter.submit_queue,
+            &mut submitter.confirm_queue,
+            &mut circuit_breaker,
+            &mut None,
+            &submitter.metrics,
+            &submitter.observers,
+        )
+        .await;
+        assert!(!circuit_breaker.is_open(&submitter.domain, Instant::now()));
+        // the operation still goes on to be confirmed
+        assert!(submitter.confirm_queue.pop().await.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_replay_log_records_submitter_decisions() {
+        let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let core_metrics = CoreMetrics::new("dummy_relayer", 37582, Registry::new()).unwrap();
+        let metrics = SerialSubmitterMetrics::new(&core_metrics, &domain);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (retry_tx, _) = tokio::sync::broadcast::channel(1);
+        let wal_path = std::env::temp_dir().join(format!("wal-{:x}", H256::random()));
+        let recorder = Arc::new(ReplayLogRecorder::default());
+        let handle = SerialSubmitter::new(
+            domain,
+            rx,
+            retry_tx,
+            metrics,
+            1,
+            TaskMonitor::new(),
+            Arc::new(MockDeadLetterStore::default()),
+            Arc::new(WriteAheadLog::open(&wal_path).unwrap()),
+        )
+        .with_replay_log(recorder.clone())
+        .spawn();
+
+        let op = MockOperation::new();
+        let id = op.id;
+        tx.send(Box::new(op) as QueueOperation).unwrap();
+
+        let confirmed = |log: &hyperlane_core::ReplayLog| {
+            log.entries().iter().any(|entry| {
+                entry.stage == OperationStage::Confirm
+                    && entry.result == PendingOperationResult::Success
+            })
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !confirmed(&recorder.replay_log()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("operation was not confirmed");
+        handle.into_inner().abort();
+        let _ = std::fs::remove_file(&wal_path);
+
+        let log = recorder.replay_log();
+        assert!(log.entries().iter().all(|entry| entry.operation_id == id));
+        let stages = log
+            .entries()
+            .iter()
+            .map(|entry| entry.stage)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stages,
+            vec![
+                OperationStage::Prepare,
+                OperationStage::Submit,
+                OperationStage::Confirm
+            ]
+        );
+        // decisions are recorded against the state the operation was in
+        assert_ne!(log.entries()[0].input_state_hash, H256::zero());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_recovered_operations_resume_at_their_stage() {
+        let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let core_metrics = CoreMetrics::new("dummy_relayer", 37582, Registry::new()).unwrap();
+        let metrics = SerialSubmitterMetrics::new(&core_metrics, &domain);
+        let (tx, rx) = mpsc::unbounded_channel();
+        let (retry_tx, _) = tokio::sync::broadcast::channel(1);
+        let wal_path = std::env::temp_dir().join(format!("wal-{:x}", H256::random()));
+        let recorder = Arc::new(ReplayLogRecorder::default());
+        let recovered = MockOperation::new();
+        let recovered_id = recovered.id;
+        let handle = SerialSubmitter::new(
+            domain,
+            rx,
+            retry_tx,
+            metrics,
+            1,
+            TaskMonitor::new(),
+            Arc::new(MockDeadLetterStore::default()),
+            Arc::new(WriteAheadLog::open(&wal_path).unwrap()),
+        )
+        .with_replay_log(recorder.clone())
+        .with_recovered_operations(vec![(
+            Box::new(recovered) as QueueOperation,
+            OperationStage::Confirm,
+        )])
+        .spawn();
+
+        // the copy a message processor sends after a restart is ignored
+        let duplicate = MockOperation {
+            id: recovered_id,
+            ..MockOperation::new()
+        };
+        tx.send(Box::new(duplicate) as QueueOperation).unwrap();
+        let fresh = MockOperation::new();
+        let fresh_id = fresh.id;
+        tx.send(Box::new(fresh) as QueueOperation).unwrap();
+
+        let confirmed = |log: &hyperlane_core::ReplayLog, id: H256| {
+            log.entries().iter().any(|entry| {
+                entry.operation_id == id
+                    && entry.stage == OperationStage::Confirm
+                    && entry.result == PendingOperationResult::Success
+            })
+        };
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while !confirmed(&recorder.replay_log(), fresh_id) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("operation was not confirmed");
+        handle.into_inner().abort();
+        let _ = std::fs::remove_file(&wal_path);
+
+        // the recovered operation went straight to confirmation, only once
+        let log = recorder.replay_log();
+        let stages = log
+            .entries()
+            .iter()
+            .filter(|entry| entry.operation_id == recovered_id)
+            .map(|entry| entry.stage)
+            .collect::<Vec<_>>();
+        assert_eq!(stages, vec![OperationStage::Confirm]);
+    }
+
+    #[tokio::test]
+    async fn test_reprepare_now_keeps_retry_count() {
+        let mut submitter = TestSubmitter::new();
+        let mut op = MockOperation::new();
+        op.retries = 3;
+        op.set_next_attempt_after(Duration::from_secs(60));
+        op.confirm_result = Some(PendingOperationResult::ReprepareNow(
+            "dependency available".to_owned(),
+        ));
+
+        submitter.confirm(Box::new(op)).await;
+        let op = submitter.prepare_queue.pop().await.unwrap();
+        assert_eq!(op.retry_count(), 3);
+        assert!(op.next_attempt_after().unwrap() <= Instant::now());
+    }
+
+    #[tokio::test]
+    async fn test_confirm_after_delays_next_attempt() {
+        let mut submitter = TestSubmitter::new();
+        let mut op = MockOperation::new();
+        op.confirm_result = Some(PendingOperationResult::ConfirmAfter(3));
+
+        let before = Instant::now();
+        submitter.confirm(Box::new(op)).await;
+        let op = submitter.confirm_queue.pop().await.unwrap();
+        let next_attempt_after = op.next_attempt_after().unwrap();
+        assert!(next_attempt_after >= before + DEFAULT_BLOCK_TIME * 3);
+        assert!(next_attempt_after <= Instant::now() + DEFAULT_BLOCK_TIME * 3);
+    }
+
+    #[tokio::test]
+    async fn test_confirm_polls_are_spaced_by_interval() {
+        let mut submitter = TestSubmitter::new();
+        let mut op: QueueOperation = Box::new(MockOperation {
+            reorg_depth_required: 5,
+            ..MockOperation::new()
+        });
+        let interval = op.confirm_poll_interval();
+        assert_eq!(interval, DEFAULT_CONFIRM_POLL_INTERVAL);
+
+        for _ in 0..2 {
+            let before = Instant::now();
+            let result = submitter.confirm(op).await;
+            assert_eq!(result, PendingOperationResult::NotReady);
+            op = submitter.confirm_queue.pop().await.unwrap();
+            let next_attempt_after = op.next_attempt_after().unwrap();
+            assert!(next_attempt_after >= before + interval);
+            assert!(next_attempt_after <= Instant::now() + interval);
+        }
+
+        // a backoff scheduled further out is kept
+        op.set_next_attempt_after(interval * 10);
+        let backoff = op.next_attempt_after();
+        submitter.confirm(op).await;
+        let op = submitter.confirm_queue.pop().await.unwrap();
+        assert_eq!(op.next_attempt_after(), backoff);
+    }
+
+    #[tokio::test]
+    async fn test_operation_with_multiple_submissions() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            submissions_required: 2,
+            ..MockOperation::new()
+        };
+        let submissions = op.submissions.clone();
+
+        // the operation goes back to be submitted until nothing remains
+        submitter.submit(Box::new(op)).await;
+        assert!(submitter.confirm_queue.pop().await.is_none());
+        let op = submitter.submit_queue.pop().await.unwrap();
+        submitter.submit(op).await;
+        assert!(submitter.submit_queue.pop().await.is_none());
+        assert!(submitter.confirm_queue.pop().await.is_some());
+        assert_eq!(submissions.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_skip_confirmation() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            skip_confirmation: true,
+            ..MockOperation::new()
+        };
+
+        submitter.submit(Box::new(op)).await;
+        assert!(submitter.submit_queue.pop().await.is_none());
+        assert!(submitter.confirm_queue.pop().await.is_none());
+    }
+
+    /// An operation that failed ten times in `stage`, alternating
+    /// between two reasons.
+    fn operation_failing_in(stage: OperationStage, reasons: [&str; 2]) -> MockOperation {
+        let mut op = MockOperation::new();
+        for attempt in 0..10 {
+            op.push_attempt(stage, reasons[attempt % 2].to_owned());
+        }
+        op
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_oscillating_operation_is_dead_lettered_if_enabled() {
+        let mut submitter = TestSubmitter::new();
+        submitter.dead_letter_oscillating = true;
+        let op = operation_failing_in(
+            OperationStage::Submit,
+            [
+                "transaction reverted",
+                "gas payment requirement no longer met",
+            ],
+        );
+        let id = op.id;
+
+        submitter.prepare_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_prepare_task();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while submitter.dead_letter_store.0.lock().unwrap().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("operation was not dead-lettered");
+        task.abort();
+
+        let records = submitter.dead_letter_store.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, id);
+        assert!(submitter.submit_queue.pop().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_oscillating_operation_is_not_dead_lettered_by_default() {
+        let mut submitter = TestSubmitter::new();
+        let op = operation_failing_in(
+            OperationStage::Submit,
+            [
+                "transaction reverted",
+                "gas payment requirement no longer met",
+            ],
+        );
+        let id = op.id;
+
+        submitter.prepare_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_prepare_task();
+        let op = next_pushed(&mut submitter.submit_queue).await;
+        task.abort();
+
+        assert_eq!(op.id(), id);
+        assert!(submitter.dead_letter_store.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_flapping_prepare_is_not_dead_lettered() {
+        let mut submitter = TestSubmitter::new();
+        submitter.dead_letter_oscillating = true;
+        let op = operation_failing_in(
+            OperationStage::Prepare,
+            [
+                "could not fetch metadata",
+                "gas payment requirement not met",
+            ],
+        );
+        let id = op.id;
+
+        submitter.prepare_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_prepare_task();
+        let op = next_pushed(&mut submitter.submit_queue).await;
+        task.abort();
+
+        assert_eq!(op.id(), id);
+        assert!(submitter.dead_letter_store.0.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_dead_letter_on_prepare() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            prepare_result: PendingOperationResult::DeadLetter("retries exhausted".to_owned()),
+            retries: 3,
+            ..MockOperation::new()
+        };
+        let id = op.id;
+
+        submitter.prepare_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_prepare_task();
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while submitter.dead_letter_store.0.lock().unwrap().is_empty() {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("operation was not dead-lettered");
+        task.abort();
+
+        let records = submitter.dead_letter_store.0.lock().unwrap().clone();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].id, id);
+        assert_eq!(records[0].reason, "retries exhausted");
+        assert_eq!(records[0].retry_count, 3);
+        assert!(submitter.prepare_queue.pop().await.is_none());
+        assert!(submitter.submit_queue.pop().await.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_revalidation_reprepares() {
+        let mut submitter = TestSubmitter::new();
+        // gas prices rose since prepare
+        let op = MockOperation {
+            revalidation_result: PendingOperationResult::Reprepare,
+            ..MockOperation::new()
+        };
+        let submissions = op.submissions.clone();
+
+        submitter.submit_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_submit_task();
+        next_pushed(&mut submitter.prepare_queue).await;
+        task.abort();
+        assert_eq!(submissions.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_failed_simulation_blocks_submission() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            simulation_passes: false,
+            ..MockOperation::new()
+        };
+        let submissions = op.submissions.clone();
+
+        submitter.submit_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_submit_task();
+        next_pushed(&mut submitter.prepare_queue).await;
+        task.abort();
+        assert_eq!(submissions.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_congested_destination_defers_submission() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            backoff_per_pending_tx: Some(Duration::from_millis(200)),
+            ..MockOperation::new()
+        };
+        let submissions = op.submissions.clone();
+        // our own unconfirmed transaction is pending on the destination
+        submitter
+            .confirm_queue
+            .push(Box::new(MockOperation::new()))
+            .await;
+
+        let before = Instant::now();
+        submitter.submit_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_submit_task();
+        let op = next_pushed(&mut submitter.submit_queue).await;
+        task.abort();
+        assert_eq!(submissions.load(Ordering::SeqCst), 0);
+        assert!(op.next_attempt_after().unwrap() >= before + Duration::from_millis(200));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_congestion_backoff_is_waited_out() {
+        let mut submitter = TestSubmitter::new();
+        let op = MockOperation {
+            backoff_per_pending_tx: Some(Duration::from_millis(200)),
+            ..MockOperation::new()
+        };
+        let submissions = op.submissions.clone();
+        submitter
+            .confirm_queue
+            .push(Box::new(MockOperation::new()))
+            .await;
+
+        submitter.submit_queue.push(Box::new(op)).await;
+        let task = submitter.spawn_submit_task();
+        sleep(Duration::from_millis(50)).await;
+        // the destination is no longer congested, but the operation still
+        // waits out its backoff
+        submitter.confirm_queue.pop().await.unwrap();
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(submissions.load(Ordering::SeqCst), 0);
+
+        sleep(Duration::from_millis(200)).await;
+        task.abort();
+        assert_eq!(submissions.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_app_context_rate_limit() {
+        let mut submitter = TestSubmitter::new();
+        submitter.rate_limiter = Some(AppContextRateLimiter::new(1, APP_CONTEXT_RATE_LIMIT_WINDOW));
+        let op_of_app = |app: &str| MockOperation {
+            app_context: AppContext::new(app).ok(),
+            ..MockOperation::new()
+        };
+
+        // submissions that don't send a transaction don't use up the limit
+        let already_submitted = MockOperation {
+            already_submitted: true,
+            ..op_of_app("noisy-app")
+        };
+        submitter.submit(Box::new(already_submitted)).await;
+        let limiter = submitter.rate_limiter.as_ref().unwrap();
+        assert!(limiter.should_allow("noisy-app", Instant::now()));
+
+        submitter.submit(Box::new(op_of_app("noisy-app"))).await;
+        let limiter = submitter.rate_limiter.as_ref().unwrap();
+        assert!(!limiter.should_allow("noisy-app", Instant::now()));
+        assert!(limiter.should_allow("other-app", Instant::now()));
+
+        // operations without an app context don't share a limit
+        for _ in 0..3 {
+            submitter.submit(Box::new(MockOperation::new())).await;
         }
+        let op = MockOperation::new();
+        let fallback_context = op.normalized_app_context();
+        assert!(rate_limited_app_context(&op).is_none());
+        let limiter = submitter.rate_limiter.as_ref().unwrap();
+        assert!(limiter.should_allow(&fallback_context, Instant::now()));
     }
 }
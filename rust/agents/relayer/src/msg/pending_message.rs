@@ -1,4 +1,5 @@
 use std::{
+    collections::BTreeMap,
     fmt::{Debug, Formatter},
     sync::Arc,
     time::{Duration, Instant},
@@ -9,9 +10,10 @@ use derive_new::new;
 use eyre::Result;
 use hyperlane_base::{db::HyperlaneRocksDB, CoreMetrics};
 use hyperlane_core::{
-    gas_used_by_operation, make_op_try, BatchItem, ChainCommunicationError, ChainResult,
+    make_op_try, AppContext, AttemptHistory, BatchItem, ChainCommunicationError, ChainResult,
     HyperlaneChain, HyperlaneDomain, HyperlaneMessage, Mailbox, MessageSubmissionData,
-    PendingOperation, PendingOperationResult, TryBatchAs, TxOutcome, H256, U256,
+    OperationKind, OperationStage, OperatorTags, PendingOperation, PendingOperationResult,
+    StageTimings, TryBatchAs, TxOutcome, H256, U256,
 };
 use prometheus::{IntCounter, IntGauge};
 use tracing::{debug, error, info, instrument, trace, warn};
@@ -53,15 +55,31 @@ pub struct MessageContext {
 pub struct PendingMessage {
     pub message: HyperlaneMessage,
     ctx: Arc<MessageContext>,
-    app_context: Option<String>,
+    app_context: Option<AppContext>,
     #[new(default)]
     submitted: bool,
     #[new(default)]
     submission_data: Option<Box<MessageSubmissionData>>,
     #[new(default)]
+    submission_data_prepared_at: Option<Instant>,
+    #[new(default)]
     num_retries: u32,
     #[new(value = "Instant::now()")]
     last_attempted_at: Instant,
+    #[new(value = "Instant::now()")]
+    created_at: Instant,
+    #[new(default)]
+    queued_priority: Option<u32>,
+    #[new(default)]
+    correlation_id: Option<H256>,
+    #[new(default)]
+    stage_timings: StageTimings,
+    #[new(default)]
+    last_stage_result: Option<(OperationStage, PendingOperationResult)>,
+    #[new(default)]
+    attempt_history: AttemptHistory,
+    #[new(default)]
+    operator_tags: OperatorTags,
     #[new(default)]
     next_attempt_after: Option<Instant>,
     #[new(default)]
@@ -83,8 +101,8 @@ impl Debug for PendingMessage {
                 }
             })
             .unwrap_or(0);
-        write!(f, "PendingMessage {{ num_retries: {}, since_last_attempt_s: {last_attempt}, next_attempt_after_s: {next_attempt}, message: {:?} }}",
-               self.num_retries, self.message)
+        write!(f, "PendingMessage {{ num_retries: {}, since_last_attempt_s: {last_attempt}, next_attempt_after_s: {next_attempt}, correlation_id: {:?}, operator_tags: {:?}, message: {:?} }}",
+               self.num_retries, self.correlation_id, self.operator_tags.tags(), self.message)
     }
 }
 
@@ -120,10 +138,50 @@ impl PendingOperation for PendingMessage {
         self.message.id()
     }
 
+    fn kind(&self) -> OperationKind {
+        OperationKind::MessageDelivery
+    }
+
     fn priority(&self) -> u32 {
         self.message.nonce
     }
 
+    fn correlation_id(&self) -> Option<H256> {
+        self.correlation_id
+    }
+
+    fn set_correlation_id(&mut self, id: H256) {
+        self.correlation_id = Some(id);
+    }
+
+    fn record_stage_timing(&mut self, stage: OperationStage, elapsed: Duration) {
+        self.stage_timings.record(stage, elapsed);
+    }
+
+    fn stage_timings(&self) -> StageTimings {
+        self.stage_timings
+    }
+
+    fn record_stage_result(&mut self, stage: OperationStage, result: PendingOperationResult) {
+        self.last_stage_result = Some((stage, result));
+    }
+
+    fn last_stage_result(&self) -> Option<(OperationStage, PendingOperationResult)> {
+        self.last_stage_result.clone()
+    }
+
+    fn created_at(&self) -> Option<Instant> {
+        Some(self.created_at)
+    }
+
+    fn queued_priority(&self) -> Option<u32> {
+        self.queued_priority
+    }
+
+    fn set_queued_priority(&mut self, priority: u32) {
+        self.queued_priority = Some(priority);
+    }
+
     fn origin_domain_id(&self) -> u32 {
         self.message.origin
     }
@@ -132,13 +190,13 @@ impl PendingOperation for PendingMessage {
         self.ctx.destination_mailbox.domain()
     }
 
-    fn app_context(&self) -> Option<String> {
+    fn app_context(&self) -> Option<AppContext> {
         self.app_context.clone()
     }
 
-    #[instrument(skip(self), ret, fields(id=?self.id()), level = "debug")]
+    #[instrument(skip(self), ret, fields(id=?self.id(), correlation_id=?self.correlation_id, operator_tags=?self.operator_tags.tags()), level = "debug")]
     async fn prepare(&mut self) -> PendingOperationResult {
-        make_op_try!(|| self.on_reprepare());
+        make_op_try!(|| self.on_reprepare(OperationStage::Prepare, "error when preparing"));
 
         if !self.is_ready() {
             trace!("Message is not ready to be submitted yet");
@@ -202,7 +260,7 @@ impl PendingOperation for PendingMessage {
             "building metadata"
         ) else {
             info!("Could not fetch metadata");
-            return self.on_reprepare();
+            return self.on_reprepare(OperationStage::Prepare, "could not fetch metadata");
         };
 
         // Estimate transaction costs for the process call. If there are issues, it's
@@ -226,7 +284,7 @@ impl PendingOperation for PendingMessage {
             "checking if message meets gas payment requirement"
         ) else {
             warn!(?tx_cost_estimate, "Gas payment requirement not met yet");
-            return self.on_reprepare();
+            return self.on_reprepare(OperationStage::Prepare, "gas payment requirement not met");
         };
 
         // Go ahead and attempt processing of message to destination chain.
@@ -239,7 +297,10 @@ impl PendingOperation for PendingMessage {
         if let Some(max_limit) = self.ctx.transaction_gas_limit {
             if gas_limit > max_limit {
                 info!("Message delivery estimated gas exceeds max gas limit");
-                return self.on_reprepare();
+                return self.on_reprepare(
+                    OperationStage::Prepare,
+                    "estimated gas exceeds max gas limit",
+                );
             }
         }
 
@@ -247,14 +308,51 @@ impl PendingOperation for PendingMessage {
             metadata,
             gas_limit,
         }));
+        self.submission_data_prepared_at = Some(Instant::now());
+        PendingOperationResult::Success
+    }
+
+    /// Gas prices may have risen since `prepare`, so check again that the gas
+    /// payment covers the delivery.
+    #[instrument(skip(self), fields(id=?self.id()), level = "debug")]
+    async fn revalidate_before_submit(&mut self) -> PendingOperationResult {
+        make_op_try!(|| self.on_reprepare(OperationStage::Submit, "error when revalidating"));
+
+        let Some(metadata) = self.submission_data.as_ref().map(|d| d.metadata.clone()) else {
+            return PendingOperationResult::Success;
+        };
+        let tx_cost_estimate = op_try!(
+            self.ctx
+                .destination_mailbox
+                .process_estimate_costs(&self.message, &metadata)
+                .await,
+            "estimating costs for process call"
+        );
+        let meets_gas_payment_requirement = op_try!(
+            self.ctx
+                .origin_gas_payment_enforcer
+                .message_meets_gas_payment_requirement(&self.message, &tx_cost_estimate)
+                .await,
+            "checking if message meets gas payment requirement"
+        );
+        if meets_gas_payment_requirement.is_none() {
+            warn!(
+                ?tx_cost_estimate,
+                "Gas payment requirement no longer met, repreparing"
+            );
+            return self.on_reprepare(
+                OperationStage::Submit,
+                "gas payment requirement no longer met",
+            );
+        }
         PendingOperationResult::Success
     }
 
     #[instrument]
-    async fn submit(&mut self) {
+    async fn submit(&mut self) -> ChainResult<Option<TxOutcome>> {
         if self.submitted {
             // this message has already been submitted, possibly not by us
-            return;
+            return Ok(None);
         }
 
         let state = self
@@ -264,29 +362,36 @@ impl PendingOperation for PendingMessage {
 
         // We use the estimated gas limit from the prior call to
         // `process_estimate_costs` to avoid a second gas estimation.
-        let tx_outcome = self
-            .ctx
+        self.ctx
             .destination_mailbox
             .process(&self.message, &state.metadata, Some(state.gas_limit))
-            .await;
-        match tx_outcome {
-            Ok(outcome) => {
-                self.set_operation_outcome(outcome, state.gas_limit);
-            }
-            Err(e) => {
-                error!(error=?e, "Error when processing message");
-            }
-        }
+            .await
+            .map(Some)
     }
 
     fn set_submission_outcome(&mut self, outcome: TxOutcome) {
         self.submission_outcome = Some(outcome);
     }
 
+    fn record_outcome_with_status(&mut self, outcome: TxOutcome) -> PendingOperationResult {
+        let executed = outcome.executed;
+        self.set_submission_outcome(outcome);
+        if executed {
+            PendingOperationResult::Confirm
+        } else {
+            warn!(?self, "Message delivery transaction reverted, repreparing");
+            self.on_reprepare(OperationStage::Submit, "transaction reverted")
+        }
+    }
+
     fn get_tx_cost_estimate(&self) -> Option<U256> {
         self.submission_data.as_ref().map(|d| d.gas_limit)
     }
 
+    fn cost_estimate_age(&self) -> Option<Duration> {
+        self.submission_data_prepared_at.map(|t| t.elapsed())
+    }
+
     async fn confirm(&mut self) -> PendingOperationResult {
         make_op_try!(|| {
             // Provider error; just try again later
@@ -322,10 +427,17 @@ impl PendingOperation for PendingMessage {
                 message_id=?self.message.id(),
                 "Transaction attempting to process message either reverted or was reorged"
             );
-            self.on_reprepare()
+            self.on_reprepare(
+                OperationStage::Confirm,
+                "transaction reverted or was reorged",
+            )
         }
     }
 
+    async fn is_delivered(&self, id: H256) -> ChainResult<bool> {
+        self.ctx.destination_mailbox.delivered(id).await
+    }
+
     fn set_operation_outcome(
         &mut self,
         submission_outcome: TxOutcome,
@@ -336,7 +448,7 @@ impl PendingOperation for PendingMessage {
             return;
         };
         // calculate the gas used by the operation
-        let gas_used_by_operation = match gas_used_by_operation(
+        let gas_used_by_operation = match self.cost_model().cost_used_by_operation(
             &submission_outcome,
             submission_estimated_cost,
             operation_estimate,
@@ -378,6 +490,36 @@ impl PendingOperation for PendingMessage {
         self.next_attempt_after = Some(Instant::now() + delay);
     }
 
+    fn set_next_attempt_at(&mut self, at: Instant) {
+        self.next_attempt_after = Some(at);
+    }
+
+    fn attempt_history(&self) -> &[(Instant, OperationStage, String)] {
+        self.attempt_history.entries()
+    }
+
+    fn push_attempt(&mut self, stage: OperationStage, reason: String) {
+        self.attempt_history.push(Instant::now(), stage, reason);
+    }
+
+    fn operator_tags(&self) -> &BTreeMap<String, String> {
+        self.operator_tags.tags()
+    }
+
+    fn set_operator_tag(&mut self, key: String, value: String) {
+        if !self.operator_tags.insert(key, value) {
+            warn!(id=?self.id(), "Too many operator tags, ignoring new tag");
+        }
+    }
+
+    fn retry_count(&self) -> u32 {
+        self.num_retries
+    }
+
+    fn clear_next_attempt(&mut self) {
+        self.next_attempt_after = None;
+    }
+
     fn reset_attempts(&mut self) {
         self.reset_attempts();
     }
@@ -393,7 +535,7 @@ impl PendingMessage {
     pub fn from_persisted_retries(
         message: HyperlaneMessage,
         ctx: Arc<MessageContext>,
-        app_context: Option<String>,
+        app_context: Option<AppContext>,
     ) -> Self {
         let mut pm = Self::new(message, ctx, app_context);
         match pm
@@ -414,8 +556,10 @@ impl PendingMessage {
         pm
     }
 
-    fn on_reprepare(&mut self) -> PendingOperationResult {
+    fn on_reprepare(&mut self, stage: OperationStage, reason: &str) -> PendingOperationResult {
         self.inc_attempts();
+        self.attempt_history
+            .push(Instant::now(), stage, reason.to_owned());
         self.submitted = false;
         PendingOperationResult::Reprepare
     }
@@ -14,7 +14,7 @@ use hyperlane_base::{
     db::{HyperlaneRocksDB, ProcessMessage},
     CoreMetrics,
 };
-use hyperlane_core::{HyperlaneDomain, HyperlaneMessage, QueueOperation};
+use hyperlane_core::{AppContext, HyperlaneDomain, HyperlaneMessage, QueueOperation};
 use prometheus::IntGauge;
 use tokio::sync::mpsc::UnboundedSender;
 use tracing::{debug, instrument, trace};
@@ -291,7 +291,11 @@ impl ProcessorExt for MessageProcessor {
             let app_context_classifier =
                 AppContextClassifier::new(self.metric_app_contexts.clone());
 
-            let app_context = app_context_classifier.get_app_context(&msg).await?;
+            let app_context = app_context_classifier
+                .get_app_context(&msg)
+                .await?
+                .as_deref()
+                .and_then(AppContext::from_str_lossy);
             // Finally, build the submit arg and dispatch it to the submitter.
             let pending_msg = PendingMessage::from_persisted_retries(
                 msg,
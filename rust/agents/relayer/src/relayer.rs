@@ -16,8 +16,8 @@ use hyperlane_base::{
     SyncOptions,
 };
 use hyperlane_core::{
-    HyperlaneDomain, HyperlaneMessage, InterchainGasPayment, MerkleTreeInsertion, QueueOperation,
-    H512, U256,
+    AppContext, HyperlaneDomain, HyperlaneMessage, InterchainGasPayment, MerkleTreeInsertion,
+    OperationStage, QueueOperation, WriteAheadLog, H512, U256,
 };
 use tokio::{
     sync::{
@@ -28,16 +28,16 @@ use tokio::{
     task::JoinHandle,
 };
 use tokio_metrics::TaskMonitor;
-use tracing::{error, info, info_span, instrument::Instrumented, warn, Instrument};
+use tracing::{debug, error, info, info_span, instrument::Instrumented, warn, Instrument};
 
 use crate::{
     merkle_tree::builder::MerkleTreeBuilder,
     msg::{
         blacklist::AddressBlacklist,
         gas_payment::GasPaymentEnforcer,
-        metadata::{BaseMetadataBuilder, IsmAwareAppContextClassifier},
+        metadata::{AppContextClassifier, BaseMetadataBuilder, IsmAwareAppContextClassifier},
         op_submitter::{SerialSubmitter, SerialSubmitterMetrics},
-        pending_message::{MessageContext, MessageSubmissionMetrics},
+        pending_message::{MessageContext, MessageSubmissionMetrics, PendingMessage},
         processor::{MessageProcessor, MessageProcessorMetrics},
     },
     server::{self as relayer_server, MessageRetryRequest},
@@ -71,6 +71,10 @@ pub struct Relayer {
     prover_syncs: HashMap<HyperlaneDomain, Arc<RwLock<MerkleTreeBuilder>>>,
     merkle_tree_hook_syncs: HashMap<HyperlaneDomain, Arc<dyn ContractSyncer<MerkleTreeInsertion>>>,
     dbs: HashMap<HyperlaneDomain, HyperlaneRocksDB>,
+    /// Where each destination's submitter keeps the operations it gave up on
+    dead_letter_dbs: HashMap<HyperlaneDomain, HyperlaneRocksDB>,
+    /// Where each destination's submitter records operation state transitions
+    write_ahead_logs: HashMap<HyperlaneDomain, Arc<WriteAheadLog>>,
     message_whitelist: Arc<MatchingList>,
     message_blacklist: Arc<MatchingList>,
     address_blacklist: Arc<AddressBlacklist>,
@@ -128,6 +132,35 @@ impl BaseAgent for Relayer {
             .iter()
             .map(|origin| (origin.clone(), HyperlaneRocksDB::new(origin, db.clone())))
             .collect::<HashMap<_, _>>();
+        let dead_letter_dbs = settings
+            .destination_chains
+            .iter()
+            .map(|destination| {
+                (
+                    destination.clone(),
+                    HyperlaneRocksDB::new(destination, db.clone()),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        // Kept next to the database rather than in it, since RocksDB owns its
+        // directory. `file_name` ignores a trailing slash, which would
+        // otherwise put the log inside the database directory.
+        let db_name = settings
+            .db
+            .file_name()
+            .ok_or_else(|| eyre::eyre!("Database path {:?} has no file name", settings.db))?
+            .to_string_lossy()
+            .into_owned();
+        let write_ahead_logs = settings
+            .destination_chains
+            .iter()
+            .map(|destination| {
+                let path = settings
+                    .db
+                    .with_file_name(format!("{db_name}.{}.wal", destination.name()));
+                Ok((destination.clone(), Arc::new(WriteAheadLog::open(&path)?)))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
 
         let mailboxes = settings
             .build_mailboxes(settings.destination_chains.iter(), &core_metrics)
@@ -272,6 +305,8 @@ impl BaseAgent for Relayer {
 
         Ok(Self {
             dbs,
+            dead_letter_dbs,
+            write_ahead_logs,
             origin_chains: settings.origin_chains,
             destination_chains,
             msg_ctxs,
@@ -330,6 +365,7 @@ impl BaseAgent for Relayer {
             let (send_channel, receive_channel) = mpsc::unbounded_channel::<QueueOperation>();
             send_channels.insert(dest_domain.id(), send_channel);
 
+            let recovered = self.recover_operations(dest_domain).await;
             tasks.push(
                 self.run_destination_submitter(
                     dest_domain,
@@ -342,6 +378,7 @@ impl BaseAgent for Relayer {
                         .map(|c| c.max_batch_size)
                         .unwrap_or(1),
                     task_monitor.clone(),
+                    recovered,
                 ),
             );
 
@@ -525,8 +562,74 @@ impl Relayer {
         processor.spawn().instrument(span)
     }
 
+    /// The messages `destination`'s submitter had in flight when the relayer
+    /// last stopped, by the stage they were queued for, as recorded in its
+    /// write-ahead log. Messages that have been processed since, or that
+    /// can't be found in their origin's database, aren't recovered.
+    async fn recover_operations(
+        &self,
+        destination: &HyperlaneDomain,
+    ) -> Vec<(QueueOperation, OperationStage)> {
+        let entries = match self.write_ahead_logs[destination].replay() {
+            Ok(entries) => entries,
+            Err(err) => {
+                warn!(?err, %destination, "Failed to replay the write-ahead log, not recovering any operations");
+                return vec![];
+            }
+        };
+        let app_context_classifier = AppContextClassifier::new(self.metric_app_contexts.clone());
+        let mut recovered = Vec::with_capacity(entries.len());
+        for entry in entries.into_values() {
+            let Some(stage) = entry.stage else {
+                continue;
+            };
+            let operation = entry.operation;
+            let Some((origin, db)) = self
+                .dbs
+                .iter()
+                .find(|(origin, _)| origin.id() == operation.origin_domain_id)
+            else {
+                debug!(
+                    ?operation,
+                    "Not recovering operation from an origin that isn't relayed from"
+                );
+                continue;
+            };
+            let message = match db.retrieve_message_by_id(&operation.id) {
+                Ok(Some(message)) => message,
+                result => {
+                    warn!(
+                        ?operation,
+                        ?result,
+                        "Failed to find recovered operation's message"
+                    );
+                    continue;
+                }
+            };
+            if let Ok(Some(true)) = db.retrieve_processed_by_nonce(&message.nonce) {
+                continue;
+            }
+            let Some(ctx) = self.msg_ctxs.get(&ContextKey {
+                origin: origin.id(),
+                destination: destination.id(),
+            }) else {
+                continue;
+            };
+            let app_context = app_context_classifier
+                .get_app_context(&message)
+                .await
+                .ok()
+                .flatten()
+                .as_deref()
+                .and_then(AppContext::from_str_lossy);
+            let op = PendingMessage::from_persisted_retries(message, ctx.clone(), app_context);
+            recovered.push((Box::new(op) as QueueOperation, stage));
+        }
+        recovered
+    }
+
     #[allow(clippy::too_many_arguments)]
-    #[tracing::instrument(skip(self, receiver))]
+    #[tracing::instrument(skip(self, receiver, recovered))]
     fn run_destination_submitter(
         &self,
         destination: &HyperlaneDomain,
@@ -534,15 +637,32 @@ impl Relayer {
         retry_receiver_channel: Sender<MessageRetryRequest>,
         batch_size: u32,
         task_monitor: TaskMonitor,
+        recovered: Vec<(QueueOperation, OperationStage)>,
     ) -> Instrumented<JoinHandle<()>> {
-        let serial_submitter = SerialSubmitter::new(
+        let mut serial_submitter = SerialSubmitter::new(
             destination.clone(),
             receiver,
             retry_receiver_channel,
             SerialSubmitterMetrics::new(&self.core.metrics, destination),
             batch_size,
             task_monitor.clone(),
-        );
+            Arc::new(self.dead_letter_dbs[destination].clone()),
+            self.write_ahead_logs[destination].clone(),
+        )
+        .with_recovered_operations(recovered);
+        let batch_config = self.core.settings.chains[destination.name()]
+            .connection
+            .operation_batch_config();
+        if let Some(max_in_flight) = batch_config.and_then(|c| c.max_in_flight) {
+            serial_submitter = serial_submitter.with_max_in_flight(max_in_flight);
+        }
+        if let Some(max_submissions) = batch_config.and_then(|c| c.max_submissions_per_app_context)
+        {
+            serial_submitter = serial_submitter.with_app_context_rate_limit(max_submissions);
+        }
+        if batch_config.is_some_and(|c| c.dead_letter_oscillating_operations) {
+            serial_submitter = serial_submitter.with_oscillation_dead_lettering();
+        }
         let span = info_span!("SerialSubmitter", destination=%destination);
         let destination = destination.clone();
         tokio::spawn(TaskMonitor::instrument(&task_monitor, async move {
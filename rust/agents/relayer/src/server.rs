@@ -30,7 +30,7 @@ impl PartialEq<QueueOperation> for &MessageRetryRequest {
         match self {
             MessageRetryRequest::MessageId(message_id) => message_id == &other.id(),
             MessageRetryRequest::DestinationDomain(destination_domain) => {
-                destination_domain == &other.destination_domain().id()
+                destination_domain == &other.destination_domain_id()
             }
         }
     }
@@ -4,9 +4,9 @@ use paste::paste;
 use tracing::{debug, instrument, trace};
 
 use hyperlane_core::{
-    GasPaymentKey, HyperlaneDomain, HyperlaneLogStore, HyperlaneMessage,
-    HyperlaneSequenceAwareIndexerStoreReader, HyperlaneWatermarkedLogStore, Indexed,
-    InterchainGasExpenditure, InterchainGasPayment, InterchainGasPaymentMeta, LogMeta,
+    DeadLetterRecord, DeadLetterStore, GasPaymentKey, HyperlaneDomain, HyperlaneLogStore,
+    HyperlaneMessage, HyperlaneSequenceAwareIndexerStoreReader, HyperlaneWatermarkedLogStore,
+    Indexed, InterchainGasExpenditure, InterchainGasPayment, InterchainGasPaymentMeta, LogMeta,
     MerkleTreeInsertion, H256,
 };
 
@@ -34,6 +34,7 @@ const MERKLE_LEAF_INDEX_BY_MESSAGE_ID: &str = "merkle_leaf_index_by_message_id_"
 const MERKLE_TREE_INSERTION_BLOCK_NUMBER_BY_LEAF_INDEX: &str =
     "merkle_tree_insertion_block_number_by_leaf_index_";
 const LATEST_INDEXED_GAS_PAYMENT_BLOCK: &str = "latest_indexed_gas_payment_block";
+const DEAD_LETTER_BY_OPERATION_ID: &str = "dead_letter_by_operation_id_";
 
 /// Rocks DB result type
 pub type DbResult<T> = std::result::Result<T, DbError>;
@@ -451,6 +452,13 @@ pub trait ProcessMessage: Send + Sync {
     fn domain(&self) -> &HyperlaneDomain;
 }
 
+impl DeadLetterStore for HyperlaneRocksDB {
+    fn store_dead_letter(&self, record: &DeadLetterRecord) -> Result<()> {
+        self.store_dead_letter_by_operation_id(&record.id, record)?;
+        Ok(())
+    }
+}
+
 impl ProcessMessage for HyperlaneRocksDB {
     fn retrieve_highest_seen_message_nonce(&self) -> DbResult<Option<u32>> {
         self.retrieve_highest_seen_message_nonce()
@@ -496,7 +504,7 @@ macro_rules! make_store_and_retrieve {
 }
 
 make_store_and_retrieve!(pub, message_id_by_nonce, MESSAGE_ID, u32, H256);
-make_store_and_retrieve!(pub(self), message_by_id, MESSAGE, H256, HyperlaneMessage);
+make_store_and_retrieve!(pub, message_by_id, MESSAGE, H256, HyperlaneMessage);
 make_store_and_retrieve!(pub(self), dispatched_block_number_by_nonce, MESSAGE_DISPATCHED_BLOCK_NUMBER, u32, u64);
 make_store_and_retrieve!(pub, processed_by_nonce, NONCE_PROCESSED, u32, bool);
 make_store_and_retrieve!(pub(self), processed_by_gas_payment_meta, GAS_PAYMENT_META_PROCESSED, InterchainGasPaymentMeta, bool);
@@ -525,6 +533,13 @@ make_store_and_retrieve!(
     H256,
     u32
 );
+make_store_and_retrieve!(
+    pub,
+    dead_letter_by_operation_id,
+    DEAD_LETTER_BY_OPERATION_ID,
+    H256,
+    DeadLetterRecord
+);
 make_store_and_retrieve!(
     pub,
     merkle_tree_insertion_block_number_by_leaf_index,
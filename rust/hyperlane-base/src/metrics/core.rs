@@ -40,6 +40,7 @@ pub struct CoreMetrics {
     submitter_queue_length: IntGaugeVec,
 
     operations_processed_count: IntCounterVec,
+    operation_time_in_queue: HistogramVec,
     messages_processed_count: IntCounterVec,
 
     latest_checkpoint: IntGaugeVec,
@@ -156,6 +157,17 @@ impl CoreMetrics {
             registry
         )?;
 
+        let operation_time_in_queue = register_histogram_vec_with_registry!(
+            histogram_opts!(
+                namespaced!("operation_time_in_queue_seconds"),
+                "Time operations spent queued before being confirmed or dropped",
+                vec![1., 10., 60., 300., 900., 3600., 6. * 3600., 24. * 3600.],
+                const_labels.clone()
+            ),
+            &["phase", "chain"],
+            registry
+        )?;
+
         let messages_processed_count = register_int_counter_vec_with_registry!(
             opts!(
                 namespaced!("messages_processed_count"),
@@ -180,6 +192,7 @@ impl CoreMetrics {
             submitter_queue_length,
 
             operations_processed_count,
+            operation_time_in_queue,
             messages_processed_count,
 
             latest_checkpoint,
@@ -354,6 +367,16 @@ impl CoreMetrics {
         self.operations_processed_count.clone()
     }
 
+    /// How long operations spent queued, from their creation until they
+    /// reached a terminal phase, in seconds.
+    ///
+    /// Labels:
+    /// - `phase`: `confirmed` or `dropped`, see `operations_processed_count`.
+    /// - `chain`: Chain the operation was destined for.
+    pub fn operation_time_in_queue(&self) -> HistogramVec {
+        self.operation_time_in_queue.clone()
+    }
+
     /// The number of messages successfully submitted by this process during its
     /// lifetime.
     ///
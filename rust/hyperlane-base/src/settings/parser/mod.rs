@@ -199,6 +199,26 @@ fn parse_chain(
         .parse_u32()
         .unwrap_or(1);
 
+    let max_in_flight = chain
+        .chain(&mut err)
+        .get_opt_key("maxInFlight")
+        .parse_u32()
+        .end()
+        .map(|max| max as usize);
+
+    let max_submissions_per_app_context = chain
+        .chain(&mut err)
+        .get_opt_key("maxSubmissionsPerAppContext")
+        .parse_u32()
+        .end()
+        .map(|max| max as usize);
+
+    let dead_letter_oscillating_operations = chain
+        .chain(&mut err)
+        .get_opt_key("deadLetterOscillatingOperations")
+        .parse_bool()
+        .unwrap_or(false);
+
     cfg_unwrap_all!(&chain.cwp, err: [domain]);
     let connection = build_connection_conf(
         domain.domain_protocol(),
@@ -209,6 +229,9 @@ fn parse_chain(
         OperationBatchConfig {
             batch_contract_address,
             max_batch_size,
+            max_in_flight,
+            max_submissions_per_app_context,
+            dead_letter_oscillating_operations,
         },
     );
 
@@ -29,6 +29,15 @@ pub struct OperationBatchConfig {
     pub batch_contract_address: Option<H256>,
     /// Batch size
     pub max_batch_size: u32,
+    /// Max operations submitted but not yet confirmed at once, unlimited if
+    /// `None`
+    pub max_in_flight: Option<usize>,
+    /// Max operations of the same app context submitted per minute,
+    /// unlimited if `None`
+    pub max_submissions_per_app_context: Option<usize>,
+    /// Whether operations stuck cycling between stages are dead-lettered
+    /// rather than reprepared with their usual backoff
+    pub dead_letter_oscillating_operations: bool,
 }
 
 /// A trait that allows for constructing `Self` from a raw config type.
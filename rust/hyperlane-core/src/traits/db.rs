@@ -4,7 +4,7 @@ use async_trait::async_trait;
 use auto_impl::auto_impl;
 use eyre::Result;
 
-use crate::{Indexed, LogMeta};
+use crate::{DeadLetterRecord, Indexed, LogMeta};
 
 /// Interface for a HyperlaneLogStore that ingests logs.
 #[async_trait]
@@ -15,6 +15,14 @@ pub trait HyperlaneLogStore<T>: Send + Sync + Debug {
     async fn store_logs(&self, logs: &[(Indexed<T>, LogMeta)]) -> Result<u32>;
 }
 
+/// Interface for keeping the records of operations that were given up on, so
+/// that they can be inspected later.
+#[auto_impl(&, Box, Arc)]
+pub trait DeadLetterStore: Send + Sync + Debug {
+    /// Store the record of an operation that was given up on
+    fn store_dead_letter(&self, record: &DeadLetterRecord) -> Result<()>;
+}
+
 /// A sequence is a monotonically increasing number that is incremented every time a message ID is indexed.
 /// E.g. for Mailbox indexing, this is equal to the message nonce, and for merkle tree hook indexing, this
 /// is equal to the leaf index.
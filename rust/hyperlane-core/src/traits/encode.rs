@@ -236,8 +236,37 @@ impl Decode for InterchainGasPayment {
     }
 }
 
-// TODO: Could generalize this implementation to support encoding arbitrary `Option<T>`
-// where T: Encode + Decode
+/// Encoded as a `bool` that is true if the value is defined, followed by the
+/// value itself if it is.
+impl<T: Encode> Encode for Option<T> {
+    fn write_to<W>(&self, writer: &mut W) -> std::io::Result<usize>
+    where
+        W: std::io::Write,
+    {
+        let mut written = 0;
+        written += self.is_some().write_to(writer)?;
+        if let Some(value) = self {
+            written += value.write_to(writer)?;
+        }
+        Ok(written)
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn read_from<R>(reader: &mut R) -> Result<Self, HyperlaneProtocolError>
+    where
+        R: std::io::Read,
+        Self: Sized,
+    {
+        let is_defined = bool::read_from(reader)?;
+        if is_defined {
+            Ok(Some(T::read_from(reader)?))
+        } else {
+            Ok(None)
+        }
+    }
+}
+
 impl<T: Encode> Encode for Indexed<T> {
     fn write_to<W>(&self, writer: &mut W) -> std::io::Result<usize>
     where
@@ -245,17 +274,7 @@ impl<T: Encode> Encode for Indexed<T> {
     {
         let mut written = 0;
         written += self.inner().write_to(writer)?;
-        match self.sequence {
-            Some(sequence) => {
-                let sequence_is_defined = true;
-                written += sequence_is_defined.write_to(writer)?;
-                written += sequence.write_to(writer)?;
-            }
-            None => {
-                let sequence_is_defined = false;
-                written += sequence_is_defined.write_to(writer)?;
-            }
-        }
+        written += self.sequence.write_to(writer)?;
         Ok(written)
     }
 }
@@ -267,10 +286,8 @@ impl<T: Decode> Decode for Indexed<T> {
         Self: Sized,
     {
         let inner = T::read_from(reader)?;
-        let sequence_is_defined = bool::read_from(reader)?;
         let mut indexed = Self::new(inner);
-        if sequence_is_defined {
-            let sequence = u32::read_from(reader)?;
+        if let Some(sequence) = Option::<u32>::read_from(reader)? {
             indexed = indexed.with_sequence(sequence)
         }
         Ok(indexed)
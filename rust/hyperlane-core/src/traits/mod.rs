@@ -11,6 +11,7 @@ pub use mailbox::*;
 pub use merkle_tree_hook::*;
 pub use multisig_ism::*;
 pub use pending_operation::*;
+pub use persistence::*;
 pub use provider::*;
 pub use routing_ism::*;
 pub use signing::*;
@@ -31,6 +32,7 @@ mod mailbox;
 mod merkle_tree_hook;
 mod multisig_ism;
 mod pending_operation;
+mod persistence;
 mod provider;
 mod routing_ism;
 mod signing;
@@ -1,20 +1,67 @@
 use std::{
     cmp::Ordering,
+    collections::{BTreeMap, BinaryHeap, HashMap, HashSet, VecDeque},
     fmt::{Debug, Display},
-    time::{Duration, Instant},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use crate::{
-    ChainResult, FixedPointNumber, HyperlaneDomain, HyperlaneMessage, TryBatchAs, TxOutcome, H256,
-    U256,
+    ChainCommunicationError, ChainResult, DeadLetterRecord, FixedPointNumber, HyperlaneDomain,
+    HyperlaneMessage, SerializableOperation, TryBatchAs, TxOutcome, H256, U256, U512,
 };
 use async_trait::async_trait;
-use num::CheckedDiv;
-use tracing::warn;
+use serde::{Deserialize, Serialize};
+use sha3::{digest::Update, Digest, Keccak256};
+#[cfg(feature = "async")]
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, trace, warn};
 
 /// Boxed operation that can be stored in an operation queue
 pub type QueueOperation = Box<dyn PendingOperation>;
 
+/// The maximum length of the app context used in metrics labels, to bound
+/// their cardinality.
+pub const MAX_APP_CONTEXT_LEN: usize = 64;
+
+/// How long an operation has to wait for its effective priority to be boosted
+/// by one. See `PendingOperation::effective_priority`.
+pub const PRIORITY_BOOST_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The number of failed attempts kept in an `AttemptHistory`.
+pub const MAX_ATTEMPT_HISTORY: usize = 16;
+
+/// The maximum number of tags an operator can attach to an operation, see
+/// `PendingOperation::operator_tags`.
+pub const MAX_OPERATOR_TAGS: usize = 16;
+
+/// The maximum length, in characters, of an operator tag key or value. Longer
+/// ones are truncated.
+pub const MAX_OPERATOR_TAG_LEN: usize = 64;
+
+/// Returned by the default `PendingOperation::operator_tags`.
+static NO_OPERATOR_TAGS: BTreeMap<String, String> = BTreeMap::new();
+
+/// How far apart the priorities `default_priority_for_kind` assigns to
+/// different `OperationKind`s are, so that kinds don't interleave unless a
+/// queue gets this long.
+pub const OPERATION_KIND_PRIORITY_OFFSET: u32 = 1_000_000_000;
+
+/// The default `PendingOperation::batch_chunk_size`, large enough that
+/// ordinary batches are processed in one go.
+pub const DEFAULT_BATCH_CHUNK_SIZE: usize = 1024;
+
+/// The destination block time assumed when an operation doesn't know better,
+/// see `PendingOperation::destination_block_time`. Matches Ethereum mainnet,
+/// which errs on the side of waiting longer on faster chains.
+pub const DEFAULT_BLOCK_TIME: Duration = Duration::from_secs(12);
+
+/// How long to wait between calls to `confirm` that return `NotReady` when an
+/// operation doesn't know better, see
+/// `PendingOperation::confirm_poll_interval`. Matches how often the submitter
+/// re-polls a confirm queue that has nothing ready.
+pub const DEFAULT_CONFIRM_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 /// A pending operation that will be run by the submitter and cause a
 /// transaction to be sent.
 ///
@@ -39,6 +86,34 @@ pub trait PendingOperation: Send + Sync + Debug + TryBatchAs<HyperlaneMessage> {
     /// Get the unique identifier for this operation.
     fn id(&self) -> H256;
 
+    /// Key used to order operations that are otherwise equal, e.g. ones from
+    /// different origins with no `next_attempt_after`. Defaults to the low
+    /// bytes of `id`, so the order is arbitrary but stable; tests can
+    /// override it to make the order reproducible.
+    fn tie_break_key(&self) -> u64 {
+        self.id().to_low_u64_be()
+    }
+
+    /// What kind of operation this is, so that queues holding several kinds
+    /// can tell them apart without downcasting.
+    fn kind(&self) -> OperationKind;
+
+    /// How much the submitter should log about this operation's progress
+    /// through each stage. Defaults to `LogVerbosity::Normal`.
+    fn log_verbosity(&self) -> LogVerbosity {
+        LogVerbosity::Normal
+    }
+
+    /// Hash of the logical content of this operation, e.g. the message being
+    /// delivered. Two queue entries with the same content hash represent the
+    /// same work, so only one of them should be submitted.
+    ///
+    /// Unlike `id`, which implementations may derive per attempt, this must be
+    /// stable across attempts and reloads. Defaults to `id`.
+    fn content_hash(&self) -> H256 {
+        self.id()
+    }
+
     /// A lower value means a higher priority, such as the message nonce
     /// As new types of PendingOperations are added, an idea is to just use the
     /// current length of the queue as this item's priority.
@@ -46,41 +121,712 @@ pub trait PendingOperation: Send + Sync + Debug + TryBatchAs<HyperlaneMessage> {
     /// operations when neither of them have a `next_attempt_after`
     fn priority(&self) -> u32;
 
+    /// Re-derive `priority` given the length of the queue the operation is
+    /// being added to. The submitter calls this for every operation it
+    /// receives, including ones loaded from storage after a restart, so that
+    /// operations whose priority depends on their position in the queue are
+    /// ordered correctly relative to the ones already in it, e.g. using
+    /// `default_priority_for_kind`. Operations with an intrinsic priority,
+    /// like a message nonce, can leave this as the default no-op.
+    fn recompute_priority(&mut self, _queue_len: u32) {}
+
+    /// When this operation was created, if known. Used to age operations so
+    /// that they aren't starved by newer ones.
+    fn created_at(&self) -> Option<Instant> {
+        None
+    }
+
+    /// How long this operation has been queued for at `now`, i.e. since
+    /// `created_at`. Zero if the creation time isn't known.
+    fn time_in_queue(&self, now: Instant) -> Duration {
+        self.created_at()
+            .map(|created_at| now.saturating_duration_since(created_at))
+            .unwrap_or_default()
+    }
+
+    /// The time this operation should preferably be delivered by. Once it has
+    /// passed, the operation is boosted to the highest priority. Defaults to
+    /// `None`.
+    fn soft_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// The time after which this operation is no longer worth delivering and
+    /// should be dropped. Defaults to `None`.
+    fn hard_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Whether the `hard_deadline` has passed at `now`.
+    fn is_expired(&self, now: Instant) -> bool {
+        self.hard_deadline()
+            .map(|deadline| now >= deadline)
+            .unwrap_or(false)
+    }
+
+    /// `priority`, boosted (i.e. lowered) by one for every
+    /// `PRIORITY_BOOST_INTERVAL` that has passed since `created_at`, so that
+    /// long-waiting operations eventually outrank newer ones, or all the way
+    /// to 0 once the `soft_deadline` has passed. Queues store this with
+    /// `set_queued_priority` when an operation is pushed, which is what the
+    /// `Ord` impl compares.
+    fn effective_priority(&self, now: Instant) -> u32 {
+        if self
+            .soft_deadline()
+            .map_or(false, |deadline| now >= deadline)
+        {
+            return 0;
+        }
+        let Some(created_at) = self.created_at() else {
+            return self.priority();
+        };
+        let age = now.saturating_duration_since(created_at);
+        let boost = age.as_secs() / PRIORITY_BOOST_INTERVAL.as_secs();
+        self.priority()
+            .saturating_sub(boost.try_into().unwrap_or(u32::MAX))
+    }
+
+    /// The `effective_priority` of this operation as of when it was last
+    /// pushed to a queue, or `None` if it hasn't been. Defaults to `None`.
+    fn queued_priority(&self) -> Option<u32> {
+        None
+    }
+
+    /// Store `priority` as the `queued_priority`. Queues call this with the
+    /// `effective_priority` when an operation is pushed, so that its rank
+    /// doesn't change while it waits in the queue. Defaults to a no-op, in
+    /// which case operations are never boosted.
+    fn set_queued_priority(&mut self, _priority: u32) {}
+
     /// The domain this originates from.
     fn origin_domain_id(&self) -> u32;
 
+    /// An id used to correlate this operation with related events on other
+    /// chains, e.g. the original dispatch of a multi-hop message. Defaults to
+    /// `None`.
+    fn correlation_id(&self) -> Option<H256> {
+        None
+    }
+
+    /// The id of an operation that has to be delivered before this one can
+    /// be, e.g. because this one's metadata needs it. See
+    /// `resolve_dependencies`. Defaults to `None`.
+    fn depends_on(&self) -> Option<H256> {
+        None
+    }
+
+    /// The id of an operation that has to be confirmed on-chain before this
+    /// one is even prepared, e.g. for stateful apps that need strict
+    /// ordering. Unlike `depends_on`, which only orders operations that are
+    /// queued together, the gate holds until `is_delivered` reports the
+    /// dependency as delivered. See `check_prepare_gate`. Defaults to `None`.
+    fn prepare_gate(&self) -> Option<H256> {
+        None
+    }
+
+    /// Set the id returned by `correlation_id`. By default operations don't
+    /// track a correlation id and this does nothing.
+    fn set_correlation_id(&mut self, _id: H256) {}
+
     /// The domain this operation will take place on.
     fn destination_domain(&self) -> &HyperlaneDomain;
 
+    /// The id of the domain this operation will take place on.
+    fn destination_domain_id(&self) -> u32 {
+        self.destination_domain().id()
+    }
+
     /// Label to use for metrics granularity.
-    fn app_context(&self) -> Option<String>;
+    fn app_context(&self) -> Option<AppContext>;
+
+    /// `app_context` as a metrics label, with a missing app context becoming
+    /// `"unknown"`. `AppContext` is always safe to use as a label.
+    fn normalized_app_context(&self) -> String {
+        self.app_context()
+            .map(|app_context| app_context.to_string())
+            .unwrap_or_else(|| "unknown".to_string())
+    }
+
+    /// Key identifying the metadata this operation needs, e.g. a hash of the
+    /// ISM and the checkpoint it verifies. Operations returning the same key
+    /// can share a single metadata fetch through a `MetadataCoalescer`.
+    /// Defaults to `None`, i.e. the metadata is never shared.
+    fn metadata_cache_key(&self) -> Option<H256> {
+        None
+    }
 
     /// Get tuple of labels for metrics.
     fn get_operation_labels(&self) -> (String, String) {
-        let app_context = self.app_context().unwrap_or("Unknown".to_string());
+        let app_context = self.normalized_app_context();
         let destination = self.destination_domain().to_string();
         (destination, app_context)
     }
 
+    /// Human-readable summary of this operation for operator dashboards, e.g.
+    /// "Operation #42 from ethereum to arbitrum, ready".
+    fn describe(&self) -> String {
+        let readiness = match self.next_attempt_after() {
+            Some(next_attempt) => {
+                let now = Instant::now();
+                if next_attempt > now {
+                    format!(
+                        "next attempt in {}s",
+                        next_attempt.duration_since(now).as_secs()
+                    )
+                } else {
+                    "ready".to_owned()
+                }
+            }
+            None => "ready".to_owned(),
+        };
+        format!(
+            "Operation #{} from {} to {}, {}",
+            self.priority(),
+            crate::utils::fmt_domain(self.origin_domain_id()),
+            self.destination_domain(),
+            readiness
+        )
+    }
+
+    /// A structured JSON line describing the outcome of running `stage` on
+    /// this operation, for support teams to append to a file and replay
+    /// offline. See `ReplayLog` for a full, ordered log of submitter decisions.
+    fn replay_event(
+        &self,
+        stage: OperationStage,
+        result: &PendingOperationResult,
+    ) -> serde_json::Value {
+        serde_json::json!({
+            "operation_id": self.id(),
+            "origin_domain_id": self.origin_domain_id(),
+            "destination_domain_id": self.destination_domain_id(),
+            "priority": self.priority(),
+            "stage": stage,
+            "result": result,
+        })
+    }
+
+    /// Record how long the last call to the lifecycle `stage` took. By default
+    /// operations don't track timings and this does nothing.
+    fn record_stage_timing(&mut self, _stage: OperationStage, _elapsed: Duration) {}
+
+    /// The timings recorded by `record_stage_timing`.
+    fn stage_timings(&self) -> StageTimings {
+        StageTimings::default()
+    }
+
+    /// Record the result of the last lifecycle `stage` this operation went
+    /// through. By default operations don't track results and this does
+    /// nothing.
+    fn record_stage_result(&mut self, _stage: OperationStage, _result: PendingOperationResult) {}
+
+    /// The stage and result recorded by `record_stage_result`.
+    fn last_stage_result(&self) -> Option<(OperationStage, PendingOperationResult)> {
+        None
+    }
+
+    /// A serializable snapshot of this operation's state, e.g. for exporting
+    /// the contents of the submitter queues to a control-plane UI.
+    fn to_snapshot(&self) -> OperationSnapshot {
+        OperationSnapshot {
+            id: self.id(),
+            origin_domain_id: self.origin_domain_id(),
+            destination_domain_id: self.destination_domain_id(),
+            priority: self.priority(),
+            next_attempt_after_ms: self.next_attempt_after().map(instant_to_unix_ms),
+            cost_estimate: self.get_tx_cost_estimate(),
+            correlation_id: self.correlation_id(),
+            stage_timings: self.stage_timings(),
+            attempt_history: self
+                .attempt_history()
+                .iter()
+                .map(|(at, _, reason)| (instant_to_unix_ms(*at), reason.clone()))
+                .collect(),
+            operator_tags: self.operator_tags().clone(),
+            estimated_confirmation_time: self.estimated_confirmation_time(),
+            current_confirmation_depth: self.current_confirmation_depth(),
+            time_in_queue: self.time_in_queue(Instant::now()),
+        }
+    }
+
+    /// Whether `other` is the same operation in the same state, e.g. for
+    /// detecting changes when diffing snapshots of a queue. Unlike `Eq` on
+    /// `QueueOperation`, which only compares ids so that queues can dedup
+    /// operations, this also compares the last stage the operation went
+    /// through and its result, its retry count and its next attempt time.
+    /// Timings aren't compared, since they change on every attempt even when
+    /// the state doesn't.
+    fn state_eq(&self, other: &dyn PendingOperation) -> bool {
+        self.id() == other.id()
+            && self.last_stage_result() == other.last_stage_result()
+            && self.retry_count() == other.retry_count()
+            && self.next_attempt_after() == other.next_attempt_after()
+    }
+
+    /// The scheduling state of this operation in a compact binary form, e.g.
+    /// for handing it to another process.
+    fn to_serializable(&self) -> SerializableOperation {
+        SerializableOperation {
+            id: self.id(),
+            origin_domain_id: self.origin_domain_id(),
+            destination_domain_id: self.destination_domain_id(),
+            priority: self.priority(),
+            retry_count: self.retry_count(),
+            cost_estimate: self.get_tx_cost_estimate(),
+            correlation_id: self.correlation_id(),
+        }
+    }
+
+    /// The most recent failed attempts of this operation, oldest first, with
+    /// when, in which stage and why each one failed. Defaults to none.
+    fn attempt_history(&self) -> &[(Instant, OperationStage, String)] {
+        &[]
+    }
+
+    /// Record an attempt of this operation that failed in `stage`, see
+    /// `attempt_history`. Defaults to doing nothing.
+    fn push_attempt(&mut self, _stage: OperationStage, _reason: String) {}
+
+    /// How much this operation is cycling between stages rather than making
+    /// progress, as the share of its recent failed attempts that sent it back
+    /// to be prepared after it had been prepared, e.g. because its
+    /// submission reverted, from 0 (none) to 1 (all). Failures while
+    /// preparing, e.g. metadata or a gas payment that isn't available yet,
+    /// don't count, since those never get the operation any further.
+    /// Computed from `attempt_history`, and 0 if it has fewer than three
+    /// entries.
+    fn oscillation_score(&self) -> f64 {
+        let history = self.attempt_history();
+        if history.len() < 3 {
+            return 0.;
+        }
+        let cycles = history
+            .iter()
+            .filter(|(_, stage, _)| *stage != OperationStage::Prepare)
+            .count();
+        cycles as f64 / history.len() as f64
+    }
+
+    /// Custom key/value pairs an operator attached to this operation, e.g. a
+    /// customer id or a ticket number, to be included in its logs and
+    /// snapshots. Defaults to none.
+    fn operator_tags(&self) -> &BTreeMap<String, String> {
+        &NO_OPERATOR_TAGS
+    }
+
+    /// Attach a tag to this operation, see `operator_tags`. Implementations
+    /// should bound the tags, e.g. by storing them in `OperatorTags`.
+    /// Defaults to doing nothing.
+    fn set_operator_tag(&mut self, _key: String, _value: String) {}
+
+    /// The number of times this operation has been retried. Defaults to 0.
+    fn retry_count(&self) -> u32 {
+        0
+    }
+
+    /// The error from the most recent failed attempt, if any. Defaults to
+    /// `None`.
+    fn last_error(&self) -> Option<String> {
+        None
+    }
+
+    /// The record of this operation to keep in a `DeadLetterStore` once it is
+    /// given up on for `reason`.
+    fn to_dead_letter(&self, reason: String) -> DeadLetterRecord {
+        DeadLetterRecord {
+            id: self.id(),
+            origin_domain_id: self.origin_domain_id(),
+            destination_domain_id: self.destination_domain_id(),
+            reason,
+            retry_count: self.retry_count(),
+            last_error: self.last_error(),
+        }
+    }
+
+    /// Whether this operation has already been delivered, e.g. by another
+    /// relayer while this one was down. `prepare_or_warmup` checks this right
+    /// before calling `prepare`, so that no work is wasted on it. Operations
+    /// whose `prepare` already checks for delivery should leave this as the
+    /// default, `Ok(false)`.
+    async fn check_already_delivered(&self) -> ChainResult<bool> {
+        Ok(false)
+    }
+
+    /// Whether the operation `id` has been delivered to this operation's
+    /// destination, according to persistent state such as the destination
+    /// mailbox, so that it holds no matter how long ago or by whom `id` was
+    /// delivered. Used to resolve `prepare_gate`. By default nothing counts
+    /// as delivered, so operations with a gate have to implement this.
+    async fn is_delivered(&self, _id: H256) -> ChainResult<bool> {
+        Ok(false)
+    }
+
+    /// Validate the configuration this kind of operation needs, e.g. that a
+    /// signer is present and the destination RPC is reachable. The submitter
+    /// runs this once per `OperationKind` when operations first arrive, see
+    /// `SelfCheckSampler`, so that misconfiguration is reported right away
+    /// rather than through repeated reprepares. Defaults to `Ok(())`.
+    async fn self_check(&self) -> ChainResult<()> {
+        Ok(())
+    }
+
     /// Prepare to submit this operation. This will be called before every
     /// submission and will usually have a very short gap between it and the
     /// submit call.
     async fn prepare(&mut self) -> PendingOperationResult;
 
-    /// Submit this operation to the blockchain
-    async fn submit(&mut self);
+    /// Prefetch expensive data that `prepare` will need, e.g. a merkle proof,
+    /// while the operation waits for its next attempt. The submitter calls
+    /// this opportunistically, through `prepare_or_warmup`, for operations it
+    /// pops before they are due.
+    ///
+    /// It may be called any number of times before `prepare`, or not at all,
+    /// so implementations should cache what they fetch, return quickly once
+    /// the cache is warm, and have `prepare` fall back to fetching anything
+    /// that is missing or stale. A result other than `Success` is only
+    /// logged. Defaults to a no-op returning `Success`.
+    async fn warmup(&mut self) -> PendingOperationResult {
+        PendingOperationResult::Success
+    }
+
+    /// Called by the submitter immediately before `submit`, to catch
+    /// conditions that may have changed since `prepare`, e.g. gas prices
+    /// rising until the gas payment no longer covers the delivery. Anything
+    /// other than `Success` skips the submission and is handled like the
+    /// result of `prepare`. Defaults to `Success`.
+    async fn revalidate_before_submit(&mut self) -> PendingOperationResult {
+        PendingOperationResult::Success
+    }
+
+    /// Simulate the submission against the destination, e.g. with an
+    /// `eth_call`, returning whether it would succeed, so that doomed
+    /// submissions don't waste gas. Defaults to `Ok(true)`.
+    async fn simulate(&mut self) -> ChainResult<bool> {
+        Ok(true)
+    }
+
+    /// Run `simulate` right before submitting, returning `Success` if the
+    /// submission can go ahead. A revert is recorded as a failed attempt and
+    /// the operation is reprepared, while an error trying to simulate leaves
+    /// it `NotReady` to be tried again later.
+    async fn check_simulation(&mut self) -> PendingOperationResult {
+        match self.simulate().await {
+            Ok(true) => PendingOperationResult::Success,
+            Ok(false) => {
+                self.push_attempt(OperationStage::Submit, "simulation reverted".to_owned());
+                PendingOperationResult::Reprepare
+            }
+            Err(err) => {
+                warn!(?err, operation=?self, "Failed to simulate operation");
+                PendingOperationResult::NotReady
+            }
+        }
+    }
+
+    /// Submit this operation to the blockchain and return the outcome of the
+    /// transaction, or `None` if there was nothing to submit because the
+    /// operation was already submitted, possibly by someone else. Recording
+    /// the outcome (see `set_operation_outcome`) is left to the caller.
+    async fn submit(&mut self) -> ChainResult<Option<TxOutcome>>;
+
+    /// How many more transactions this operation needs to submit before it can
+    /// be confirmed, e.g. a setup transaction followed by the delivery.
+    ///
+    /// The submitter checks this after every `submit` call: while it is
+    /// non-zero the operation goes back to the submit queue and `submit` is
+    /// called again, and once it reaches zero the operation moves on to
+    /// `confirm`. Defaults to 0, i.e. a single transaction.
+    fn remaining_submissions(&self) -> u32 {
+        0
+    }
+
+    /// Whether a successful `submit` is terminal for this operation, so that
+    /// `confirm` is never called. Useful for low-value operations where the
+    /// reorg risk is acceptable and the extra RPC load isn't. Defaults to
+    /// `false`.
+    fn skip_confirmation(&self) -> bool {
+        false
+    }
+
+    /// The stage the submitter should run next after a `submit` call: `Submit`
+    /// again while `remaining_submissions` is non-zero, then `Confirm`, or
+    /// `None` if the operation is done because it `skip_confirmation`s.
+    fn stage_after_submission(&self) -> Option<OperationStage> {
+        if self.remaining_submissions() > 0 {
+            Some(OperationStage::Submit)
+        } else if self.skip_confirmation() {
+            None
+        } else {
+            Some(OperationStage::Confirm)
+        }
+    }
+
+    /// Whether this operation may be batched with others at all, e.g. `false`
+    /// for operations that need a standalone transaction even though they
+    /// can be converted with `try_batch`. Defaults to `true`.
+    fn is_batchable(&self) -> bool {
+        true
+    }
 
-    /// Set the outcome of the `submit` call
+    /// Whether this operation must succeed or fail together with every other
+    /// operation it is batched with.
+    ///
+    /// If any operation in a batch requires atomicity, a batch that reverts or
+    /// can't be submitted is treated as failed for all of its members: they are
+    /// all sent back to be reprepared, rather than confirmed individually or
+    /// resubmitted one by one. Defaults to `false`.
+    fn requires_atomic_batch(&self) -> bool {
+        false
+    }
+
+    /// Set the outcome of the `submit` call. Used when the outcome was not
+    /// returned by `submit` itself, e.g. when recovering a submission.
     fn set_submission_outcome(&mut self, outcome: TxOutcome);
 
+    /// Record `outcome` and return the next step for the operation. A
+    /// transaction that was mined but reverted is not a success, so it
+    /// results in `Reprepare` rather than `Confirm`.
+    fn record_outcome_with_status(&mut self, outcome: TxOutcome) -> PendingOperationResult {
+        let executed = outcome.executed;
+        self.set_submission_outcome(outcome);
+        if executed {
+            PendingOperationResult::Confirm
+        } else {
+            PendingOperationResult::Reprepare
+        }
+    }
+
     /// Get the estimated the cost of the `submit` call
     fn get_tx_cost_estimate(&self) -> Option<U256>;
 
+    /// The `(low, high)` bounds of the cost of the `submit` call, e.g. for
+    /// reasoning about the worst-case cost of a batch. Implementations that
+    /// override this should return the midpoint of the range from
+    /// `get_tx_cost_estimate`, see `cost_estimate_midpoint`, so that callers
+    /// relying on a single estimate keep working. Defaults to
+    /// `get_tx_cost_estimate` as both bounds.
+    fn cost_estimate_range(&self) -> Option<(U256, U256)> {
+        self.get_tx_cost_estimate()
+            .map(|cost_estimate| (cost_estimate, cost_estimate))
+    }
+
+    /// What the submitter should do with this operation if it has no cost
+    /// estimate. Defaults to `MissingEstimatePolicy::RequeueForEstimate`.
+    fn on_missing_estimate(&self) -> MissingEstimatePolicy {
+        MissingEstimatePolicy::RequeueForEstimate
+    }
+
+    /// Check that this operation has a cost estimate before it is submitted,
+    /// applying `on_missing_estimate` if it doesn't. Returns `Success` if it
+    /// can be submitted.
+    fn check_cost_estimate(&mut self) -> PendingOperationResult {
+        if self.get_tx_cost_estimate().is_some() {
+            return PendingOperationResult::Success;
+        }
+        match self.on_missing_estimate() {
+            MissingEstimatePolicy::SubmitAnyway => PendingOperationResult::Success,
+            MissingEstimatePolicy::RequeueForEstimate => {
+                self.push_attempt(OperationStage::Submit, "missing cost estimate".to_owned());
+                PendingOperationResult::Reprepare
+            }
+            MissingEstimatePolicy::Drop => PendingOperationResult::Drop,
+        }
+    }
+
+    /// Recompute the cost estimate returned by `get_tx_cost_estimate`, e.g.
+    /// right before the operation is submitted as part of a batch. Defaults
+    /// to returning the current estimate, or zero if there is none.
+    async fn refresh_cost_estimate(&mut self) -> ChainResult<U256> {
+        Ok(self.get_tx_cost_estimate().unwrap_or_default())
+    }
+
+    /// How many operations like this one batch helpers such as
+    /// `refresh_all_estimates` process before yielding to the executor, so
+    /// that a large batch doesn't starve other tasks. Defaults to
+    /// `DEFAULT_BATCH_CHUNK_SIZE`.
+    fn batch_chunk_size(&self) -> usize {
+        DEFAULT_BATCH_CHUNK_SIZE
+    }
+
+    /// How long ago the cost estimate returned by `get_tx_cost_estimate` was
+    /// computed. Defaults to `None`, i.e. the estimate never goes stale.
+    fn cost_estimate_age(&self) -> Option<Duration> {
+        None
+    }
+
+    /// Whether the cost estimate is older than `max_age` and the operation
+    /// should be reprepared to refresh it before being submitted.
+    fn is_cost_estimate_stale(&self, max_age: Duration) -> bool {
+        self.cost_estimate_age()
+            .map(|age| age > max_age)
+            .unwrap_or(false)
+    }
+
     /// This will be called after the operation has been submitted and is
     /// responsible for checking if the operation has reached a point at
     /// which we consider it safe from reorgs.
     async fn confirm(&mut self) -> PendingOperationResult;
 
+    /// The number of confirmations, counting the block that includes the
+    /// submission, that must be observed before the operation is considered
+    /// safe from reorgs. Operators can raise this for high-value routes.
+    /// Enforced by `confirm_with_deadline`, see `has_reorg_safe_depth`.
+    ///
+    /// Defaults to 1, i.e. inclusion is enough. This matches the existing
+    /// behaviour, which only relies on the confirm delay.
+    fn reorg_depth_required(&self) -> u32 {
+        1
+    }
+
+    /// Whether a submission with `confirmations` confirmations is deep
+    /// enough, according to `reorg_depth_required`, to be promoted to
+    /// `Success`.
+    fn is_reorg_safe(&self, confirmations: u32) -> bool {
+        confirmations >= self.reorg_depth_required()
+    }
+
+    /// The number of confirmations of the submission that the latest call to
+    /// `confirm` observed, e.g. for showing progress towards
+    /// `reorg_depth_required` on dashboards. Defaults to `None`, i.e. unknown.
+    fn current_confirmation_depth(&self) -> Option<u32> {
+        None
+    }
+
+    /// Whether the `current_confirmation_depth` is reorg safe. If the depth is
+    /// unknown, only a `reorg_depth_required` of at most 1 is met, since
+    /// inclusion is all that a successful `confirm` vouches for, so
+    /// operations that raise it must report their depth.
+    fn has_reorg_safe_depth(&self) -> bool {
+        match self.current_confirmation_depth() {
+            Some(depth) => self.is_reorg_safe(depth),
+            None => self.reorg_depth_required() <= 1,
+        }
+    }
+
+    /// The expected time between blocks on the destination chain. Defaults to
+    /// `DEFAULT_BLOCK_TIME`.
+    fn destination_block_time(&self) -> Duration {
+        DEFAULT_BLOCK_TIME
+    }
+
+    /// How long this operation is expected to take to be confirmed once
+    /// submitted, i.e. `reorg_depth_required` blocks of
+    /// `destination_block_time`, e.g. for reporting an ETA. `None` if it
+    /// can't be estimated. Since `destination_block_time` falls back to
+    /// `DEFAULT_BLOCK_TIME`, the default is always an estimate.
+    fn estimated_confirmation_time(&self) -> Option<Duration> {
+        self.destination_block_time()
+            .checked_mul(self.reorg_depth_required())
+    }
+
+    /// Delay the next confirmation attempt by roughly `blocks` destination
+    /// blocks, e.g. when handling `PendingOperationResult::ConfirmAfter`.
+    fn delay_confirmation_by_blocks(&mut self, blocks: u32) {
+        self.set_next_attempt_after(self.destination_block_time() * blocks);
+    }
+
+    /// How long to wait between calls to `confirm` that return `NotReady`,
+    /// so that destinations with slow blocks aren't polled needlessly often.
+    /// Defaults to `DEFAULT_CONFIRM_POLL_INTERVAL`, since the default
+    /// `destination_block_time` is only a guess. Operations that know their
+    /// destination's block time can poll at that instead.
+    fn confirm_poll_interval(&self) -> Duration {
+        DEFAULT_CONFIRM_POLL_INTERVAL
+    }
+
+    /// How long to hold off submitting, given the number of transactions
+    /// currently pending on the destination, so that submitting during
+    /// congestion doesn't just add to the fee competition. Implementations
+    /// should scale the backoff with `pending_tx_count`. Defaults to `None`,
+    /// i.e. no backoff.
+    fn congestion_backoff(&self, _pending_tx_count: u64) -> Option<Duration> {
+        None
+    }
+
+    /// Space out the next call to `confirm`, after one at `now` that didn't
+    /// succeed, by `confirm_poll_interval`. Leaves a next attempt that is
+    /// already scheduled after `now` alone, e.g. a backoff set by `confirm`
+    /// itself.
+    fn schedule_next_confirm(&mut self, now: Instant) {
+        if self.next_attempt_after().map_or(true, |after| after <= now) {
+            self.set_next_attempt_at(now + self.confirm_poll_interval());
+        }
+    }
+
+    /// When to stop waiting for `confirm` to stop returning `NotReady`, e.g.
+    /// because the destination chain has stalled. Defaults to `None`, i.e.
+    /// wait indefinitely.
+    fn confirm_deadline(&self) -> Option<Instant> {
+        None
+    }
+
+    /// Like `confirm`, but holds a `Success` back as `NotReady` until the
+    /// operation `has_reorg_safe_depth`, and escalates a `NotReady` result to
+    /// `Reprepare` if `confirm_deadline` has passed at `now`. Operations that
+    /// set a deadline must therefore be able to go through `prepare` again
+    /// after being submitted.
+    async fn confirm_with_deadline(&mut self, now: Instant) -> PendingOperationResult {
+        let result = match self.confirm().await {
+            PendingOperationResult::Success if !self.has_reorg_safe_depth() => {
+                trace!(
+                    operation=?self,
+                    depth=?self.current_confirmation_depth(),
+                    required=self.reorg_depth_required(),
+                    "Submission is not deep enough to be confirmed yet"
+                );
+                PendingOperationResult::NotReady
+            }
+            result => result,
+        };
+        match (&result, self.confirm_deadline()) {
+            (PendingOperationResult::NotReady, Some(deadline)) if now >= deadline => {
+                warn!(operation=?self, ?deadline, "Confirmation timed out, repreparing operation");
+                PendingOperationResult::Reprepare
+            }
+            _ => result,
+        }
+    }
+
+    /// Like `prepare`, but returns `NotReady` as soon as `token` is
+    /// cancelled instead of waiting for slow RPCs to finish.
+    #[cfg(feature = "async")]
+    async fn prepare_with_cancel(&mut self, token: CancellationToken) -> PendingOperationResult {
+        run_until_cancelled(token, self.prepare()).await
+    }
+
+    /// Like `submit`, but gives up as soon as `token` is cancelled. Returns
+    /// `None` if it was cancelled, in which case a transaction may or may not
+    /// have been sent, so the operation should go through `confirm` before
+    /// being resubmitted.
+    #[cfg(feature = "async")]
+    async fn submit_with_cancel(
+        &mut self,
+        token: CancellationToken,
+    ) -> Option<ChainResult<Option<TxOutcome>>> {
+        use futures::future::{select, Either};
+
+        let cancelled = token.cancelled();
+        let submission = self.submit();
+        futures::pin_mut!(cancelled, submission);
+        match select(cancelled, submission).await {
+            Either::Left(_) => None,
+            Either::Right((outcome, _)) => Some(outcome),
+        }
+    }
+
+    /// Like `confirm`, but returns `NotReady` as soon as `token` is
+    /// cancelled instead of waiting for slow RPCs to finish.
+    #[cfg(feature = "async")]
+    async fn confirm_with_cancel(&mut self, token: CancellationToken) -> PendingOperationResult {
+        run_until_cancelled(token, self.confirm()).await
+    }
+
+    /// How the cost of a transaction including this operation is attributed
+    /// to it. Defaults to `EvmCostModel`.
+    fn cost_model(&self) -> &dyn CostModel {
+        &EvmCostModel
+    }
+
     /// Record the outcome of the operation
     fn set_operation_outcome(
         &mut self,
@@ -97,6 +843,21 @@ pub trait PendingOperation: Send + Sync + Debug + TryBatchAs<HyperlaneMessage> {
     /// Set the next time this operation should be attempted.
     fn set_next_attempt_after(&mut self, delay: Duration);
 
+    /// Set the next time this operation should be attempted to an absolute
+    /// instant, e.g. when handling `PendingOperationResult::Reschedule`.
+    fn set_next_attempt_at(&mut self, at: Instant) {
+        self.set_next_attempt_after(at.saturating_duration_since(Instant::now()));
+    }
+
+    /// Make this operation due for its next attempt right away, without
+    /// touching its retry count, e.g. when handling
+    /// `PendingOperationResult::ReprepareNow`. Defaults to a zero delay;
+    /// implementations that can should clear `next_attempt_after` so that it
+    /// sorts to the front of the queue.
+    fn clear_next_attempt(&mut self) {
+        self.set_next_attempt_after(Duration::ZERO);
+    }
+
     /// Reset the number of attempts this operation has made, causing it to be
     /// retried immediately.
     fn reset_attempts(&mut self);
@@ -106,126 +867,4225 @@ pub trait PendingOperation: Send + Sync + Debug + TryBatchAs<HyperlaneMessage> {
     fn set_retries(&mut self, retries: u32);
 }
 
-/// Utility fn to calculate the total estimated cost of an operation batch
-pub fn total_estimated_cost(ops: &[Box<dyn PendingOperation>]) -> U256 {
-    ops.iter()
-        .fold(U256::zero(), |acc, op| match op.get_tx_cost_estimate() {
-            Some(cost_estimate) => acc.saturating_add(cost_estimate),
-            None => {
-                warn!(operation=?op, "No cost estimate available for operation, defaulting to 0");
-                acc
-            }
-        })
+/// A priority for an operation of `kind` without one of its own that is added
+/// to a queue of `queue_len` operations, see
+/// `PendingOperation::recompute_priority`. Message deliveries come first,
+/// then gas payments, then custom operations; within a kind, operations are
+/// ordered by queue length.
+pub fn default_priority_for_kind(kind: OperationKind, queue_len: u32) -> u32 {
+    let offset = match kind {
+        OperationKind::MessageDelivery => 0,
+        OperationKind::GasPayment => OPERATION_KIND_PRIORITY_OFFSET,
+        OperationKind::Custom(_) => 2 * OPERATION_KIND_PRIORITY_OFFSET,
+    };
+    offset.saturating_add(queue_len.min(OPERATION_KIND_PRIORITY_OFFSET - 1))
 }
 
-/// Calculate the gas used by an operation (either in a batch or single-submission), by looking at the total cost of the tx,
-/// and the estimated cost of the operation compared to the sum of the estimates of all operations in the batch.
-/// When using this for single-submission rather than a batch,
-/// the `tx_estimated_cost` should be the same as the `tx_estimated_cost`
-pub fn gas_used_by_operation(
-    tx_outcome: &TxOutcome,
-    tx_estimated_cost: U256,
-    operation_estimated_cost: U256,
-) -> ChainResult<U256> {
-    let gas_used_by_tx = FixedPointNumber::try_from(tx_outcome.gas_used)?;
-    let operation_gas_estimate = FixedPointNumber::try_from(operation_estimated_cost)?;
-    let tx_gas_estimate = FixedPointNumber::try_from(tx_estimated_cost)?;
-    let gas_used_by_operation = (gas_used_by_tx * operation_gas_estimate)
-        .checked_div(&tx_gas_estimate)
-        .ok_or(eyre::eyre!("Division by zero"))?;
-    gas_used_by_operation.try_into()
+/// Runs `PendingOperation::self_check` on the first operation of each
+/// `OperationKind` it is given, e.g. the first ones a submitter receives on
+/// startup.
+#[derive(Debug, Default)]
+pub struct SelfCheckSampler {
+    checked: HashSet<OperationKind>,
 }
 
-impl Display for QueueOperation {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "QueueOperation(id: {}, origin: {}, destination: {}, priority: {})",
-            self.id(),
-            self.origin_domain_id(),
-            self.destination_domain(),
-            self.priority()
-        )
+impl SelfCheckSampler {
+    /// Run `self_check` on `op` if no operation of its kind has been checked
+    /// yet, returning the result. `None` if its kind was already checked.
+    pub async fn check(&mut self, op: &dyn PendingOperation) -> Option<ChainResult<()>> {
+        if !self.checked.insert(op.kind()) {
+            return None;
+        }
+        Some(op.self_check().await)
     }
 }
 
-impl PartialOrd for QueueOperation {
-    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
-        Some(self.cmp(other))
+/// Whether `op` should be dropped on receipt because it has already been
+/// delivered, see `PendingOperation::check_already_delivered`. Errors are
+/// logged and treated as not delivered, so that the operation still goes
+/// through `prepare`.
+pub async fn is_already_delivered(op: &dyn PendingOperation) -> bool {
+    match op.check_already_delivered().await {
+        Ok(delivered) => delivered,
+        Err(err) => {
+            warn!(?err, operation=?op, "Failed to check whether operation was delivered");
+            false
+        }
     }
 }
 
-impl PartialEq for QueueOperation {
-    fn eq(&self, other: &Self) -> bool {
-        self.id().eq(&other.id())
+/// Drive `future` to completion unless `token` is cancelled first, in which
+/// case `NotReady` is returned and `future` is dropped.
+#[cfg(feature = "async")]
+async fn run_until_cancelled(
+    token: CancellationToken,
+    future: impl std::future::Future<Output = PendingOperationResult>,
+) -> PendingOperationResult {
+    use futures::future::{select, Either};
+
+    let cancelled = token.cancelled();
+    futures::pin_mut!(cancelled, future);
+    match select(cancelled, future).await {
+        Either::Left(_) => PendingOperationResult::NotReady,
+        Either::Right((result, _)) => result,
     }
 }
 
-impl Eq for QueueOperation {}
+/// Coalesces metadata fetches for operations that share a
+/// `metadata_cache_key`, so that the first caller for a key does the fetch
+/// and every other caller awaits the same result.
+///
+/// Results are kept for as long as the coalescer lives, so it should be
+/// scoped to a single batch of operations.
+#[cfg(feature = "async")]
+pub struct MetadataCoalescer<T> {
+    fetches: std::sync::Mutex<
+        HashMap<H256, futures::future::Shared<futures::future::BoxFuture<'static, T>>>,
+    >,
+}
 
-impl Ord for QueueOperation {
-    fn cmp(&self, other: &Self) -> Ordering {
-        use Ordering::*;
-        match (self.next_attempt_after(), other.next_attempt_after()) {
-            (Some(a), Some(b)) => a.cmp(&b),
-            // No time means it should come before
-            (None, Some(_)) => Less,
-            (Some(_), None) => Greater,
-            (None, None) => {
-                if self.origin_domain_id() == other.origin_domain_id() {
-                    // Should execute in order of nonce for the same origin
-                    self.priority().cmp(&other.priority())
-                } else {
-                    // There is no priority between these messages, so arbitrarily use the id
-                    self.id().cmp(&other.id())
-                }
-            }
+#[cfg(feature = "async")]
+impl<T> Default for MetadataCoalescer<T> {
+    fn default() -> Self {
+        Self {
+            fetches: Default::default(),
         }
     }
 }
 
-/// Possible outcomes of performing an action on a pending operation (such as `prepare`, `submit` or `confirm`).
-#[derive(Debug)]
-pub enum PendingOperationResult {
-    /// Promote to the next step
-    Success,
-    /// This operation is not ready to be attempted again yet
-    NotReady,
-    /// Operation needs to be started from scratch again
-    Reprepare,
-    /// Do not attempt to run the operation again, forget about it
-    Drop,
-    /// Send this message straight to the confirm queue
-    Confirm,
+#[cfg(feature = "async")]
+impl<T: Clone + Send + Sync + 'static> MetadataCoalescer<T> {
+    /// Get the metadata for `key`, calling `fetch` only if no other caller
+    /// has already started fetching it.
+    pub async fn get_or_fetch<F, Fut>(&self, key: H256, fetch: F) -> T
+    where
+        F: FnOnce() -> Fut,
+        Fut: std::future::Future<Output = T> + Send + 'static,
+    {
+        use futures::FutureExt;
+
+        let shared = self
+            .fetches
+            .lock()
+            .expect("metadata coalescer lock poisoned")
+            .entry(key)
+            .or_insert_with(|| fetch().boxed().shared())
+            .clone();
+        shared.await
+    }
 }
 
-/// create a `op_try!` macro for the `on_retry` handler.
-#[macro_export]
-macro_rules! make_op_try {
-    ($on_retry:expr) => {
-        /// Handle a result and either return early with retry or a critical failure on
-        /// error.
-        macro_rules! op_try {
-                            (critical: $e:expr, $ctx:literal) => {
-                                match $e {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        error!(error=?e, concat!("Critical error when ", $ctx));
-                                        #[allow(clippy::redundant_closure_call)]
-                                        return $on_retry();
-                                    }
-                                }
-                            };
-                            ($e:expr, $ctx:literal) => {
-                                match $e {
-                                    Ok(v) => v,
-                                    Err(e) => {
-                                        warn!(error=?e, concat!("Error when ", $ctx));
-                                        #[allow(clippy::redundant_closure_call)]
-                                        return $on_retry();
-                                    }
-                                }
-                            };
-                        }
+/// Notified of the lifecycle transitions of the operations a submitter runs,
+/// e.g. to forward them to webhooks or external queues, see
+/// `OperationObservers`. Callbacks run inline on the submitter's tasks, so
+/// they should return quickly, e.g. by handing the event to a channel. They
+/// all default to doing nothing.
+pub trait OperationObserver: Send + Sync + Debug {
+    /// `stage` is about to be run on `op`.
+    fn on_stage_start(&self, _op: &dyn PendingOperation, _stage: OperationStage) {}
+
+    /// `prepare` was called on the operation `id`, returning `result`.
+    fn on_prepare(&self, _id: H256, _result: &PendingOperationResult) {}
+
+    /// The operation `id` was submitted in a transaction with `outcome`, or
+    /// `None` if the submission failed.
+    fn on_submit(&self, _id: H256, _outcome: Option<&TxOutcome>) {}
+
+    /// `confirm` was called on the operation `id`, returning `result`.
+    fn on_confirm(&self, _id: H256, _result: &PendingOperationResult) {}
+
+    /// The operation `id` was dropped, or dead-lettered, while in `stage`,
+    /// for `reason`.
+    fn on_drop(&self, _id: H256, _stage: OperationStage, _reason: &str) {}
+}
+
+/// An `OperationObserver` that ignores every event.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopOperationObserver;
+
+impl OperationObserver for NoopOperationObserver {}
+
+/// The observers registered with a submitter. Notifying it notifies each of
+/// them in the order they were registered.
+#[derive(Debug, Clone, Default)]
+pub struct OperationObservers(Vec<Arc<dyn OperationObserver>>);
+
+impl OperationObservers {
+    /// Notify `observer` of every event from now on.
+    pub fn register(&mut self, observer: Arc<dyn OperationObserver>) {
+        self.0.push(observer);
+    }
+}
+
+impl OperationObserver for OperationObservers {
+    fn on_stage_start(&self, op: &dyn PendingOperation, stage: OperationStage) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_stage_start(op, stage));
+    }
+
+    fn on_prepare(&self, id: H256, result: &PendingOperationResult) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_prepare(id, result));
+    }
+
+    fn on_submit(&self, id: H256, outcome: Option<&TxOutcome>) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_submit(id, outcome));
+    }
+
+    fn on_confirm(&self, id: H256, result: &PendingOperationResult) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_confirm(id, result));
+    }
+
+    fn on_drop(&self, id: H256, stage: OperationStage, reason: &str) {
+        self.0
+            .iter()
+            .for_each(|observer| observer.on_drop(id, stage, reason));
+    }
+}
+
+/// Decides how long an operation should back off after it is sent back to be
+/// reprepared, independently of the operation type.
+pub trait RetryPolicy: Debug + Send + Sync {
+    /// The delay before the next attempt, given the number of retries so far
+    /// (including the one being scheduled). `None` means the operation should
+    /// not be retried again and can be dropped.
+    fn next_delay(&self, retries: u32) -> Option<Duration>;
+
+    /// When the next attempt should happen if it is scheduled at `now`, or
+    /// `None` if the operation should not be retried again.
+    fn next_attempt_at(&self, retries: u32, now: Instant) -> Option<Instant> {
+        self.next_delay(retries).map(|delay| now + delay)
+    }
+}
+
+/// Retry after the same delay every time, up to `max_retries` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FixedRetryPolicy {
+    /// Delay between attempts
+    pub delay: Duration,
+    /// Number of retries after which the operation is given up on
+    pub max_retries: u32,
+}
+
+impl RetryPolicy for FixedRetryPolicy {
+    fn next_delay(&self, retries: u32) -> Option<Duration> {
+        (retries <= self.max_retries).then_some(self.delay)
+    }
+}
+
+/// Retry after `base_delay * 2^(retries - 1)`, capped at `max_delay`, up to
+/// `max_retries` times.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ExponentialRetryPolicy {
+    /// Delay before the first retry
+    pub base_delay: Duration,
+    /// Upper bound on the delay between attempts
+    pub max_delay: Duration,
+    /// Number of retries after which the operation is given up on
+    pub max_retries: u32,
+}
+
+impl RetryPolicy for ExponentialRetryPolicy {
+    fn next_delay(&self, retries: u32) -> Option<Duration> {
+        if retries > self.max_retries {
+            return None;
+        }
+        let delay = 2u32
+            .checked_pow(retries.saturating_sub(1))
+            .and_then(|factor| self.base_delay.checked_mul(factor))
+            .unwrap_or(self.max_delay);
+        Some(delay.min(self.max_delay))
+    }
+}
+
+/// A deterministic time source for tests, which only moves forward when
+/// `advance` is called. Pass its `now` to the helpers that take the current
+/// time instead of calling `Instant::now()`.
+#[cfg(any(test, feature = "test-utils"))]
+#[derive(Debug, Clone, Copy)]
+pub struct SimClock {
+    now: Instant,
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl Default for SimClock {
+    fn default() -> Self {
+        Self {
+            now: Instant::now(),
+        }
+    }
+}
+
+#[cfg(any(test, feature = "test-utils"))]
+impl SimClock {
+    /// The current simulated time.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Move the simulated time forward by `by`.
+    pub fn advance(&mut self, by: Duration) {
+        self.now += by;
+    }
+}
+
+/// The most stages that `run_lifecycle` runs before giving up on an operation
+/// reaching a terminal state.
+#[cfg(any(test, feature = "test-utils"))]
+pub const MAX_LIFECYCLE_STEPS: usize = 100;
+
+/// Drive `op` through `prepare`, `submit` and `confirm`, following each
+/// result to the next stage, and return the stages that were run along with
+/// their results. Useful for checking that an operation implementation
+/// reaches a terminal state.
+///
+/// Time is simulated with a `SimClock` that is moved forward to the
+/// operation's `next_attempt_after` before each stage, and `confirm` calls
+/// that aren't `Success` are spaced out with `schedule_next_confirm`. A
+/// `submit` that fails, or needs further submissions, results in
+/// `Reprepare` or `NotReady` respectively. Stops once `confirm` succeeds, a
+/// `submit` needs no confirmation, the result `is_terminal`, or after
+/// `MAX_LIFECYCLE_STEPS` stages.
+#[cfg(any(test, feature = "test-utils"))]
+pub async fn run_lifecycle(
+    op: &mut dyn PendingOperation,
+) -> Vec<(OperationStage, PendingOperationResult)> {
+    let mut clock = SimClock::default();
+    let mut stage = OperationStage::Prepare;
+    let mut trace = Vec::new();
+    while trace.len() < MAX_LIFECYCLE_STEPS {
+        if let Some(after) = op.next_attempt_after() {
+            clock.advance(after.saturating_duration_since(clock.now()));
+        }
+        let now = clock.now();
+        let result = match stage {
+            OperationStage::Prepare => op.prepare().await,
+            OperationStage::Submit => {
+                let result = match op.submit().await {
+                    Ok(Some(outcome)) => op.record_outcome_with_status(outcome),
+                    // already submitted, so carry on as if it had just been
+                    Ok(None) => PendingOperationResult::Confirm,
+                    Err(_) => PendingOperationResult::Reprepare,
+                };
+                match result {
+                    PendingOperationResult::Confirm => match op.stage_after_submission() {
+                        Some(OperationStage::Submit) => PendingOperationResult::NotReady,
+                        Some(_) => PendingOperationResult::Confirm,
+                        None => PendingOperationResult::Success,
+                    },
+                    result => result,
+                }
+            }
+            OperationStage::Confirm => op.confirm_with_deadline(now).await,
+        };
+        trace.push((stage, result.clone()));
+        match result {
+            PendingOperationResult::Success => match stage {
+                OperationStage::Prepare => stage = OperationStage::Submit,
+                OperationStage::Submit | OperationStage::Confirm => break,
+            },
+            PendingOperationResult::NotReady => {
+                if stage == OperationStage::Confirm {
+                    op.schedule_next_confirm(now);
+                }
+            }
+            PendingOperationResult::Reschedule(at) => op.set_next_attempt_at(at),
+            PendingOperationResult::Reprepare | PendingOperationResult::ReprepareNow(_) => {
+                stage = OperationStage::Prepare
+            }
+            PendingOperationResult::Confirm => stage = OperationStage::Confirm,
+            PendingOperationResult::ConfirmAfter(blocks) => {
+                op.delay_confirmation_by_blocks(blocks);
+                stage = OperationStage::Confirm;
+            }
+            PendingOperationResult::Drop | PendingOperationResult::DeadLetter(_) => break,
+        }
+    }
+    trace
+}
+
+/// Stops operations from being submitted to destination domains that keep
+/// failing.
+///
+/// A domain's breaker trips (opens) after `failure_threshold` consecutive
+/// failures. Once `cooldown` has passed it becomes half-open and lets a single
+/// submission through as a probe: a success closes it, while a failure opens
+/// it for another `cooldown`. Submissions stay held back while the probe is in
+/// flight, unless it isn't resolved within `cooldown`, e.g. because the probe
+/// never sent a transaction, in which case another probe is let through.
+#[derive(Debug, Clone)]
+pub struct DomainCircuitBreaker {
+    failure_threshold: u32,
+    cooldown: Duration,
+    states: HashMap<HyperlaneDomain, CircuitState>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum CircuitState {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { probe_sent_at: Instant },
+}
+
+impl DomainCircuitBreaker {
+    /// Create a breaker that trips after `failure_threshold` consecutive
+    /// failures and probes again after `cooldown`.
+    pub fn new(failure_threshold: u32, cooldown: Duration) -> Self {
+        Self {
+            failure_threshold,
+            cooldown,
+            states: HashMap::new(),
+        }
+    }
+
+    /// Record a failed submission to `domain`.
+    pub fn record_failure(&mut self, domain: &HyperlaneDomain, now: Instant) {
+        let state = self
+            .states
+            .entry(domain.clone())
+            .or_insert(CircuitState::Closed { failures: 0 });
+        *state = match *state {
+            CircuitState::Closed { failures } if failures + 1 < self.failure_threshold => {
+                CircuitState::Closed {
+                    failures: failures + 1,
+                }
+            }
+            _ => CircuitState::Open {
+                until: now + self.cooldown,
+            },
+        };
+    }
+
+    /// Record a successful submission to `domain`, closing its breaker.
+    pub fn record_success(&mut self, domain: &HyperlaneDomain) {
+        self.states.remove(domain);
+    }
+
+    /// Whether submissions to `domain` should be held back at `now`. An open
+    /// breaker whose cooldown has passed becomes half-open, and lets the
+    /// caller send a probe, after which it is open to every other caller
+    /// until the probe is recorded as a success or failure.
+    pub fn is_open(&mut self, domain: &HyperlaneDomain, now: Instant) -> bool {
+        let Some(state) = self.states.get_mut(domain) else {
+            return false;
+        };
+        match *state {
+            CircuitState::Open { until } if now < until => true,
+            CircuitState::HalfOpen { probe_sent_at } if now < probe_sent_at + self.cooldown => true,
+            CircuitState::Open { .. } | CircuitState::HalfOpen { .. } => {
+                *state = CircuitState::HalfOpen { probe_sent_at: now };
+                false
+            }
+            CircuitState::Closed { .. } => false,
+        }
+    }
+
+    /// Whether `domain`'s breaker is half-open, i.e. only a single probe
+    /// should be submitted to it.
+    pub fn is_half_open(&self, domain: &HyperlaneDomain) -> bool {
+        matches!(self.states.get(domain), Some(CircuitState::HalfOpen { .. }))
+    }
+}
+
+/// Limits how many operations of each app context are submitted within a
+/// sliding `window`, so that a single noisy application can't monopolize
+/// submission throughput. Contexts are keyed by
+/// `PendingOperation::normalized_app_context`.
+#[derive(Debug, Clone)]
+pub struct AppContextRateLimiter {
+    max_submissions: usize,
+    window: Duration,
+    submissions: HashMap<String, VecDeque<Instant>>,
+}
+
+impl AppContextRateLimiter {
+    /// Create a limiter that allows at most `max_submissions` per app context
+    /// within any `window`.
+    pub fn new(max_submissions: usize, window: Duration) -> Self {
+        Self {
+            max_submissions,
+            window,
+            submissions: HashMap::new(),
+        }
+    }
+
+    /// Whether another operation of app context `ctx` may be submitted at
+    /// `now`.
+    pub fn should_allow(&self, ctx: &str, now: Instant) -> bool {
+        let Some(submissions) = self.submissions.get(ctx) else {
+            return true;
+        };
+        let in_window = submissions
+            .iter()
+            .filter(|at| now.saturating_duration_since(**at) < self.window)
+            .count();
+        in_window < self.max_submissions
+    }
+
+    /// Record that an operation of app context `ctx` was submitted at `now`.
+    pub fn record_submission(&mut self, ctx: &str, now: Instant) {
+        let submissions = self.submissions.entry(ctx.to_owned()).or_default();
+        while submissions
+            .front()
+            .map(|at| now.saturating_duration_since(*at) >= self.window)
+            .unwrap_or(false)
+        {
+            submissions.pop_front();
+        }
+        submissions.push_back(now);
+    }
+}
+
+/// Refresh the cost estimates of all of `ops`, returning the result for each
+/// operation in order. Operations are refreshed concurrently in chunks of the
+/// first one's `batch_chunk_size`, yielding to the executor between chunks.
+#[cfg(feature = "async")]
+pub async fn refresh_all_estimates(ops: &mut [QueueOperation]) -> Vec<ChainResult<U256>> {
+    let chunk_size = ops.first().map_or(1, |op| op.batch_chunk_size().max(1));
+    let mut results = Vec::with_capacity(ops.len());
+    for (i, chunk) in ops.chunks_mut(chunk_size).enumerate() {
+        if i > 0 {
+            tokio::task::yield_now().await;
+        }
+        results.extend(
+            futures::future::join_all(chunk.iter_mut().map(|op| op.refresh_cost_estimate())).await,
+        );
+    }
+    results
+}
+
+/// The midpoint of a cost estimate range, see
+/// `PendingOperation::cost_estimate_range`, rounded down.
+pub fn cost_estimate_midpoint(low: U256, high: U256) -> U256 {
+    // avoids overflowing for bounds close to `U256::MAX`
+    low / 2 + high / 2 + (low % 2 + high % 2) / 2
+}
+
+/// Sum the `cost_estimate_range` of every operation in `ops` into the
+/// `(low, high)` bounds of the cost of the whole batch. Like
+/// `total_estimated_cost`, operations without a cost estimate are counted as
+/// free, with a warning.
+pub fn total_estimated_cost_range(ops: &[QueueOperation]) -> (U256, U256) {
+    ops.iter().fold(
+        (U256::zero(), U256::zero()),
+        |(low, high), op| match op.cost_estimate_range() {
+            Some((op_low, op_high)) => (low.saturating_add(op_low), high.saturating_add(op_high)),
+            None => {
+                warn!(operation=?op, "No cost estimate available for operation, defaulting to 0");
+                (low, high)
+            }
+        },
+    )
+}
+
+/// Utility fn to calculate the total estimated cost of an operation batch
+pub fn total_estimated_cost(ops: &[Box<dyn PendingOperation>]) -> U256 {
+    ops.iter()
+        .fold(U256::zero(), |acc, op| match op.get_tx_cost_estimate() {
+            Some(cost_estimate) => acc.saturating_add(cost_estimate),
+            None => {
+                warn!(operation=?op, "No cost estimate available for operation, defaulting to 0");
+                acc
+            }
+        })
+}
+
+/// Whether a batch of operations has to be handled atomically, i.e. whether
+/// any of its members `requires_atomic_batch`.
+pub fn batch_is_atomic(ops: &[QueueOperation]) -> bool {
+    ops.iter().any(|op| op.requires_atomic_batch())
+}
+
+/// Map the outcome of a non-atomic batch back to its operations, given the
+/// ids of the ones that were delivered, e.g. according to the tx's event
+/// logs. Delivered operations should be confirmed and the rest reprepared.
+/// The results are in the same order as `ops`.
+pub fn split_batch_outcome(
+    ops: &[QueueOperation],
+    delivered_ids: &[H256],
+) -> Vec<PendingOperationResult> {
+    let delivered: HashSet<_> = delivered_ids.iter().collect();
+    ops.iter()
+        .map(|op| {
+            if delivered.contains(&op.id()) {
+                PendingOperationResult::Confirm
+            } else {
+                PendingOperationResult::Reprepare
+            }
+        })
+        .collect()
+}
+
+/// Aggregate counts over a set of operations, for reporting.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct QueueStats {
+    /// Number of operations per destination domain name
+    pub by_destination: HashMap<String, usize>,
+    /// Number of operations per kind
+    pub by_kind: HashMap<OperationKind, usize>,
+    /// Sum of the operations' cost estimates, see `total_estimated_cost`
+    pub total_estimated_cost: U256,
+}
+
+/// Aggregate `ops` into `QueueStats`.
+pub fn aggregate_stats(ops: &[QueueOperation]) -> QueueStats {
+    let mut stats = QueueStats {
+        total_estimated_cost: total_estimated_cost(ops),
+        ..Default::default()
+    };
+    for op in ops {
+        *stats
+            .by_destination
+            .entry(op.destination_domain().to_string())
+            .or_default() += 1;
+        *stats.by_kind.entry(op.kind()).or_default() += 1;
+    }
+    stats
+}
+
+/// A list of operations that can also be looked up by `id` in constant time.
+///
+/// The index always maps the id of every operation in the list to its
+/// position. The only way to move operations around without going through the
+/// index is `operations_mut`, after which `reindex` must be called.
+#[derive(Debug, Default)]
+pub struct OperationIndex {
+    operations: Vec<QueueOperation>,
+    positions: HashMap<H256, usize>,
+}
+
+impl OperationIndex {
+    /// Build an index over `operations`. If several share an id, only the last
+    /// one is kept.
+    pub fn new(operations: Vec<QueueOperation>) -> Self {
+        let mut index = Self::default();
+        for op in operations {
+            index.insert(op);
+        }
+        index
+    }
+
+    /// The operations, in list order.
+    pub fn operations(&self) -> &[QueueOperation] {
+        &self.operations
+    }
+
+    /// The operations, for reordering in place. `reindex` must be called
+    /// afterwards.
+    pub fn operations_mut(&mut self) -> &mut [QueueOperation] {
+        &mut self.operations
+    }
+
+    /// Look up an operation by id.
+    pub fn get(&self, id: &H256) -> Option<&QueueOperation> {
+        self.positions.get(id).map(|&i| &self.operations[i])
+    }
+
+    /// Add an operation to the end of the list, returning the operation with
+    /// the same id it replaced, if any. A replaced operation's position is
+    /// taken over by the new one.
+    pub fn insert(&mut self, op: QueueOperation) -> Option<QueueOperation> {
+        match self.positions.get(&op.id()) {
+            Some(&i) => Some(std::mem::replace(&mut self.operations[i], op)),
+            None => {
+                self.positions.insert(op.id(), self.operations.len());
+                self.operations.push(op);
+                None
+            }
+        }
+    }
+
+    /// Remove an operation by id. The last operation in the list takes its
+    /// place.
+    pub fn remove(&mut self, id: &H256) -> Option<QueueOperation> {
+        let i = self.positions.remove(id)?;
+        let op = self.operations.swap_remove(i);
+        if let Some(moved) = self.operations.get(i) {
+            self.positions.insert(moved.id(), i);
+        }
+        Some(op)
+    }
+
+    /// Rebuild the index after the list was reordered through
+    /// `operations_mut`.
+    pub fn reindex(&mut self) {
+        self.positions = self
+            .operations
+            .iter()
+            .enumerate()
+            .map(|(i, op)| (op.id(), i))
+            .collect();
+    }
+
+    /// The number of operations.
+    pub fn len(&self) -> usize {
+        self.operations.len()
+    }
+
+    /// Whether there are no operations.
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+}
+
+/// Whether `op` can be prepared: `NotReady` if its `prepare_gate` hasn't been
+/// delivered yet, see `PendingOperation::is_delivered`, `Success` otherwise.
+/// Errors are logged and treated as not delivered, so that the gate is checked
+/// again on the next attempt.
+pub async fn check_prepare_gate(op: &dyn PendingOperation) -> PendingOperationResult {
+    let Some(gate) = op.prepare_gate() else {
+        return PendingOperationResult::Success;
+    };
+    match op.is_delivered(gate).await {
+        Ok(true) => PendingOperationResult::Success,
+        Ok(false) => PendingOperationResult::NotReady,
+        Err(err) => {
+            warn!(?err, operation=?op, ?gate, "Failed to check whether prepare gate was delivered");
+            PendingOperationResult::NotReady
+        }
+    }
+}
+
+/// Caps how many operations are in flight to each destination at once, i.e.
+/// between being submitted and leaving the confirm stage, so that a
+/// destination or its providers aren't overwhelmed. Operations are tracked by
+/// id, so acquiring a slot an operation already holds, e.g. when it is
+/// submitted again, or releasing one it doesn't hold is a no-op.
+#[derive(Debug)]
+pub struct InFlightLimiter {
+    default_limit: usize,
+    limits: HashMap<HyperlaneDomain, usize>,
+    in_flight: Mutex<HashMap<HyperlaneDomain, HashSet<H256>>>,
+}
+
+impl InFlightLimiter {
+    /// Create a limiter that allows at most `default_limit` operations in
+    /// flight to any destination without a limit of its own.
+    pub fn new(default_limit: usize) -> Self {
+        Self {
+            default_limit,
+            limits: HashMap::new(),
+            in_flight: Default::default(),
+        }
+    }
+
+    /// Allow at most `limit` operations in flight to `domain`.
+    pub fn with_limit(mut self, domain: HyperlaneDomain, limit: usize) -> Self {
+        self.limits.insert(domain, limit);
+        self
+    }
+
+    /// Take an in-flight slot on `domain` for the operation `id`, returning
+    /// whether it holds one afterwards.
+    pub fn try_acquire(&self, domain: &HyperlaneDomain, id: H256) -> bool {
+        let limit = self
+            .limits
+            .get(domain)
+            .copied()
+            .unwrap_or(self.default_limit);
+        let mut in_flight = self.lock();
+        let ids = in_flight.entry(domain.clone()).or_default();
+        if ids.contains(&id) {
+            return true;
+        }
+        if ids.len() >= limit {
+            return false;
+        }
+        ids.insert(id)
+    }
+
+    /// Give back the in-flight slot on `domain` held by the operation `id`,
+    /// if any.
+    pub fn release(&self, domain: &HyperlaneDomain, id: H256) {
+        let mut in_flight = self.lock();
+        if let Some(ids) = in_flight.get_mut(domain) {
+            ids.remove(&id);
+            if ids.is_empty() {
+                in_flight.remove(domain);
+            }
+        }
+    }
+
+    /// How many operations are in flight to `domain`.
+    pub fn in_flight(&self, domain: &HyperlaneDomain) -> usize {
+        self.lock().get(domain).map_or(0, HashSet::len)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<HyperlaneDomain, HashSet<H256>>> {
+        self.in_flight
+            .lock()
+            .expect("in-flight operations lock poisoned")
+    }
+}
+
+/// Call `prepare` on `op` if it is due at `now`, or otherwise `warmup` so it
+/// can be prepared quickly once it is, returning `NotReady`. Due operations
+/// that were already delivered are dropped instead of being prepared.
+pub async fn prepare_or_warmup(
+    op: &mut dyn PendingOperation,
+    now: Instant,
+) -> PendingOperationResult {
+    if op.next_attempt_after().map_or(true, |after| after <= now) {
+        if is_already_delivered(op).await {
+            debug!(operation=?op, "Operation has already been delivered, dropping");
+            return PendingOperationResult::Drop;
+        }
+        return op.prepare().await;
+    }
+    let warmup_result = op.warmup().await;
+    if !matches!(warmup_result, PendingOperationResult::Success) {
+        debug!(operation=?op, result=?warmup_result, "Operation warmup did not succeed");
+    }
+    PendingOperationResult::NotReady
+}
+
+/// Remove queue entries that represent the same logical operation (i.e. share
+/// a `content_hash`), keeping the one that would be scheduled first. The
+/// returned operations are sorted by their `Ord` implementation.
+pub fn dedup(mut ops: Vec<QueueOperation>) -> Vec<QueueOperation> {
+    ops.sort();
+    let mut seen = HashSet::new();
+    ops.retain(|op| seen.insert(op.content_hash()));
+    ops
+}
+
+/// Find operations that contradict each other because they share an origin
+/// and nonce, i.e. `origin_domain_id` and `priority`, so that at most one of
+/// them can succeed on-chain. Only operations of the same `kind` are
+/// compared, since other kinds don't use the priority as a nonce in the same
+/// space. Returns every conflicting pair of ids, in the order the operations
+/// appear in `ops`.
+///
+/// The caller should keep one operation of each conflict and drop the rest,
+/// preferring the one with the higher `retry_count`, which has made the most
+/// progress, then the newer one by `created_at`, which reflects the latest
+/// state of the origin.
+pub fn detect_nonce_conflicts(ops: &[QueueOperation]) -> Vec<(H256, H256)> {
+    let mut by_nonce: HashMap<(OperationKind, u32, u32), Vec<H256>> = HashMap::new();
+    let mut conflicts = vec![];
+    for op in ops {
+        let id = op.id();
+        let seen = by_nonce
+            .entry((op.kind(), op.origin_domain_id(), op.priority()))
+            .or_default();
+        conflicts.extend(
+            seen.iter()
+                .filter(|other| **other != id)
+                .map(|other| (*other, id)),
+        );
+        seen.push(id);
+    }
+    conflicts
+}
+
+/// Order `ops` so that no operation comes before the one it `depends_on`,
+/// pulling dependencies forward to the position of their most urgent
+/// dependent, and otherwise by their `Ord` impl. Returns what to do with each
+/// operation, in the new order of `ops`:
+/// - `NotReady` if its dependency is also in `ops`, i.e. it has to wait for
+///   the dependency to leave the queue
+/// - `Drop` if it is part of a dependency cycle, which can never be resolved
+/// - `Success` if it can go ahead
+///
+/// Only dependencies among `ops` are considered.
+pub fn resolve_dependencies(ops: &mut [QueueOperation]) -> Vec<PendingOperationResult> {
+    ops.sort();
+    let index: HashMap<H256, usize> = ops.iter().enumerate().map(|(i, op)| (op.id(), i)).collect();
+    let dependencies: Vec<Option<usize>> = ops
+        .iter()
+        .map(|op| op.depends_on().and_then(|id| index.get(&id).copied()))
+        .collect();
+
+    let in_cycle = |start: usize| {
+        let mut current = start;
+        for _ in 0..ops.len() {
+            match dependencies[current] {
+                Some(next) if next == start => return true,
+                Some(next) => current = next,
+                None => return false,
+            }
+        }
+        false
     };
+    let results: Vec<_> = (0..ops.len())
+        .map(|i| {
+            if in_cycle(i) {
+                PendingOperationResult::Drop
+            } else if dependencies[i].is_some() {
+                PendingOperationResult::NotReady
+            } else {
+                PendingOperationResult::Success
+            }
+        })
+        .collect();
+
+    // Emit each operation right after its not-yet-emitted dependencies,
+    // furthest dependency first
+    let mut order = Vec::with_capacity(ops.len());
+    let mut emitted = vec![false; ops.len()];
+    for start in 0..ops.len() {
+        let mut chain = vec![];
+        let mut current = Some(start);
+        while let Some(i) = current {
+            if emitted[i] || chain.contains(&i) {
+                break;
+            }
+            chain.push(i);
+            current = dependencies[i];
+        }
+        for &i in chain.iter().rev() {
+            emitted[i] = true;
+            order.push(i);
+        }
+    }
+
+    // Move every operation to its new position
+    let mut positions = vec![0; ops.len()];
+    for (new, &old) in order.iter().enumerate() {
+        positions[old] = new;
+    }
+    for i in 0..ops.len() {
+        while positions[i] != i {
+            let j = positions[i];
+            ops.swap(i, j);
+            positions.swap(i, j);
+        }
+    }
+    order.into_iter().map(|i| results[i].clone()).collect()
+}
+
+/// Split `ops` into those that are ready to be attempted at `now` (i.e. have no
+/// `next_attempt_after` or one that has passed) and those that aren't, returned
+/// as `(ready, pending)`. Both groups are sorted by the `Ord` impl.
+pub fn drain_ready(
+    mut ops: Vec<QueueOperation>,
+    now: Instant,
+) -> (Vec<QueueOperation>, Vec<QueueOperation>) {
+    ops.sort();
+    ops.into_iter()
+        .partition(|op| op.next_attempt_after().map_or(true, |after| after <= now))
+}
+
+/// Remove the operations in `ops` whose lifecycle has ended, i.e. whose
+/// `last_stage_result` is terminal, keeping the order of the rest. See
+/// `PendingOperationResult::is_terminal`.
+pub fn retain_active(ops: &mut Vec<QueueOperation>) {
+    ops.retain(|op| {
+        !op.last_stage_result()
+            .is_some_and(|(_, result)| result.is_terminal())
+    });
+}
+
+/// Pull the operations that are ready to be attempted at `now` out of `ops`,
+/// taking at most `max_per_origin` from each origin domain, so that a
+/// high-volume origin
+/// can't starve the others within a single drain cycle.
+///
+/// The drained operations are interleaved round-robin across origins, starting
+/// with the origin of the most urgent operation, and keep their nonce
+/// (`priority`) order within each origin, see `sort_preserving_nonce`.
+/// Everything else is left in `ops`.
+pub fn round_robin_drain(
+    ops: &mut BinaryHeap<QueueOperation>,
+    max_per_origin: usize,
+    now: Instant,
+) -> Vec<QueueOperation> {
+    // `Ord` orders by `next_attempt_after` before nonce, so sorting the whole
+    // heap at once wouldn't keep each origin in nonce order
+    let mut by_origin: HashMap<u32, Vec<QueueOperation>> = HashMap::new();
+    for op in std::mem::take(ops).into_vec() {
+        by_origin.entry(op.origin_domain_id()).or_default().push(op);
+    }
+
+    let mut remaining = vec![];
+    let mut drained_by_origin: Vec<VecDeque<QueueOperation>> = vec![];
+    for mut origin_ops in by_origin.into_values() {
+        origin_ops.sort_by_key(|op| op.priority());
+        let mut drained = VecDeque::new();
+        for op in origin_ops {
+            let is_ready = op.next_attempt_after().map_or(true, |after| after <= now);
+            if is_ready && drained.len() < max_per_origin {
+                drained.push_back(op);
+            } else {
+                remaining.push(op);
+            }
+        }
+        if !drained.is_empty() {
+            drained_by_origin.push(drained);
+        }
+    }
+    *ops = remaining.into();
+    drained_by_origin.sort_by(|a, b| a[0].cmp(&b[0]));
+
+    let total = drained_by_origin.iter().map(VecDeque::len).sum();
+    let mut result = Vec::with_capacity(total);
+    while result.len() < total {
+        for drained in &mut drained_by_origin {
+            if let Some(op) = drained.pop_front() {
+                result.push(op);
+            }
+        }
+    }
+    result
+}
+
+/// Split `ops` into `(batchable, standalone)`, according to `is_batchable`,
+/// preserving their order.
+pub fn partition_batchable(ops: Vec<QueueOperation>) -> (Vec<QueueOperation>, Vec<QueueOperation>) {
+    ops.into_iter().partition(|op| op.is_batchable())
+}
+
+/// Greedily pack `ops`, in `Ord` order, into sub-batches whose total estimated
+/// cost stays within `block_gas_limit`.
+///
+/// An operation that exceeds the limit on its own, or that isn't batchable,
+/// is placed alone in its own sub-batch, for the caller to handle separately.
+/// Like
+/// `total_estimated_cost`, operations without a cost estimate are counted as
+/// free.
+pub fn split_batch_by_gas_limit(
+    mut ops: Vec<QueueOperation>,
+    block_gas_limit: U256,
+) -> Vec<Vec<QueueOperation>> {
+    ops.sort();
+    let mut batches = vec![];
+    let mut batch: Vec<QueueOperation> = vec![];
+    let mut batch_cost = U256::zero();
+    for op in ops {
+        let cost = op.get_tx_cost_estimate().unwrap_or_default();
+        if cost > block_gas_limit || !op.is_batchable() {
+            batches.push(vec![op]);
+            continue;
+        }
+        if batch_cost.saturating_add(cost) > block_gas_limit {
+            batches.push(std::mem::take(&mut batch));
+            batch_cost = U256::zero();
+        }
+        batch_cost = batch_cost.saturating_add(cost);
+        batch.push(op);
+    }
+    if !batch.is_empty() {
+        batches.push(batch);
+    }
+    batches
+}
+
+/// Push back the next attempt of every operation in `ops` to `delay` from now,
+/// e.g. while their destination chain is down.
+pub fn requeue_all(ops: &mut [QueueOperation], delay: Duration) {
+    requeue_matching(ops, |_| true, delay)
+}
+
+/// Like `requeue_all`, but only for the operations matching `predicate`, e.g.
+/// those on a single route.
+pub fn requeue_matching(
+    ops: &mut [QueueOperation],
+    predicate: impl Fn(&dyn PendingOperation) -> bool,
+    delay: Duration,
+) {
+    for op in ops.iter_mut().filter(|op| predicate(op.as_ref())) {
+        op.set_next_attempt_after(delay);
+    }
+}
+
+/// The operations in `ops` matching `predicate`, e.g. for answering control
+/// plane queries without exposing the queues themselves. Predicates can be
+/// combined from `by_destination`, `by_origin`, `by_kind` and `is_retrying`.
+pub fn filter_operations(
+    ops: &[QueueOperation],
+    predicate: impl Fn(&dyn PendingOperation) -> bool,
+) -> Vec<&QueueOperation> {
+    ops.iter().filter(|op| predicate(op.as_ref())).collect()
+}
+
+/// Matches operations that take place on `domain`.
+pub fn by_destination(domain: HyperlaneDomain) -> impl Fn(&dyn PendingOperation) -> bool {
+    move |op| *op.destination_domain() == domain
+}
+
+/// Matches operations that originate from the domain with id `domain_id`.
+pub fn by_origin(domain_id: u32) -> impl Fn(&dyn PendingOperation) -> bool {
+    move |op| op.origin_domain_id() == domain_id
+}
+
+/// Matches operations of kind `kind`.
+pub fn by_kind(kind: OperationKind) -> impl Fn(&dyn PendingOperation) -> bool {
+    move |op| op.kind() == kind
+}
+
+/// Matches operations that have been retried at least once.
+pub fn is_retrying(op: &dyn PendingOperation) -> bool {
+    op.retry_count() > 0
+}
+
+/// How many operations, taken in order from the front of `ops`, fit within
+/// `budget`, along with their cumulative estimated cost.
+///
+/// Operations are charged the upper bound of their `cost_estimate_range`, so
+/// that the budget holds in the worst case. Stops at the first operation that
+/// would exceed the budget. Like `total_estimated_cost`, operations without a
+/// cost estimate are counted as free, with a warning.
+pub fn operations_within_budget(ops: &[QueueOperation], budget: U256) -> (usize, U256) {
+    let mut total = U256::zero();
+    for (count, op) in ops.iter().enumerate() {
+        let cost = op
+            .cost_estimate_range()
+            .map(|(_, high)| high)
+            .unwrap_or_else(|| {
+                warn!(operation=?op, "No cost estimate available for operation, defaulting to 0");
+                U256::zero()
+            });
+        match total.checked_add(cost) {
+            Some(new_total) if new_total <= budget => total = new_total,
+            _ => return (count, total),
+        }
+    }
+    (ops.len(), total)
+}
+
+/// Calculate the gas used by an operation (either in a batch or single-submission), by looking at the total cost of the tx,
+/// and the estimated cost of the operation compared to the sum of the estimates of all operations in the batch.
+/// When using this for single-submission rather than a batch,
+/// the `tx_estimated_cost` should be the same as the `tx_estimated_cost`
+pub fn gas_used_by_operation(
+    tx_outcome: &TxOutcome,
+    tx_estimated_cost: U256,
+    operation_estimated_cost: U256,
+) -> ChainResult<U256> {
+    gas_used_by_operation_rounded(
+        tx_outcome,
+        tx_estimated_cost,
+        operation_estimated_cost,
+        RoundingMode::Floor,
+    )
+}
+
+/// Attributes the cost of a submitted transaction to the operations it
+/// included, in whatever unit the destination chain meters, e.g. gas or
+/// compute units.
+///
+/// Implementations must return an error when `tx_estimated_cost` is zero,
+/// since the operation's share is undefined, rather than attributing an
+/// arbitrary amount.
+pub trait CostModel: Send + Sync + Debug {
+    /// The share of the cost of `tx_outcome` used by an operation, given the
+    /// estimated cost of the operation and of the whole transaction. When the
+    /// operation was submitted on its own, both estimates are the same.
+    fn cost_used_by_operation(
+        &self,
+        tx_outcome: &TxOutcome,
+        tx_estimated_cost: U256,
+        operation_estimated_cost: U256,
+    ) -> ChainResult<U256>;
+}
+
+/// The cost model of gas-metered EVM chains, see `gas_used_by_operation`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EvmCostModel;
+
+impl CostModel for EvmCostModel {
+    fn cost_used_by_operation(
+        &self,
+        tx_outcome: &TxOutcome,
+        tx_estimated_cost: U256,
+        operation_estimated_cost: U256,
+    ) -> ChainResult<U256> {
+        gas_used_by_operation(tx_outcome, tx_estimated_cost, operation_estimated_cost)
+    }
+}
+
+/// What to do with an operation that has no cost estimate when it is about to
+/// be submitted, see `PendingOperation::on_missing_estimate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MissingEstimatePolicy {
+    /// Submit it anyway, counting its cost as zero
+    SubmitAnyway,
+    /// Send it back to be prepared, which computes the estimate
+    RequeueForEstimate,
+    /// Forget about it
+    Drop,
+}
+
+/// How to round a fractional amount of gas to a whole unit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RoundingMode {
+    /// Round down. Never over-attributes.
+    Floor,
+    /// Round up. Never under-attributes, e.g. for cost recovery.
+    Ceil,
+    /// Round to the nearest unit, with halves rounded up.
+    Nearest,
+}
+
+/// Like `gas_used_by_operation`, but rounding the operation's share of the gas
+/// according to `rounding`.
+pub fn gas_used_by_operation_rounded(
+    tx_outcome: &TxOutcome,
+    tx_estimated_cost: U256,
+    operation_estimated_cost: U256,
+    rounding: RoundingMode,
+) -> ChainResult<U256> {
+    let tx_gas_estimate = U512::from(tx_estimated_cost);
+    if tx_gas_estimate.is_zero() {
+        return Err(eyre::eyre!("Division by zero").into());
+    }
+    let gas_used = U512::from(tx_outcome.gas_used) * U512::from(operation_estimated_cost);
+    let (share, remainder) = gas_used.div_mod(tx_gas_estimate);
+    let round_up = match rounding {
+        RoundingMode::Floor => false,
+        RoundingMode::Ceil => !remainder.is_zero(),
+        RoundingMode::Nearest => remainder * 2 >= tx_gas_estimate,
+    };
+    let share = if round_up { share + 1 } else { share };
+    // Only happens if the operation estimate is far larger than the tx one
+    U256::try_from(share).map_err(|_| {
+        eyre::eyre!(
+            "Gas used by operation overflows U256 (tx gas used: {}, operation estimate: {}, tx estimate: {})",
+            tx_outcome.gas_used,
+            operation_estimated_cost,
+            tx_estimated_cost
+        )
+        .into()
+    })
+}
+
+/// Attribute the gas used by a batch transaction to each of its operations in
+/// proportion to their cost `estimates`.
+///
+/// Unlike calling `gas_used_by_operation` for each operation, which truncates
+/// every share and can leave part of the tx gas unattributed, the rounding
+/// remainder is handed out using the largest-remainder method, so the
+/// returned amounts always sum to exactly `tx_outcome.gas_used`. Ties go to
+/// the operation that comes first. Exact integer arithmetic is used so the
+/// remainders can be compared precisely.
+pub fn gas_used_by_operations_batch(
+    tx_outcome: &TxOutcome,
+    estimates: &[U256],
+) -> ChainResult<Vec<U256>> {
+    if estimates.is_empty() {
+        return Ok(vec![]);
+    }
+    let tx_gas_estimate = estimates
+        .iter()
+        .fold(U512::zero(), |acc, estimate| acc + U512::from(estimate));
+    if tx_gas_estimate.is_zero() {
+        return Err(eyre::eyre!("Division by zero").into());
+    }
+    let gas_used_by_tx = U512::from(tx_outcome.gas_used);
+
+    let mut shares = Vec::with_capacity(estimates.len());
+    let mut remainders = Vec::with_capacity(estimates.len());
+    for (index, estimate) in estimates.iter().enumerate() {
+        let (share, remainder) = (gas_used_by_tx * U512::from(estimate)).div_mod(tx_gas_estimate);
+        shares.push(share);
+        remainders.push((remainder, index));
+    }
+
+    // The truncated shares fall short of the tx gas by less than one unit
+    // per operation, so hand one unit each to the largest remainders
+    let attributed = shares.iter().fold(U512::zero(), |acc, share| acc + share);
+    let unattributed = (gas_used_by_tx - attributed).low_u64() as usize;
+    remainders.sort_by(|(a, a_index), (b, b_index)| b.cmp(a).then(a_index.cmp(b_index)));
+    for (_, index) in remainders.into_iter().take(unattributed) {
+        shares[index] += U512::one();
+    }
+
+    shares
+        .into_iter()
+        .map(|share| Ok(U256::try_from(share)?))
+        .collect()
+}
+
+/// Like `gas_used_by_operations_batch`, but no operation is attributed more
+/// than `max_multiple` times its own estimate, so that a batch that blew
+/// through its estimates doesn't inflate per-operation cost metrics.
+///
+/// Shares are proportional to the same estimates the caps are based on, so
+/// either no operation reaches its cap or all of them do at once. There is
+/// therefore never an uncapped operation to hand the excess to: it is left
+/// unattributed, and the returned amounts sum to less than
+/// `tx_outcome.gas_used`.
+pub fn gas_used_capped(
+    tx_outcome: &TxOutcome,
+    estimates: &[U256],
+    max_multiple: FixedPointNumber,
+) -> ChainResult<Vec<U256>> {
+    let shares = gas_used_by_operations_batch(tx_outcome, estimates)?;
+    estimates
+        .iter()
+        .zip(shares)
+        .map(|(estimate, share)| {
+            let cap = to_fixed_point(*estimate, "operation estimate")? * max_multiple.clone();
+            // A cap beyond `U256::MAX` can't be reached by any share
+            let cap: U256 = cap.try_into().unwrap_or(U256::MAX);
+            Ok(share.min(cap))
+        })
+        .collect()
+}
+
+/// The effective gas price paid by a (batch) transaction, for cost analytics.
+/// Errors if no gas was used, since the transaction then has no meaningful
+/// price.
+pub fn batch_effective_gas_price(tx_outcome: &TxOutcome) -> ChainResult<FixedPointNumber> {
+    if tx_outcome.gas_used.is_zero() {
+        return Err(eyre::eyre!("Transaction used no gas").into());
+    }
+    Ok(tx_outcome.gas_price.clone())
+}
+
+/// Convert `value` to a `FixedPointNumber` for cost math, naming which `input`
+/// it is if that fails.
+fn to_fixed_point(value: U256, input: &str) -> ChainResult<FixedPointNumber> {
+    FixedPointNumber::try_from(value).map_err(|err| {
+        ChainCommunicationError::from(eyre::eyre!(
+            "Failed to convert {input} ({value}) to a fixed point number: {err}"
+        ))
+    })
+}
+
+/// The alternate form, `{:#}`, only prints numeric domain ids, so that it is
+/// cheap enough for hot-path logging.
+impl Display for QueueOperation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if f.alternate() {
+            return write!(
+                f,
+                "QueueOperation(id: {}, origin: {}, destination: {}, priority: {})",
+                self.id(),
+                self.origin_domain_id(),
+                self.destination_domain_id(),
+                self.priority()
+            );
+        }
+        write!(
+            f,
+            "QueueOperation(id: {}, origin: {}, destination: {}, priority: {})",
+            self.id(),
+            self.origin_domain_id(),
+            self.destination_domain(),
+            self.priority()
+        )
+    }
+}
+
+impl PartialOrd for QueueOperation {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for QueueOperation {
+    fn eq(&self, other: &Self) -> bool {
+        self.id().eq(&other.id())
+    }
+}
+
+impl Eq for QueueOperation {}
+
+impl Ord for QueueOperation {
+    fn cmp(&self, other: &Self) -> Ordering {
+        use Ordering::*;
+        match (self.next_attempt_after(), other.next_attempt_after()) {
+            (Some(a), Some(b)) => a.cmp(&b),
+            // No time means it should come before
+            (None, Some(_)) => Less,
+            (Some(_), None) => Greater,
+            (None, None) => {
+                if self.origin_domain_id() == other.origin_domain_id() {
+                    // Should execute in order of nonce for the same origin
+                    self.priority().cmp(&other.priority())
+                } else {
+                    // There is no priority between these messages, so prefer the one that
+                    // was boosted more by waiting when it was queued, and otherwise
+                    // arbitrarily use the tie-break key, falling back to the id to stay
+                    // consistent with `Eq`
+                    queued_boost(other.as_ref())
+                        .cmp(&queued_boost(self.as_ref()))
+                        .then_with(|| self.tie_break_key().cmp(&other.tie_break_key()))
+                        .then_with(|| self.id().cmp(&other.id()))
+                }
+            }
+        }
+    }
+}
+
+/// How much `op` was boosted by `effective_priority` when it was last queued.
+fn queued_boost(op: &dyn PendingOperation) -> u32 {
+    op.queued_priority().map_or(0, |queued_priority| {
+        op.priority().saturating_sub(queued_priority)
+    })
+}
+
+/// Sort a drained batch of operations without submitting nonces out of
+/// order.
+///
+/// The `Ord` impl only falls back to `priority` (the nonce) when neither
+/// operation has a `next_attempt_after`, so after a reload, when retried
+/// operations all have one set, same-origin operations are ordered by time
+/// alone and can come out of nonce order. This groups operations by origin,
+/// orders each group by `priority`, and then merges the groups by `Ord` of
+/// their next operation, so the result is deterministic for any mix of
+/// origins.
+pub fn sort_preserving_nonce(ops: &mut Vec<QueueOperation>) {
+    let mut by_origin: BTreeMap<u32, VecDeque<QueueOperation>> = BTreeMap::new();
+    for op in ops.drain(..) {
+        by_origin
+            .entry(op.origin_domain_id())
+            .or_default()
+            .push_back(op);
+    }
+    for group in by_origin.values_mut() {
+        group.make_contiguous().sort_by_key(|op| op.priority());
+    }
+    while let Some(group) = by_origin
+        .values_mut()
+        .filter(|group| !group.is_empty())
+        .min_by(|a, b| a[0].cmp(&b[0]))
+    {
+        ops.extend(group.pop_front());
+    }
+}
+
+/// Possible outcomes of performing an action on a pending operation (such as `prepare`, `submit` or `confirm`).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PendingOperationResult {
+    /// Promote to the next step
+    Success,
+    /// This operation is not ready to be attempted again yet
+    NotReady,
+    /// Operation needs to be started from scratch again
+    Reprepare,
+    /// Do not attempt to run the operation again, forget about it
+    Drop,
+    /// Send this message straight to the confirm queue
+    Confirm,
+    /// Attempt the operation again at the given instant, e.g. a chain-derived
+    /// time like the next epoch boundary. Unlike `NotReady`, which leaves the
+    /// timing of the next attempt to the operation, this sets it explicitly
+    /// through `set_next_attempt_at`.
+    Reschedule(#[serde(with = "instant_unix_ms")] Instant),
+    /// Do not attempt to run the operation again, e.g. because it exhausted
+    /// its retries, but keep a record of it in the dead-letter store for
+    /// later inspection. Unlike `Drop`, the operation isn't silently
+    /// forgotten.
+    DeadLetter(String),
+    /// Wait for this many more destination blocks before attempting `confirm`
+    /// again, e.g. during a suspected reorg. The submitter converts the
+    /// blocks to a delay using `PendingOperation::destination_block_time`.
+    /// This comes on top of `reorg_depth_required`, which the next `confirm`
+    /// still has to satisfy.
+    ConfirmAfter(u32),
+    /// Operation needs to be started from scratch again, right away and for
+    /// the given reason, e.g. because a dependency just became available.
+    /// Unlike `Reprepare`, which leaves the operation to back off, this
+    /// clears its next attempt time, and unlike `reset_attempts` it keeps the
+    /// retry count.
+    ReprepareNow(String),
+}
+
+impl PendingOperationResult {
+    /// A stable label for this result, e.g. for metrics. Inner values such as
+    /// reasons are left out to bound the label's cardinality.
+    pub fn outcome_label(&self) -> &'static str {
+        match self {
+            Self::Success => "success",
+            Self::NotReady => "not_ready",
+            Self::Reprepare => "reprepare",
+            Self::Drop => "drop",
+            Self::Confirm => "confirm",
+            Self::Reschedule(_) => "reschedule",
+            Self::DeadLetter(_) => "dead_letter",
+            Self::ConfirmAfter(_) => "confirm_after",
+            Self::ReprepareNow(_) => "reprepare_now",
+        }
+    }
+
+    /// Whether this result ends the operation's lifecycle, i.e. it won't be
+    /// attempted again in any stage. `Success` isn't terminal, since outside
+    /// of `confirm` it only promotes the operation to the next stage.
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Self::Drop | Self::DeadLetter(_) => true,
+            Self::Success
+            | Self::NotReady
+            | Self::Reprepare
+            | Self::Confirm
+            | Self::Reschedule(_)
+            | Self::ConfirmAfter(_)
+            | Self::ReprepareNow(_) => false,
+        }
+    }
+
+    /// This result as a flat JSON object for audit logs, tagged with its
+    /// `outcome_label` under `"outcome"` and with any inner value as a named
+    /// field. Unlike the serde representation, a `Reschedule` instant is given
+    /// as `after_ms`, the milliseconds from now until it (zero if it has
+    /// passed), so that it doesn't depend on the wall clock.
+    pub fn as_audit_json(&self) -> serde_json::Value {
+        let outcome = self.outcome_label();
+        match self {
+            Self::Reschedule(at) => serde_json::json!({
+                "outcome": outcome,
+                "after_ms": at.saturating_duration_since(Instant::now()).as_millis() as u64,
+            }),
+            Self::DeadLetter(reason) | Self::ReprepareNow(reason) => serde_json::json!({
+                "outcome": outcome,
+                "reason": reason,
+            }),
+            Self::ConfirmAfter(blocks) => serde_json::json!({
+                "outcome": outcome,
+                "blocks": blocks,
+            }),
+            Self::Success | Self::NotReady | Self::Reprepare | Self::Drop | Self::Confirm => {
+                serde_json::json!({ "outcome": outcome })
+            }
+        }
+    }
+}
+
+/// The kind of work a `PendingOperation` does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum OperationKind {
+    /// Delivering a message to its destination mailbox
+    MessageDelivery,
+    /// Paying for interchain gas
+    GasPayment,
+    /// Any other kind of operation, identified by name
+    Custom(&'static str),
+}
+
+impl Display for OperationKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MessageDelivery => write!(f, "message_delivery"),
+            Self::GasPayment => write!(f, "gas_payment"),
+            Self::Custom(name) => write!(f, "{name}"),
+        }
+    }
+}
+
+/// How much to log about an operation's progress, see
+/// `PendingOperation::log_verbosity`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogVerbosity {
+    /// Don't log per-stage events, e.g. for bulk traffic
+    Quiet,
+    /// Log per-stage events at debug level
+    #[default]
+    Normal,
+    /// Log per-stage events at info level, e.g. for high-value routes
+    Verbose,
+}
+
+/// A stage of the pending operation lifecycle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OperationStage {
+    /// `prepare` was called
+    Prepare,
+    /// `submit` was called
+    Submit,
+    /// `confirm` was called
+    Confirm,
+}
+
+/// How long the most recent call to each lifecycle stage of an operation took,
+/// to help spot slow RPC providers.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StageTimings {
+    /// The last `prepare` call
+    pub prepare: Option<Duration>,
+    /// The last `submit` call
+    pub submit: Option<Duration>,
+    /// The last `confirm` call
+    pub confirm: Option<Duration>,
+}
+
+impl StageTimings {
+    /// Record that the last call to `stage` took `elapsed`.
+    pub fn record(&mut self, stage: OperationStage, elapsed: Duration) {
+        let timing = match stage {
+            OperationStage::Prepare => &mut self.prepare,
+            OperationStage::Submit => &mut self.submit,
+            OperationStage::Confirm => &mut self.confirm,
+        };
+        *timing = Some(elapsed);
+    }
+}
+
+/// Hash of the inputs the submitter's decision logic sees for an operation.
+///
+/// `next_attempt_after` is left out on purpose since an `Instant` can't be
+/// reproduced offline.
+pub fn operation_state_hash(op: &dyn PendingOperation) -> H256 {
+    let mut cost_estimate = [0u8; 32];
+    op.get_tx_cost_estimate()
+        .unwrap_or_default()
+        .to_big_endian(&mut cost_estimate);
+    H256::from_slice(
+        Keccak256::new()
+            .chain(op.id())
+            .chain(op.priority().to_be_bytes())
+            .chain(op.origin_domain_id().to_be_bytes())
+            .chain(op.destination_domain_id().to_be_bytes())
+            .chain(cost_estimate)
+            .finalize()
+            .as_slice(),
+    )
+}
+
+/// The state of an operation at a point in time, see
+/// `PendingOperation::to_snapshot`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OperationSnapshot {
+    /// The operation id
+    pub id: H256,
+    /// The domain the operation originates from
+    pub origin_domain_id: u32,
+    /// The domain the operation will take place on
+    pub destination_domain_id: u32,
+    /// The operation priority, lower is more important
+    pub priority: u32,
+    /// When the operation will next be attempted, in milliseconds since the
+    /// unix epoch. `None` if it can be attempted right away.
+    pub next_attempt_after_ms: Option<u64>,
+    /// The estimated cost of submitting the operation, if known
+    pub cost_estimate: Option<U256>,
+    /// See `PendingOperation::correlation_id`
+    pub correlation_id: Option<H256>,
+    /// See `PendingOperation::stage_timings`
+    pub stage_timings: StageTimings,
+    /// See `PendingOperation::attempt_history`, with times in milliseconds
+    /// since the unix epoch
+    pub attempt_history: Vec<(u64, String)>,
+    /// See `PendingOperation::operator_tags`
+    pub operator_tags: BTreeMap<String, String>,
+    /// See `PendingOperation::estimated_confirmation_time`
+    pub estimated_confirmation_time: Option<Duration>,
+    /// See `PendingOperation::current_confirmation_depth`
+    pub current_confirmation_depth: Option<u32>,
+    /// See `PendingOperation::time_in_queue`
+    pub time_in_queue: Duration,
+}
+
+/// The failed attempts of an operation, see
+/// `PendingOperation::attempt_history`. Only the last `MAX_ATTEMPT_HISTORY`
+/// are kept.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AttemptHistory(Vec<(Instant, OperationStage, String)>);
+
+impl AttemptHistory {
+    /// Record an attempt that failed at `at` in `stage` because of `reason`,
+    /// forgetting the oldest one if the history is full.
+    pub fn push(&mut self, at: Instant, stage: OperationStage, reason: String) {
+        if self.0.len() >= MAX_ATTEMPT_HISTORY {
+            self.0.remove(0);
+        }
+        self.0.push((at, stage, reason));
+    }
+
+    /// The recorded attempts, oldest first
+    pub fn entries(&self) -> &[(Instant, OperationStage, String)] {
+        &self.0
+    }
+}
+
+/// Why a string isn't a valid `AppContext`.
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidAppContext {
+    /// The app context is empty
+    #[error("App context is empty")]
+    Empty,
+    /// The app context is longer than `MAX_APP_CONTEXT_LEN` characters
+    #[error("App context is longer than {MAX_APP_CONTEXT_LEN} characters")]
+    TooLong,
+    /// The app context contains a character other than a lowercase ASCII
+    /// letter, a digit, `-`, `_`, `.` or `/`
+    #[error("App context contains invalid character {0:?}")]
+    InvalidCharacter(char),
+}
+
+/// The application an operation belongs to, used to label metrics, see
+/// `PendingOperation::app_context`.
+///
+/// App contexts are non-empty, at most `MAX_APP_CONTEXT_LEN` characters long
+/// and only made of lowercase ASCII letters, digits, `-`, `_`, `.` and `/`, so
+/// that they are always safe to use as metrics labels.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct AppContext(String);
+
+impl AppContext {
+    /// Validate `app_context`.
+    pub fn new(app_context: impl Into<String>) -> Result<Self, InvalidAppContext> {
+        let app_context = app_context.into();
+        if app_context.is_empty() {
+            return Err(InvalidAppContext::Empty);
+        }
+        if app_context.chars().count() > MAX_APP_CONTEXT_LEN {
+            return Err(InvalidAppContext::TooLong);
+        }
+        if let Some(invalid) = app_context.chars().find(|c| !Self::is_valid_char(*c)) {
+            return Err(InvalidAppContext::InvalidCharacter(invalid));
+        }
+        Ok(Self(app_context))
+    }
+
+    /// Turn any string, e.g. a free-form app context from config, into an
+    /// app context by trimming and lowercasing it, replacing invalid
+    /// characters with `_` and truncating it to `MAX_APP_CONTEXT_LEN`
+    /// characters. `None` if nothing is left.
+    pub fn from_str_lossy(app_context: &str) -> Option<Self> {
+        let app_context: String = app_context
+            .trim()
+            .chars()
+            .take(MAX_APP_CONTEXT_LEN)
+            .map(|c| c.to_ascii_lowercase())
+            .map(|c| if Self::is_valid_char(c) { c } else { '_' })
+            .collect();
+        (!app_context.is_empty()).then_some(Self(app_context))
+    }
+
+    /// The app context as a string.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    fn is_valid_char(c: char) -> bool {
+        c.is_ascii_lowercase() || c.is_ascii_digit() || matches!(c, '-' | '_' | '.' | '/')
+    }
+}
+
+impl Display for AppContext {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// The tags attached to an operation, see `PendingOperation::operator_tags`.
+/// At most `MAX_OPERATOR_TAGS` are kept, with keys and values truncated to
+/// `MAX_OPERATOR_TAG_LEN` characters.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OperatorTags(BTreeMap<String, String>);
+
+impl OperatorTags {
+    /// Set the tag `key` to `value`. Returns `false`, leaving the tags
+    /// unchanged, if `key` is new and there are already `MAX_OPERATOR_TAGS`
+    /// tags.
+    pub fn insert(&mut self, key: String, value: String) -> bool {
+        let truncate = |s: String| s.chars().take(MAX_OPERATOR_TAG_LEN).collect::<String>();
+        let key = truncate(key);
+        if !self.0.contains_key(&key) && self.0.len() >= MAX_OPERATOR_TAGS {
+            return false;
+        }
+        self.0.insert(key, truncate(value));
+        true
+    }
+
+    /// The tags, ordered by key
+    pub fn tags(&self) -> &BTreeMap<String, String> {
+        &self.0
+    }
+}
+
+/// Convert a monotonic `Instant` to wall-clock milliseconds since the unix
+/// epoch, relative to the current time.
+pub(super) fn instant_to_unix_ms(instant: Instant) -> u64 {
+    let now = Instant::now();
+    let system_time = if instant >= now {
+        SystemTime::now() + (instant - now)
+    } else {
+        SystemTime::now() - (now - instant)
+    };
+    system_time
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+/// Convert wall-clock milliseconds since the unix epoch to a monotonic
+/// `Instant`, relative to the current time.
+fn unix_ms_to_instant(unix_ms: u64) -> Instant {
+    let now_ms = instant_to_unix_ms(Instant::now());
+    if unix_ms >= now_ms {
+        Instant::now() + Duration::from_millis(unix_ms - now_ms)
+    } else {
+        Instant::now()
+            .checked_sub(Duration::from_millis(now_ms - unix_ms))
+            .unwrap_or_else(Instant::now)
+    }
+}
+
+/// (De)serializes an `Instant` as milliseconds since the unix epoch.
+mod instant_unix_ms {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::time::Instant;
+
+    pub fn serialize<S: Serializer>(instant: &Instant, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(super::instant_to_unix_ms(*instant))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Instant, D::Error> {
+        u64::deserialize(deserializer).map(super::unix_ms_to_instant)
+    }
+}
+
+/// The result of a submission with the given `outcome`, where `None` means
+/// it failed: an executed transaction moves the operation on, a reverted one
+/// needs it to be prepared again and a failed one is retried later.
+pub fn submission_result(outcome: Option<&TxOutcome>) -> PendingOperationResult {
+    match outcome {
+        Some(outcome) if outcome.executed => PendingOperationResult::Success,
+        Some(_) => PendingOperationResult::Reprepare,
+        None => PendingOperationResult::NotReady,
+    }
+}
+
+/// create a `op_try!` macro for the `on_retry` handler.
+#[macro_export]
+macro_rules! make_op_try {
+    ($on_retry:expr) => {
+        /// Handle a result and either return early with retry or a critical failure on
+        /// error.
+        macro_rules! op_try {
+                            (critical: $e:expr, $ctx:literal) => {
+                                match $e {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        error!(error=?e, concat!("Critical error when ", $ctx));
+                                        #[allow(clippy::redundant_closure_call)]
+                                        return $on_retry();
+                                    }
+                                }
+                            };
+                            ($e:expr, $ctx:literal) => {
+                                match $e {
+                                    Ok(v) => v,
+                                    Err(e) => {
+                                        warn!(error=?e, concat!("Error when ", $ctx));
+                                        #[allow(clippy::redundant_closure_call)]
+                                        return $on_retry();
+                                    }
+                                }
+                            };
+                        }
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+
+    use super::*;
+    use crate::{KnownHyperlaneDomain, H512};
+
+    /// A counter incremented by another task each time it gets to run, and a
+    /// log of its values
+    type YieldObserver = (Arc<AtomicUsize>, Arc<Mutex<Vec<usize>>>);
+
+    #[derive(Debug)]
+    struct MockPendingOperation {
+        id: H256,
+        content_hash: H256,
+        origin_domain_id: u32,
+        destination_domain: HyperlaneDomain,
+        priority: u32,
+        next_attempt_after: Option<Instant>,
+        reorg_depth_required: u32,
+        confirmations: u32,
+        prepare_delay: Option<Duration>,
+        cost_estimate: Option<U256>,
+        submission_outcome: Option<TxOutcome>,
+        submissions_required: u32,
+        submissions: u32,
+        created_at: Option<Instant>,
+        requires_atomic_batch: bool,
+        cost_estimate_age: Option<Duration>,
+        app_context: Option<AppContext>,
+        confirm_deadline: Option<Instant>,
+        correlation_id: Option<H256>,
+        soft_deadline: Option<Instant>,
+        queued_priority: Option<u32>,
+        hard_deadline: Option<Instant>,
+        stage_timings: StageTimings,
+        last_stage_result: Option<(OperationStage, PendingOperationResult)>,
+        kind: OperationKind,
+        skip_confirmation: bool,
+        refreshed_cost_estimate: Option<U256>,
+        is_batchable: bool,
+        tie_break_key: Option<u64>,
+        retries: u32,
+        delivered: bool,
+        delivered_operations: HashSet<H256>,
+        log_verbosity: LogVerbosity,
+        attempt_history: AttemptHistory,
+        operator_tags: OperatorTags,
+        missing_estimate_policy: MissingEstimatePolicy,
+        lifecycle_calls: Vec<&'static str>,
+        block_time: Option<Duration>,
+        depends_on: Option<H256>,
+        prepare_gate: Option<H256>,
+        cost_estimate_range: Option<(U256, U256)>,
+        self_check_error: Option<&'static str>,
+        chunk_size: Option<usize>,
+        /// Logged whenever the cost estimate is refreshed
+        yield_observer: Option<YieldObserver>,
+    }
+
+    impl MockPendingOperation {
+        fn new(priority: u32) -> Self {
+            let id = H256::random();
+            Self {
+                id,
+                content_hash: id,
+                origin_domain_id: KnownHyperlaneDomain::Ethereum as u32,
+                destination_domain: KnownHyperlaneDomain::Arbitrum.into(),
+                priority,
+                next_attempt_after: None,
+                reorg_depth_required: 1,
+                confirmations: 0,
+                prepare_delay: None,
+                cost_estimate: None,
+                submission_outcome: None,
+                submissions_required: 1,
+                submissions: 0,
+                created_at: None,
+                requires_atomic_batch: false,
+                cost_estimate_age: None,
+                app_context: None,
+                confirm_deadline: None,
+                correlation_id: None,
+                soft_deadline: None,
+                queued_priority: None,
+                hard_deadline: None,
+                stage_timings: StageTimings::default(),
+                last_stage_result: None,
+                kind: OperationKind::MessageDelivery,
+                skip_confirmation: false,
+                refreshed_cost_estimate: None,
+                is_batchable: true,
+                tie_break_key: None,
+                retries: 0,
+                delivered: false,
+                delivered_operations: HashSet::new(),
+                log_verbosity: LogVerbosity::default(),
+                attempt_history: AttemptHistory::default(),
+                operator_tags: OperatorTags::default(),
+                missing_estimate_policy: MissingEstimatePolicy::RequeueForEstimate,
+                lifecycle_calls: vec![],
+                block_time: None,
+                depends_on: None,
+                prepare_gate: None,
+                cost_estimate_range: None,
+                self_check_error: None,
+                chunk_size: None,
+                yield_observer: None,
+            }
+        }
+    }
+
+    impl TryBatchAs<HyperlaneMessage> for MockPendingOperation {}
+
+    #[async_trait]
+    impl PendingOperation for MockPendingOperation {
+        fn id(&self) -> H256 {
+            self.id
+        }
+
+        fn kind(&self) -> OperationKind {
+            self.kind
+        }
+
+        fn log_verbosity(&self) -> LogVerbosity {
+            self.log_verbosity
+        }
+
+        fn tie_break_key(&self) -> u64 {
+            self.tie_break_key
+                .unwrap_or_else(|| self.id.to_low_u64_be())
+        }
+
+        fn content_hash(&self) -> H256 {
+            self.content_hash
+        }
+
+        fn priority(&self) -> u32 {
+            self.priority
+        }
+
+        fn recompute_priority(&mut self, queue_len: u32) {
+            self.priority = default_priority_for_kind(self.kind, queue_len);
+        }
+
+        fn created_at(&self) -> Option<Instant> {
+            self.created_at
+        }
+
+        fn soft_deadline(&self) -> Option<Instant> {
+            self.soft_deadline
+        }
+
+        fn queued_priority(&self) -> Option<u32> {
+            self.queued_priority
+        }
+
+        fn set_queued_priority(&mut self, priority: u32) {
+            self.queued_priority = Some(priority);
+        }
+
+        fn hard_deadline(&self) -> Option<Instant> {
+            self.hard_deadline
+        }
+
+        fn origin_domain_id(&self) -> u32 {
+            self.origin_domain_id
+        }
+
+        fn correlation_id(&self) -> Option<H256> {
+            self.correlation_id
+        }
+
+        fn record_stage_timing(&mut self, stage: OperationStage, elapsed: Duration) {
+            self.stage_timings.record(stage, elapsed);
+        }
+
+        fn stage_timings(&self) -> StageTimings {
+            self.stage_timings
+        }
+
+        fn record_stage_result(&mut self, stage: OperationStage, result: PendingOperationResult) {
+            self.last_stage_result = Some((stage, result));
+        }
+
+        fn last_stage_result(&self) -> Option<(OperationStage, PendingOperationResult)> {
+            self.last_stage_result.clone()
+        }
+
+        fn set_correlation_id(&mut self, id: H256) {
+            self.correlation_id = Some(id);
+        }
+
+        fn destination_domain(&self) -> &HyperlaneDomain {
+            &self.destination_domain
+        }
+
+        fn app_context(&self) -> Option<AppContext> {
+            self.app_context.clone()
+        }
+
+        async fn check_already_delivered(&self) -> ChainResult<bool> {
+            Ok(self.delivered)
+        }
+
+        async fn is_delivered(&self, id: H256) -> ChainResult<bool> {
+            Ok(self.delivered_operations.contains(&id))
+        }
+
+        async fn self_check(&self) -> ChainResult<()> {
+            match self.self_check_error {
+                Some(err) => Err(ChainCommunicationError::from_other_str(err)),
+                None => Ok(()),
+            }
+        }
+
+        async fn prepare(&mut self) -> PendingOperationResult {
+            self.lifecycle_calls.push("prepare");
+            if let Some(delay) = self.prepare_delay {
+                tokio::time::sleep(delay).await;
+            }
+            PendingOperationResult::Success
+        }
+
+        async fn warmup(&mut self) -> PendingOperationResult {
+            self.lifecycle_calls.push("warmup");
+            PendingOperationResult::Success
+        }
+
+        /// Uses up exactly the estimated gas.
+        async fn submit(&mut self) -> ChainResult<Option<TxOutcome>> {
+            self.submissions += 1;
+            Ok(Some(TxOutcome {
+                transaction_id: H512::random(),
+                executed: true,
+                gas_used: self.cost_estimate.unwrap_or_default(),
+                gas_price: FixedPointNumber::zero(),
+            }))
+        }
+
+        fn remaining_submissions(&self) -> u32 {
+            self.submissions_required.saturating_sub(self.submissions)
+        }
+
+        fn is_batchable(&self) -> bool {
+            self.is_batchable
+        }
+
+        fn requires_atomic_batch(&self) -> bool {
+            self.requires_atomic_batch
+        }
+
+        fn skip_confirmation(&self) -> bool {
+            self.skip_confirmation
+        }
+
+        fn set_submission_outcome(&mut self, outcome: TxOutcome) {
+            self.submission_outcome = Some(outcome);
+        }
+
+        fn get_tx_cost_estimate(&self) -> Option<U256> {
+            match self.cost_estimate_range {
+                Some((low, high)) => Some(cost_estimate_midpoint(low, high)),
+                None => self.cost_estimate,
+            }
+        }
+
+        fn cost_estimate_range(&self) -> Option<(U256, U256)> {
+            self.cost_estimate_range
+                .or_else(|| self.cost_estimate.map(|estimate| (estimate, estimate)))
+        }
+
+        async fn refresh_cost_estimate(&mut self) -> ChainResult<U256> {
+            if let Some((counter, log)) = &self.yield_observer {
+                log.lock()
+                    .unwrap()
+                    .push(counter.load(AtomicOrdering::SeqCst));
+            }
+            if let Some(refreshed) = self.refreshed_cost_estimate {
+                self.cost_estimate = Some(refreshed);
+            }
+            Ok(self.cost_estimate.unwrap_or_default())
+        }
+
+        fn batch_chunk_size(&self) -> usize {
+            self.chunk_size.unwrap_or(DEFAULT_BATCH_CHUNK_SIZE)
+        }
+
+        fn cost_estimate_age(&self) -> Option<Duration> {
+            self.cost_estimate_age
+        }
+
+        fn on_missing_estimate(&self) -> MissingEstimatePolicy {
+            self.missing_estimate_policy
+        }
+
+        /// Each call observes one more block on top of the submission.
+        async fn confirm(&mut self) -> PendingOperationResult {
+            self.confirmations += 1;
+            PendingOperationResult::Success
+        }
+
+        fn reorg_depth_required(&self) -> u32 {
+            self.reorg_depth_required
+        }
+
+        fn current_confirmation_depth(&self) -> Option<u32> {
+            (self.confirmations > 0).then_some(self.confirmations)
+        }
+
+        fn depends_on(&self) -> Option<H256> {
+            self.depends_on
+        }
+
+        fn prepare_gate(&self) -> Option<H256> {
+            self.prepare_gate
+        }
+
+        fn destination_block_time(&self) -> Duration {
+            self.block_time.unwrap_or(DEFAULT_BLOCK_TIME)
+        }
+
+        fn confirm_deadline(&self) -> Option<Instant> {
+            self.confirm_deadline
+        }
+
+        fn set_operation_outcome(
+            &mut self,
+            submission_outcome: TxOutcome,
+            _submission_estimated_cost: U256,
+        ) {
+            self.set_submission_outcome(submission_outcome);
+        }
+
+        fn next_attempt_after(&self) -> Option<Instant> {
+            self.next_attempt_after
+        }
+
+        fn set_next_attempt_after(&mut self, delay: Duration) {
+            self.next_attempt_after = Some(Instant::now() + delay);
+        }
+
+        fn set_next_attempt_at(&mut self, at: Instant) {
+            self.next_attempt_after = Some(at);
+        }
+
+        fn clear_next_attempt(&mut self) {
+            self.next_attempt_after = None;
+        }
+
+        fn reset_attempts(&mut self) {
+            self.next_attempt_after = None;
+        }
+
+        fn attempt_history(&self) -> &[(Instant, OperationStage, String)] {
+            self.attempt_history.entries()
+        }
+
+        fn push_attempt(&mut self, stage: OperationStage, reason: String) {
+            self.attempt_history.push(Instant::now(), stage, reason);
+        }
+
+        fn operator_tags(&self) -> &BTreeMap<String, String> {
+            self.operator_tags.tags()
+        }
+
+        fn set_operator_tag(&mut self, key: String, value: String) {
+            self.operator_tags.insert(key, value);
+        }
+
+        fn retry_count(&self) -> u32 {
+            self.retries
+        }
+
+        fn set_retries(&mut self, retries: u32) {
+            self.retries = retries;
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Like `block_on`, but with tokio's clock paused, so that sleeps
+    /// complete as soon as the runtime has nothing else to do.
+    #[cfg(feature = "async")]
+    fn block_on_paused<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .start_paused(true)
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn test_confirm_waits_for_reorg_depth() {
+        let mut op = MockPendingOperation::new(0);
+        op.reorg_depth_required = 3;
+
+        let results = block_on(async {
+            let mut results = vec![];
+            for _ in 0..3 {
+                results.push(op.confirm_with_deadline(Instant::now()).await);
+            }
+            results
+        });
+        assert!(matches!(results[0], PendingOperationResult::NotReady));
+        assert!(matches!(results[1], PendingOperationResult::NotReady));
+        assert!(matches!(results[2], PendingOperationResult::Success));
+    }
+
+    #[test]
+    fn test_outcome_labels() {
+        use PendingOperationResult::*;
+
+        let labels: Vec<_> = [
+            Success,
+            NotReady,
+            Reprepare,
+            Drop,
+            Confirm,
+            Reschedule(Instant::now()),
+            DeadLetter("retries exhausted".to_owned()),
+            ConfirmAfter(3),
+            ReprepareNow("dependency available".to_owned()),
+        ]
+        .iter()
+        .map(PendingOperationResult::outcome_label)
+        .collect();
+        assert_eq!(
+            labels,
+            vec![
+                "success",
+                "not_ready",
+                "reprepare",
+                "drop",
+                "confirm",
+                "reschedule",
+                "dead_letter",
+                "confirm_after",
+                "reprepare_now",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_time_in_queue() {
+        let mut op = MockPendingOperation::new(0);
+        let now = Instant::now();
+        assert_eq!(op.time_in_queue(now), Duration::ZERO);
+
+        op.created_at = now.checked_sub(Duration::from_secs(90));
+        assert_eq!(op.time_in_queue(now), Duration::from_secs(90));
+        assert!(op.to_snapshot().time_in_queue >= Duration::from_secs(90));
+    }
+
+    #[test]
+    fn test_current_confirmation_depth() {
+        let mut op = MockPendingOperation::new(0);
+        op.reorg_depth_required = 12;
+        assert_eq!(op.current_confirmation_depth(), None);
+
+        for depth in 1..=3 {
+            assert!(matches!(
+                block_on(op.confirm_with_deadline(Instant::now())),
+                PendingOperationResult::NotReady
+            ));
+            assert_eq!(op.current_confirmation_depth(), Some(depth));
+        }
+        assert_eq!(op.to_snapshot().current_confirmation_depth, Some(3));
+    }
+
+    #[test]
+    fn test_estimated_confirmation_time() {
+        let mut op = MockPendingOperation::new(0);
+        assert_eq!(op.estimated_confirmation_time(), Some(DEFAULT_BLOCK_TIME));
+
+        op.reorg_depth_required = 3;
+        op.block_time = Some(Duration::from_secs(2));
+        assert_eq!(
+            op.estimated_confirmation_time(),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(
+            op.to_snapshot().estimated_confirmation_time,
+            Some(Duration::from_secs(6))
+        );
+    }
+
+    #[test]
+    fn test_confirm_escalates_after_deadline() {
+        let mut op = MockPendingOperation::new(0);
+        // the submission never gets deep enough to be confirmed
+        op.reorg_depth_required = u32::MAX;
+        op.confirm_deadline = Some(Instant::now() + Duration::from_secs(60));
+
+        let (before_deadline, after_deadline) = block_on(async {
+            let mut before_deadline = vec![];
+            for _ in 0..3 {
+                before_deadline.push(op.confirm_with_deadline(Instant::now()).await);
+            }
+            op.confirm_deadline = Instant::now().checked_sub(Duration::from_secs(1));
+            (
+                before_deadline,
+                op.confirm_with_deadline(Instant::now()).await,
+            )
+        });
+        assert!(before_deadline
+            .iter()
+            .all(|r| matches!(r, PendingOperationResult::NotReady)));
+        assert!(matches!(after_deadline, PendingOperationResult::Reprepare));
+    }
+
+    #[test]
+    fn test_app_context_rate_limiter() {
+        let window = Duration::from_secs(10);
+        let mut limiter = AppContextRateLimiter::new(2, window);
+        let start = Instant::now();
+        let noisy = MockPendingOperation::new(0);
+        let noisy = noisy.normalized_app_context();
+
+        for _ in 0..2 {
+            assert!(limiter.should_allow(&noisy, start));
+            limiter.record_submission(&noisy, start);
+        }
+        // the noisy context is deferred, other contexts aren't affected
+        assert!(!limiter.should_allow(&noisy, start + Duration::from_secs(5)));
+        assert!(limiter.should_allow("other", start + Duration::from_secs(5)));
+
+        // recovers once its submissions slide out of the window
+        assert!(limiter.should_allow(&noisy, start + window));
+        limiter.record_submission(&noisy, start + window);
+        assert!(limiter.should_allow(&noisy, start + window));
+        limiter.record_submission(&noisy, start + window);
+        assert!(!limiter.should_allow(&noisy, start + window));
+    }
+
+    #[test]
+    fn test_aggregate_stats() {
+        let mut to_ethereum = MockPendingOperation::new(0);
+        to_ethereum.destination_domain = KnownHyperlaneDomain::Ethereum.into();
+        to_ethereum.cost_estimate = Some(U256::from(100));
+        let mut gas_payment = MockPendingOperation::new(1);
+        gas_payment.kind = OperationKind::GasPayment;
+        gas_payment.cost_estimate = Some(U256::from(50));
+        let mut to_arbitrum = MockPendingOperation::new(2);
+        to_arbitrum.cost_estimate = Some(U256::from(25));
+        let ops: Vec<QueueOperation> = vec![
+            Box::new(to_ethereum),
+            Box::new(gas_payment),
+            Box::new(to_arbitrum),
+        ];
+
+        let stats = aggregate_stats(&ops);
+        assert_eq!(
+            stats.by_destination,
+            HashMap::from([("ethereum".to_owned(), 1), ("arbitrum".to_owned(), 2)])
+        );
+        assert_eq!(
+            stats.by_kind,
+            HashMap::from([
+                (OperationKind::MessageDelivery, 2),
+                (OperationKind::GasPayment, 1)
+            ])
+        );
+        assert_eq!(stats.total_estimated_cost, U256::from(175));
+
+        assert_eq!(aggregate_stats(&[]), QueueStats::default());
+    }
+
+    #[test]
+    fn test_operation_index() {
+        let ops: Vec<QueueOperation> = (0..3)
+            .map(|priority| Box::new(MockPendingOperation::new(priority)) as QueueOperation)
+            .collect();
+        let ids: Vec<_> = ops.iter().map(|op| op.id()).collect();
+        let mut index = OperationIndex::new(ops);
+        assert_eq!(index.len(), 3);
+        for (priority, id) in ids.iter().enumerate() {
+            assert_eq!(index.get(id).unwrap().priority(), priority as u32);
+        }
+        assert!(index.get(&H256::random()).is_none());
+
+        let removed = index.remove(&ids[0]).unwrap();
+        assert_eq!(removed.id(), ids[0]);
+        assert!(index.get(&ids[0]).is_none());
+        assert!(index.remove(&ids[0]).is_none());
+        // the operation that was moved into the gap can still be found
+        assert_eq!(index.get(&ids[2]).unwrap().priority(), 2);
+        assert_eq!(index.get(&ids[1]).unwrap().priority(), 1);
+
+        let new_op = MockPendingOperation::new(5);
+        let new_id = new_op.id;
+        assert!(index.insert(Box::new(new_op)).is_none());
+        assert_eq!(index.get(&new_id).unwrap().priority(), 5);
+        assert!(index.insert(removed).is_none());
+        assert_eq!(index.len(), 4);
+    }
+
+    #[test]
+    fn test_operation_index_reindex_after_sort() {
+        let ops: Vec<QueueOperation> = [3, 1, 2, 0]
+            .into_iter()
+            .map(|priority| Box::new(MockPendingOperation::new(priority)) as QueueOperation)
+            .collect();
+        let mut index = OperationIndex::new(ops);
+
+        index.operations_mut().sort();
+        index.reindex();
+        let priorities: Vec<_> = index.operations().iter().map(|op| op.priority()).collect();
+        assert_eq!(priorities, vec![0, 1, 2, 3]);
+        for op in index.operations() {
+            assert_eq!(index.get(&op.id()).unwrap().priority(), op.priority());
+        }
+    }
+
+    #[test]
+    fn test_dedup_keeps_one_op_per_content_hash() {
+        let first = MockPendingOperation::new(1);
+        let mut duplicate = MockPendingOperation::new(2);
+        duplicate.content_hash = first.content_hash;
+        let other = MockPendingOperation::new(3);
+        let (first_id, other_id) = (first.id(), other.id());
+
+        let ops: Vec<QueueOperation> = vec![Box::new(duplicate), Box::new(other), Box::new(first)];
+        let ids: Vec<_> = dedup(ops).iter().map(|op| op.id()).collect();
+        assert_eq!(ids, vec![first_id, other_id]);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_cancelling_mid_prepare_returns_promptly() {
+        let mut op = MockPendingOperation::new(0);
+        // stands in for an RPC that hangs
+        op.prepare_delay = Some(Duration::from_secs(600));
+        let token = CancellationToken::new();
+
+        let (result, elapsed) = block_on_paused(async {
+            let started = tokio::time::Instant::now();
+            let cancel = async {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                token.cancel();
+            };
+            let (result, _) = futures::join!(op.prepare_with_cancel(token.clone()), cancel);
+            (result, started.elapsed())
+        });
+        assert_eq!(result, PendingOperationResult::NotReady);
+        assert!(elapsed < Duration::from_secs(600));
+
+        // without cancellation the result is passed through
+        op.prepare_delay = None;
+        let result = block_on(op.prepare_with_cancel(CancellationToken::new()));
+        assert_eq!(result, PendingOperationResult::Success);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_metadata_coalescer_fetches_once_per_key() {
+        let fetches = Arc::new(AtomicUsize::new(0));
+        let fetch = |metadata: &'static str| {
+            let fetches = fetches.clone();
+            move || async move {
+                fetches.fetch_add(1, AtomicOrdering::SeqCst);
+                metadata.as_bytes().to_vec()
+            }
+        };
+        let shared_key = H256::random();
+        let coalescer = MetadataCoalescer::default();
+
+        let (first, second, other) = block_on(async {
+            futures::join!(
+                coalescer.get_or_fetch(shared_key, fetch("checkpoint")),
+                coalescer.get_or_fetch(shared_key, fetch("checkpoint")),
+                coalescer.get_or_fetch(H256::random(), fetch("other checkpoint")),
+            )
+        });
+        assert_eq!(first, b"checkpoint".to_vec());
+        assert_eq!(second, first);
+        assert_eq!(other, b"other checkpoint".to_vec());
+        assert_eq!(fetches.load(AtomicOrdering::SeqCst), 2);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_refresh_all_estimates() {
+        let mut ops: Vec<QueueOperation> = [(100, Some(150)), (200, None)]
+            .into_iter()
+            .enumerate()
+            .map(|(priority, (estimate, refreshed))| {
+                let mut op = MockPendingOperation::new(priority as u32);
+                op.cost_estimate = Some(U256::from(estimate));
+                op.refreshed_cost_estimate = refreshed.map(U256::from);
+                Box::new(op) as QueueOperation
+            })
+            .collect();
+        assert_eq!(total_estimated_cost(&ops), U256::from(300));
+
+        let refreshed = block_on(refresh_all_estimates(&mut ops));
+        let refreshed: Vec<_> = refreshed.into_iter().map(Result::unwrap).collect();
+        assert_eq!(refreshed, vec![U256::from(150), U256::from(200)]);
+        assert_eq!(total_estimated_cost(&ops), U256::from(350));
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_refresh_all_estimates_yields_between_chunks() {
+        let yield_counter = Arc::new(AtomicUsize::new(0));
+        let ticks = Arc::new(Mutex::new(vec![]));
+        let mut ops: Vec<QueueOperation> = (0..10)
+            .map(|priority| {
+                let mut op = MockPendingOperation::new(priority);
+                op.chunk_size = Some(4);
+                op.yield_observer = Some((yield_counter.clone(), ticks.clone()));
+                Box::new(op) as QueueOperation
+            })
+            .collect();
+
+        let refreshed = block_on(async {
+            // only gets to run when `refresh_all_estimates` yields
+            let counter = yield_counter.clone();
+            let observer = tokio::spawn(async move {
+                loop {
+                    counter.fetch_add(1, AtomicOrdering::SeqCst);
+                    tokio::task::yield_now().await;
+                }
+            });
+            tokio::task::yield_now().await;
+            let refreshed = refresh_all_estimates(&mut ops).await;
+            observer.abort();
+            refreshed
+        });
+        assert_eq!(refreshed.len(), 10);
+
+        let ticks = ticks.lock().unwrap().clone();
+        assert_eq!(ticks.len(), 10);
+        // no yields within a chunk, and one at every chunk boundary
+        for chunk in ticks.chunks(4) {
+            assert!(chunk.iter().all(|tick| *tick == chunk[0]));
+        }
+        assert!(ticks[0] < ticks[4]);
+        assert!(ticks[4] < ticks[8]);
+    }
+
+    #[test]
+    fn test_drops_already_delivered_operations() {
+        let mut op = MockPendingOperation::new(0);
+        assert!(!block_on(is_already_delivered(&op)));
+
+        // delivered by another relayer
+        op.delivered = true;
+        assert!(block_on(is_already_delivered(&op)));
+        let result = block_on(prepare_or_warmup(&mut op, Instant::now()));
+        assert_eq!(result, PendingOperationResult::Drop);
+        assert!(op.lifecycle_calls.is_empty());
+    }
+
+    #[test]
+    fn test_submit_returns_outcome() {
+        let mut op = MockPendingOperation::new(0);
+        op.cost_estimate = Some(100.into());
+
+        let outcome = block_on(op.submit()).unwrap().unwrap();
+        assert_eq!(Some(outcome.gas_used), op.get_tx_cost_estimate());
+        // `submit` leaves recording the outcome to the caller
+        assert!(op.submission_outcome.is_none());
+
+        op.set_operation_outcome(outcome.clone(), op.get_tx_cost_estimate().unwrap());
+        let recorded = op.submission_outcome.as_ref().unwrap();
+        assert_eq!(recorded.transaction_id, outcome.transaction_id);
+        assert_eq!(recorded.gas_used, outcome.gas_used);
+    }
+
+    #[test]
+    fn test_record_outcome_with_status() {
+        let mut op = MockPendingOperation::new(0);
+        let mut outcome = block_on(op.submit()).unwrap().unwrap();
+        assert_eq!(
+            op.record_outcome_with_status(outcome.clone()),
+            PendingOperationResult::Confirm
+        );
+
+        // mined but reverted
+        outcome.executed = false;
+        assert_eq!(
+            op.record_outcome_with_status(outcome),
+            PendingOperationResult::Reprepare
+        );
+        assert!(!op.submission_outcome.as_ref().unwrap().executed);
+    }
+
+    fn op_with_cost_estimate(priority: u32, cost_estimate: Option<u64>) -> QueueOperation {
+        let mut op = MockPendingOperation::new(priority);
+        op.cost_estimate = cost_estimate.map(Into::into);
+        Box::new(op)
+    }
+
+    #[test]
+    fn test_missing_estimate_policies() {
+        let mut op = MockPendingOperation::new(0);
+        op.cost_estimate = Some(100.into());
+        assert_eq!(op.check_cost_estimate(), PendingOperationResult::Success);
+
+        op.cost_estimate = None;
+        assert_eq!(op.check_cost_estimate(), PendingOperationResult::Reprepare);
+        let (_, stage, reason) = &op.attempt_history()[0];
+        assert_eq!(*stage, OperationStage::Submit);
+        assert_eq!(reason, "missing cost estimate");
+
+        op.missing_estimate_policy = MissingEstimatePolicy::SubmitAnyway;
+        assert_eq!(op.check_cost_estimate(), PendingOperationResult::Success);
+
+        op.missing_estimate_policy = MissingEstimatePolicy::Drop;
+        assert_eq!(op.check_cost_estimate(), PendingOperationResult::Drop);
+    }
+
+    fn op_with_cost_estimate_range(priority: u32, low: u64, high: u64) -> QueueOperation {
+        let mut op = MockPendingOperation::new(priority);
+        op.cost_estimate_range = Some((low.into(), high.into()));
+        Box::new(op)
+    }
+
+    #[test]
+    fn test_cost_estimate_range_midpoint() {
+        let op = op_with_cost_estimate_range(0, 100, 201);
+        assert_eq!(op.get_tx_cost_estimate(), Some(U256::from(150)));
+        assert_eq!(
+            op.cost_estimate_range(),
+            Some((U256::from(100), U256::from(201)))
+        );
+        assert_eq!(total_estimated_cost(&[op]), U256::from(150));
+
+        // operations with a single estimate have an empty range around it
+        let op = op_with_cost_estimate(0, Some(40));
+        assert_eq!(
+            op.cost_estimate_range(),
+            Some((U256::from(40), U256::from(40)))
+        );
+        assert_eq!(op_with_cost_estimate(0, None).cost_estimate_range(), None);
+
+        assert_eq!(cost_estimate_midpoint(U256::MAX, U256::MAX), U256::MAX);
+        assert_eq!(cost_estimate_midpoint(3.into(), 5.into()), U256::from(4));
+    }
+
+    #[test]
+    fn test_total_estimated_cost_range() {
+        let ops = vec![
+            op_with_cost_estimate_range(0, 10, 30),
+            op_with_cost_estimate(1, Some(40)),
+            op_with_cost_estimate(2, None),
+            op_with_cost_estimate_range(3, 5, 5),
+        ];
+        assert_eq!(
+            total_estimated_cost_range(&ops),
+            (U256::from(55), U256::from(75))
+        );
+        assert_eq!(
+            total_estimated_cost_range(&[]),
+            (U256::zero(), U256::zero())
+        );
+    }
+
+    #[test]
+    fn test_operations_within_budget_uses_upper_bound() {
+        let ops = vec![
+            op_with_cost_estimate_range(0, 20, 60),
+            op_with_cost_estimate_range(1, 20, 60),
+        ];
+        // the midpoints would fit, but not the worst case
+        assert_eq!(total_estimated_cost(&ops), U256::from(80));
+        assert_eq!(
+            operations_within_budget(&ops, 100.into()),
+            (1, U256::from(60))
+        );
+    }
+
+    #[test]
+    fn test_operations_within_budget() {
+        let ops = vec![
+            op_with_cost_estimate(0, Some(40)),
+            op_with_cost_estimate(1, Some(50)),
+            op_with_cost_estimate(2, Some(30)),
+        ];
+        assert_eq!(
+            operations_within_budget(&ops, 100.into()),
+            (2, U256::from(90))
+        );
+        assert_eq!(
+            operations_within_budget(&ops, 120.into()),
+            (3, U256::from(120))
+        );
+        assert_eq!(operations_within_budget(&ops, 10.into()), (0, U256::zero()));
+
+        // a missing estimate counts as free
+        let ops = vec![
+            op_with_cost_estimate(0, Some(40)),
+            op_with_cost_estimate(1, None),
+            op_with_cost_estimate(2, Some(70)),
+        ];
+        assert_eq!(
+            operations_within_budget(&ops, 100.into()),
+            (2, U256::from(40))
+        );
+    }
+
+    fn tx_outcome_with_gas_used(gas_used: u64) -> TxOutcome {
+        TxOutcome {
+            transaction_id: H512::random(),
+            executed: true,
+            gas_used: gas_used.into(),
+            gas_price: FixedPointNumber::zero(),
+        }
+    }
+
+    #[test]
+    fn test_gas_used_by_operations_batch_accounts_for_all_gas() {
+        let tx_outcome = tx_outcome_with_gas_used(101);
+        let estimates = vec![U256::from(1000), U256::from(1000), U256::from(1000)];
+
+        // attributing each operation on its own loses 2 gas units to truncation
+        let naive: U256 = estimates
+            .iter()
+            .map(|estimate| gas_used_by_operation(&tx_outcome, 3000.into(), *estimate).unwrap())
+            .fold(U256::zero(), |acc, gas| acc + gas);
+        assert_eq!(naive, U256::from(99));
+
+        let attributed = gas_used_by_operations_batch(&tx_outcome, &estimates).unwrap();
+        assert_eq!(
+            attributed,
+            vec![U256::from(34), U256::from(34), U256::from(33)]
+        );
+    }
+
+    #[test]
+    fn test_gas_used_by_operations_batch_is_proportional() {
+        let tx_outcome = tx_outcome_with_gas_used(1000);
+        let estimates = vec![U256::from(100), U256::from(300), U256::from(600)];
+        let attributed = gas_used_by_operations_batch(&tx_outcome, &estimates).unwrap();
+        assert_eq!(
+            attributed,
+            vec![U256::from(100), U256::from(300), U256::from(600)]
+        );
+
+        assert!(gas_used_by_operations_batch(&tx_outcome, &[U256::zero()]).is_err());
+        assert!(gas_used_by_operations_batch(&tx_outcome, &[])
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_gas_used_by_operation_rounding() {
+        let tx_outcome = tx_outcome_with_gas_used(100);
+        let rounded = |operation_estimate: u64, rounding| {
+            gas_used_by_operation_rounded(
+                &tx_outcome,
+                U256::from(3),
+                U256::from(operation_estimate),
+                rounding,
+            )
+            .unwrap()
+        };
+        // 100 / 3 = 33.33..
+        assert_eq!(rounded(1, RoundingMode::Floor), U256::from(33));
+        assert_eq!(rounded(1, RoundingMode::Ceil), U256::from(34));
+        assert_eq!(rounded(1, RoundingMode::Nearest), U256::from(33));
+        // 200 / 3 = 66.66..
+        assert_eq!(rounded(2, RoundingMode::Nearest), U256::from(67));
+        // exact shares aren't rounded
+        assert_eq!(rounded(3, RoundingMode::Ceil), U256::from(100));
+
+        assert_eq!(
+            gas_used_by_operation(&tx_outcome, U256::from(3), U256::one()).unwrap(),
+            rounded(1, RoundingMode::Floor)
+        );
+        assert!(gas_used_by_operation_rounded(
+            &tx_outcome,
+            U256::zero(),
+            U256::one(),
+            RoundingMode::Ceil
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_ceil_never_under_attributes() {
+        for gas_used in [0, 1, 7, 999, 21_000] {
+            let tx_outcome = tx_outcome_with_gas_used(gas_used);
+            for (tx_estimate, operation_estimate) in [(1, 1), (3, 1), (7, 3), (1000, 999)] {
+                let attribute = |rounding| {
+                    gas_used_by_operation_rounded(
+                        &tx_outcome,
+                        U256::from(tx_estimate),
+                        U256::from(operation_estimate),
+                        rounding,
+                    )
+                    .unwrap()
+                };
+                assert!(attribute(RoundingMode::Ceil) >= attribute(RoundingMode::Floor));
+            }
+        }
+    }
+
+    /// Charges every operation exactly its estimate, e.g. for chains where
+    /// compute units are known up front.
+    #[derive(Debug)]
+    struct MockComputeUnitCostModel;
+
+    impl CostModel for MockComputeUnitCostModel {
+        fn cost_used_by_operation(
+            &self,
+            _tx_outcome: &TxOutcome,
+            tx_estimated_cost: U256,
+            operation_estimated_cost: U256,
+        ) -> ChainResult<U256> {
+            if tx_estimated_cost.is_zero() {
+                return Err(eyre::eyre!("Division by zero").into());
+            }
+            Ok(operation_estimated_cost)
+        }
+    }
+
+    #[test]
+    fn test_cost_model() {
+        let tx_outcome = tx_outcome_with_gas_used(300);
+        let op = MockPendingOperation::new(0);
+        assert_eq!(
+            op.cost_model()
+                .cost_used_by_operation(&tx_outcome, 200.into(), 100.into())
+                .unwrap(),
+            gas_used_by_operation(&tx_outcome, 200.into(), 100.into()).unwrap()
+        );
+
+        let models: [&dyn CostModel; 2] = [&EvmCostModel, &MockComputeUnitCostModel];
+        let shares: Vec<_> = models
+            .iter()
+            .map(|model| {
+                model
+                    .cost_used_by_operation(&tx_outcome, 200.into(), 100.into())
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(shares, vec![U256::from(150), U256::from(100)]);
+
+        for model in models {
+            assert!(model
+                .cost_used_by_operation(&tx_outcome, U256::zero(), 100.into())
+                .is_err());
+        }
+    }
+
+    #[test]
+    fn test_batch_effective_gas_price() {
+        let mut tx_outcome = tx_outcome_with_gas_used(21_000);
+        tx_outcome.gas_price = FixedPointNumber::from(30);
+        assert_eq!(
+            batch_effective_gas_price(&tx_outcome).unwrap(),
+            FixedPointNumber::from(30)
+        );
+
+        let tx_outcome = tx_outcome_with_gas_used(0);
+        assert!(batch_effective_gas_price(&tx_outcome).is_err());
+    }
+
+    #[test]
+    fn test_gas_used_by_operation_overflow() {
+        let mut tx_outcome = tx_outcome_with_gas_used(0);
+        tx_outcome.gas_used = U256::MAX;
+
+        // right at the boundary the share still fits
+        assert_eq!(
+            gas_used_by_operation(&tx_outcome, 2.into(), 2.into()).unwrap(),
+            U256::MAX
+        );
+
+        let err = gas_used_by_operation(&tx_outcome, 1.into(), 2.into()).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "Gas used by operation overflows U256 (tx gas used: {}, operation estimate: 2, tx estimate: 1)",
+                U256::MAX
+            )
+        );
+    }
+
+    #[test]
+    fn test_gas_used_capped_near_overflow() {
+        // the cap is beyond `U256::MAX`, so the whole share is attributed
+        let mut tx_outcome = tx_outcome_with_gas_used(0);
+        tx_outcome.gas_used = U256::MAX;
+        let estimates = vec![U256::MAX];
+        assert_eq!(
+            gas_used_capped(&tx_outcome, &estimates, FixedPointNumber::from(2)).unwrap(),
+            vec![U256::MAX]
+        );
+    }
+
+    #[test]
+    fn test_gas_used_capped() {
+        let max_multiple: FixedPointNumber = "1.5".parse().unwrap();
+        let estimates = vec![U256::from(100), U256::from(300)];
+
+        // within the caps this is the same as the uncapped attribution
+        let tx_outcome = tx_outcome_with_gas_used(500);
+        assert_eq!(
+            gas_used_capped(&tx_outcome, &estimates, max_multiple.clone()).unwrap(),
+            gas_used_by_operations_batch(&tx_outcome, &estimates).unwrap()
+        );
+
+        // a blowout is capped at 1.5x each estimate
+        let tx_outcome = tx_outcome_with_gas_used(4000);
+        assert_eq!(
+            gas_used_capped(&tx_outcome, &estimates, max_multiple).unwrap(),
+            vec![U256::from(150), U256::from(450)]
+        );
+    }
+
+    #[test]
+    fn test_split_batch_by_gas_limit() {
+        let ops = vec![
+            op_with_cost_estimate(0, Some(40)),
+            op_with_cost_estimate(1, Some(50)),
+            op_with_cost_estimate(2, Some(30)),
+            op_with_cost_estimate(3, None),
+        ];
+        let batches = split_batch_by_gas_limit(ops, U256::from(100));
+        let priorities: Vec<Vec<_>> = batches
+            .iter()
+            .map(|batch| batch.iter().map(|op| op.priority()).collect())
+            .collect();
+        assert_eq!(priorities, vec![vec![0, 1], vec![2, 3]]);
+    }
+
+    #[test]
+    fn test_split_batch_by_gas_limit_isolates_oversized_op() {
+        let ops = vec![
+            op_with_cost_estimate(0, Some(40)),
+            op_with_cost_estimate(1, Some(150)),
+            op_with_cost_estimate(2, Some(30)),
+        ];
+        let batches = split_batch_by_gas_limit(ops, U256::from(100));
+        let priorities: Vec<Vec<_>> = batches
+            .iter()
+            .map(|batch| batch.iter().map(|op| op.priority()).collect())
+            .collect();
+        assert_eq!(priorities, vec![vec![1], vec![0, 2]]);
+
+        assert!(split_batch_by_gas_limit(vec![], U256::from(100)).is_empty());
+    }
+
+    #[test]
+    fn test_warmup_before_prepare() {
+        let mut clock = SimClock::default();
+        let mut op = MockPendingOperation::new(0);
+        op.next_attempt_after = Some(clock.now() + Duration::from_secs(10));
+
+        block_on(async {
+            let waiting = prepare_or_warmup(&mut op, clock.now()).await;
+            assert!(matches!(waiting, PendingOperationResult::NotReady));
+            clock.advance(Duration::from_secs(10));
+            let due = prepare_or_warmup(&mut op, clock.now()).await;
+            assert!(matches!(due, PendingOperationResult::Success));
+        });
+        assert_eq!(op.lifecycle_calls, vec!["warmup", "prepare"]);
+    }
+
+    #[test]
+    fn test_dependent_waits_for_dependency() {
+        let a = MockPendingOperation::new(5);
+        let mut b = MockPendingOperation::new(0);
+        b.depends_on = Some(a.id);
+        let c = MockPendingOperation::new(1);
+        let (a_id, b_id, c_id) = (a.id, b.id, c.id);
+        let mut ops: Vec<QueueOperation> = vec![Box::new(b), Box::new(c), Box::new(a)];
+
+        let results = resolve_dependencies(&mut ops);
+        // `a` inherits the position of `b`, which outranked everything else
+        let ids: Vec<_> = ops.iter().map(|op| op.id()).collect();
+        assert_eq!(ids, vec![a_id, b_id, c_id]);
+        let labels: Vec<_> = results.iter().map(|r| r.outcome_label()).collect();
+        assert_eq!(labels, vec!["success", "not_ready", "success"]);
+
+        // once `a` has left the queue, `b` can go ahead
+        ops.remove(0);
+        let labels: Vec<_> = resolve_dependencies(&mut ops)
+            .iter()
+            .map(|r| r.outcome_label())
+            .collect();
+        assert_eq!(labels, vec!["success", "success"]);
+    }
+
+    #[test]
+    fn test_dependency_cycle_is_dropped() {
+        let mut x = MockPendingOperation::new(0);
+        let mut y = MockPendingOperation::new(1);
+        let mut z = MockPendingOperation::new(2);
+        x.depends_on = Some(y.id);
+        y.depends_on = Some(x.id);
+        z.depends_on = Some(x.id);
+        let (x_id, y_id, z_id) = (x.id, y.id, z.id);
+        let mut ops: Vec<QueueOperation> = vec![Box::new(z), Box::new(y), Box::new(x)];
+
+        let results = resolve_dependencies(&mut ops);
+        let resolved: HashMap<_, _> = ops
+            .iter()
+            .map(|op| op.id())
+            .zip(results.iter().map(|r| r.outcome_label()))
+            .collect();
+        assert_eq!(resolved[&x_id], "drop");
+        assert_eq!(resolved[&y_id], "drop");
+        // `z` isn't part of the cycle, but has to wait for it to be dropped
+        assert_eq!(resolved[&z_id], "not_ready");
+    }
+
+    #[test]
+    fn test_alternate_display_omits_domain_name() {
+        let op: QueueOperation = Box::new(MockPendingOperation::new(3));
+        let destination_id = op.destination_domain_id();
+
+        let verbose = format!("{op}");
+        assert!(verbose.contains("destination: arbitrum"));
+
+        let compact = format!("{op:#}");
+        assert!(!compact.contains("arbitrum"));
+        assert!(compact.contains(&format!("destination: {destination_id}")));
+        assert!(compact.ends_with("priority: 3)"));
+    }
+
+    #[test]
+    fn test_split_batch_outcome_with_failed_middle_op() {
+        let ops: Vec<QueueOperation> = (0..3)
+            .map(|priority| Box::new(MockPendingOperation::new(priority)) as QueueOperation)
+            .collect();
+        let delivered_ids = [ops[2].id(), ops[0].id()];
+
+        let results = split_batch_outcome(&ops, &delivered_ids);
+        let labels: Vec<_> = results.iter().map(|r| r.outcome_label()).collect();
+        assert_eq!(labels, vec!["confirm", "reprepare", "confirm"]);
+
+        assert!(split_batch_outcome(&ops, &[])
+            .iter()
+            .all(|r| matches!(r, PendingOperationResult::Reprepare)));
+    }
+
+    #[test]
+    fn test_non_batchable_op_is_never_batched() {
+        let ops = || -> Vec<QueueOperation> {
+            (0..4)
+                .map(|priority| {
+                    let mut op = MockPendingOperation::new(priority);
+                    op.cost_estimate = Some(U256::from(10));
+                    op.is_batchable = priority != 1;
+                    Box::new(op) as QueueOperation
+                })
+                .collect()
+        };
+        let priorities =
+            |ops: &[QueueOperation]| ops.iter().map(|op| op.priority()).collect::<Vec<_>>();
+
+        let (batchable, standalone) = partition_batchable(ops());
+        assert_eq!(priorities(&batchable), vec![0, 2, 3]);
+        assert_eq!(priorities(&standalone), vec![1]);
+
+        let batches = split_batch_by_gas_limit(ops(), U256::from(100));
+        let batches: Vec<_> = batches.iter().map(|batch| priorities(batch)).collect();
+        assert_eq!(batches, vec![vec![1], vec![0, 2, 3]]);
+    }
+
+    #[test]
+    fn test_sort_preserving_nonce() {
+        let next_attempt_after = Some(Instant::now() + Duration::from_secs(10));
+        let mut later_nonce = MockPendingOperation::new(5);
+        later_nonce.next_attempt_after = next_attempt_after;
+        let mut earlier_nonce = MockPendingOperation::new(3);
+        earlier_nonce.next_attempt_after = next_attempt_after;
+        let mut ops: Vec<QueueOperation> = vec![Box::new(later_nonce), Box::new(earlier_nonce)];
+
+        // `Ord` can't tell them apart
+        assert_eq!(ops[0].cmp(&ops[1]), Ordering::Equal);
+
+        sort_preserving_nonce(&mut ops);
+        let priorities: Vec<_> = ops.iter().map(|op| op.priority()).collect();
+        assert_eq!(priorities, vec![3, 5]);
+    }
+
+    #[test]
+    fn test_sort_preserving_nonce_with_mixed_origins() {
+        let op = |origin: KnownHyperlaneDomain, nonce: u32, delay: Option<u64>| {
+            let mut op = MockPendingOperation::new(nonce);
+            op.origin_domain_id = origin as u32;
+            op.next_attempt_after = delay.map(|secs| Instant::now() + Duration::from_secs(secs));
+            Box::new(op) as QueueOperation
+        };
+        let ops = || {
+            vec![
+                op(KnownHyperlaneDomain::Ethereum, 5, None),
+                op(KnownHyperlaneDomain::Polygon, 1, Some(5)),
+                op(KnownHyperlaneDomain::Ethereum, 3, Some(10)),
+                op(KnownHyperlaneDomain::Polygon, 0, Some(20)),
+            ]
+        };
+        let keys = |ops: &[QueueOperation]| {
+            ops.iter()
+                .map(|op| (op.origin_domain_id(), op.priority()))
+                .collect::<Vec<_>>()
+        };
+
+        let mut sorted = ops();
+        sort_preserving_nonce(&mut sorted);
+        let sorted = keys(&sorted);
+        for origin in [
+            KnownHyperlaneDomain::Ethereum,
+            KnownHyperlaneDomain::Polygon,
+        ] {
+            let nonces: Vec<_> = sorted
+                .iter()
+                .filter(|(origin_id, _)| *origin_id == origin as u32)
+                .map(|(_, nonce)| *nonce)
+                .collect();
+            assert!(nonces.windows(2).all(|pair| pair[0] < pair[1]));
+        }
+
+        // the order doesn't depend on the order operations were drained in
+        let mut reversed = ops();
+        reversed.reverse();
+        sort_preserving_nonce(&mut reversed);
+        assert_eq!(keys(&reversed), sorted);
+    }
+
+    #[test]
+    fn test_tie_break_key_orders_cross_origin_operations() {
+        let op = |origin: KnownHyperlaneDomain, tie_break_key: u64| {
+            let mut op = MockPendingOperation::new(0);
+            op.origin_domain_id = origin as u32;
+            op.tie_break_key = Some(tie_break_key);
+            Box::new(op) as QueueOperation
+        };
+        let mut ops = vec![
+            op(KnownHyperlaneDomain::Optimism, 3),
+            op(KnownHyperlaneDomain::Ethereum, 1),
+            op(KnownHyperlaneDomain::Polygon, 2),
+        ];
+
+        ops.sort();
+        let keys: Vec<_> = ops.iter().map(|op| op.tie_break_key()).collect();
+        assert_eq!(keys, vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_skip_confirmation() {
+        let mut op = MockPendingOperation::new(0);
+        op.submissions_required = 2;
+        op.skip_confirmation = true;
+
+        block_on(async {
+            op.submit().await.unwrap();
+            assert_eq!(op.stage_after_submission(), Some(OperationStage::Submit));
+            // the second submission is terminal
+            op.submit().await.unwrap();
+            assert_eq!(op.stage_after_submission(), None);
+        });
+
+        op.submissions = 0;
+        op.skip_confirmation = false;
+        assert_eq!(op.stage_after_submission(), Some(OperationStage::Submit));
+        op.submissions = 2;
+        assert_eq!(op.stage_after_submission(), Some(OperationStage::Confirm));
+    }
+
+    #[test]
+    fn test_batch_is_atomic() {
+        assert!(!batch_is_atomic(&[]));
+
+        let mut batch: Vec<QueueOperation> = (0..3)
+            .map(|priority| Box::new(MockPendingOperation::new(priority)) as QueueOperation)
+            .collect();
+        assert!(!batch_is_atomic(&batch));
+
+        let mut atomic = MockPendingOperation::new(3);
+        atomic.requires_atomic_batch = true;
+        batch.push(Box::new(atomic));
+        assert!(batch_is_atomic(&batch));
+    }
+
+    #[test]
+    fn test_stale_cost_estimate() {
+        let max_age = Duration::from_secs(60);
+        let mut op = MockPendingOperation::new(0);
+        // no age means the estimate never goes stale
+        assert!(!op.is_cost_estimate_stale(max_age));
+
+        op.cost_estimate_age = Some(Duration::from_secs(30));
+        assert!(!op.is_cost_estimate_stale(max_age));
+
+        op.cost_estimate_age = Some(Duration::from_secs(61));
+        assert!(op.is_cost_estimate_stale(max_age));
+    }
+
+    #[test]
+    fn test_as_audit_json() {
+        use serde_json::json;
+
+        let cases = [
+            (
+                PendingOperationResult::Success,
+                json!({"outcome": "success"}),
+            ),
+            (
+                PendingOperationResult::NotReady,
+                json!({"outcome": "not_ready"}),
+            ),
+            (
+                PendingOperationResult::Reprepare,
+                json!({"outcome": "reprepare"}),
+            ),
+            (PendingOperationResult::Drop, json!({"outcome": "drop"})),
+            (
+                PendingOperationResult::Confirm,
+                json!({"outcome": "confirm"}),
+            ),
+            (
+                PendingOperationResult::DeadLetter("out of retries".to_owned()),
+                json!({"outcome": "dead_letter", "reason": "out of retries"}),
+            ),
+            (
+                PendingOperationResult::ConfirmAfter(3),
+                json!({"outcome": "confirm_after", "blocks": 3}),
+            ),
+            (
+                PendingOperationResult::ReprepareNow("dependency delivered".to_owned()),
+                json!({"outcome": "reprepare_now", "reason": "dependency delivered"}),
+            ),
+            (
+                PendingOperationResult::Reschedule(Instant::now() - Duration::from_secs(1)),
+                json!({"outcome": "reschedule", "after_ms": 0}),
+            ),
+        ];
+        for (result, expected) in cases {
+            assert_eq!(result.as_audit_json(), expected);
+        }
+
+        let at = Instant::now() + Duration::from_secs(60);
+        let audit = PendingOperationResult::Reschedule(at).as_audit_json();
+        assert_eq!(audit["outcome"], "reschedule");
+        let after_ms = audit["after_ms"].as_u64().unwrap();
+        assert!((59_000..=60_000).contains(&after_ms));
+    }
+
+    #[test]
+    fn test_is_terminal() {
+        let cases = [
+            (PendingOperationResult::Success, false),
+            (PendingOperationResult::NotReady, false),
+            (PendingOperationResult::Reprepare, false),
+            (PendingOperationResult::Drop, true),
+            (PendingOperationResult::Confirm, false),
+            (PendingOperationResult::Reschedule(Instant::now()), false),
+            (
+                PendingOperationResult::DeadLetter("out of retries".to_owned()),
+                true,
+            ),
+            (PendingOperationResult::ConfirmAfter(3), false),
+            (
+                PendingOperationResult::ReprepareNow("dependency delivered".to_owned()),
+                false,
+            ),
+        ];
+        for (result, terminal) in cases {
+            assert_eq!(result.is_terminal(), terminal, "{result:?}");
+        }
+    }
+
+    #[test]
+    fn test_retain_active() {
+        let fresh = MockPendingOperation::new(1);
+        let mut succeeded = MockPendingOperation::new(2);
+        succeeded.record_stage_result(OperationStage::Prepare, PendingOperationResult::Success);
+        let mut dropped = MockPendingOperation::new(3);
+        dropped.record_stage_result(OperationStage::Submit, PendingOperationResult::Drop);
+        let mut dead_lettered = MockPendingOperation::new(4);
+        dead_lettered.record_stage_result(
+            OperationStage::Confirm,
+            PendingOperationResult::DeadLetter("out of retries".to_owned()),
+        );
+        let (fresh_id, succeeded_id) = (fresh.id, succeeded.id);
+
+        let mut ops: Vec<QueueOperation> = vec![
+            Box::new(fresh),
+            Box::new(dropped),
+            Box::new(succeeded),
+            Box::new(dead_lettered),
+        ];
+        retain_active(&mut ops);
+        let ids: Vec<H256> = ops.iter().map(|op| op.id()).collect();
+        assert_eq!(ids, vec![fresh_id, succeeded_id]);
+    }
+
+    #[test]
+    fn test_reschedule_serializes_to_millis() {
+        let at = Instant::now() + Duration::from_secs(3600);
+
+        // the instant survives serialization to millisecond precision
+        let json = serde_json::to_string(&PendingOperationResult::Reschedule(at)).unwrap();
+        let PendingOperationResult::Reschedule(parsed) = serde_json::from_str(&json).unwrap()
+        else {
+            panic!("expected a reschedule result");
+        };
+        let difference = if parsed > at {
+            parsed - at
+        } else {
+            at - parsed
+        };
+        assert!(difference < Duration::from_millis(10));
+    }
+
+    #[test]
+    fn test_requeue() {
+        let delay = Duration::from_secs(600);
+        let mut ops: Vec<QueueOperation> = (0..3)
+            .map(|priority| Box::new(MockPendingOperation::new(priority)) as QueueOperation)
+            .collect();
+
+        let before = Instant::now();
+        requeue_matching(&mut ops, |op| op.priority() == 1, delay);
+        assert!(ops[0].next_attempt_after().is_none());
+        assert!(ops[1].next_attempt_after().unwrap() >= before + delay);
+        assert!(ops[2].next_attempt_after().is_none());
+
+        requeue_all(&mut ops, delay);
+        assert!(ops
+            .iter()
+            .all(|op| op.next_attempt_after().unwrap() >= before + delay));
+    }
+
+    #[test]
+    fn test_filter_operations() {
+        let op = |destination: KnownHyperlaneDomain, retries: u32, kind: OperationKind| {
+            let mut op = MockPendingOperation::new(0);
+            op.destination_domain = destination.into();
+            op.retries = retries;
+            op.kind = kind;
+            Box::new(op) as QueueOperation
+        };
+        let ops = vec![
+            op(
+                KnownHyperlaneDomain::Arbitrum,
+                2,
+                OperationKind::MessageDelivery,
+            ),
+            op(
+                KnownHyperlaneDomain::Arbitrum,
+                0,
+                OperationKind::MessageDelivery,
+            ),
+            op(
+                KnownHyperlaneDomain::Optimism,
+                1,
+                OperationKind::MessageDelivery,
+            ),
+            op(KnownHyperlaneDomain::Arbitrum, 3, OperationKind::GasPayment),
+        ];
+        let ids = |filtered: Vec<&QueueOperation>| -> Vec<H256> {
+            filtered.into_iter().map(|op| op.id()).collect()
+        };
+
+        let to_arbitrum = by_destination(KnownHyperlaneDomain::Arbitrum.into());
+        assert_eq!(filter_operations(&ops, &to_arbitrum).len(), 3);
+
+        // all operations retrying to Arbitrum
+        let retrying_to_arbitrum = |op: &dyn PendingOperation| to_arbitrum(op) && is_retrying(op);
+        assert_eq!(
+            ids(filter_operations(&ops, retrying_to_arbitrum)),
+            vec![ops[0].id(), ops[3].id()]
+        );
+
+        let deliveries = by_kind(OperationKind::MessageDelivery);
+        assert_eq!(
+            ids(filter_operations(&ops, |op| retrying_to_arbitrum(op) && deliveries(op))),
+            vec![ops[0].id()]
+        );
+        assert_eq!(
+            filter_operations(&ops, by_origin(KnownHyperlaneDomain::Ethereum as u32)).len(),
+            4
+        );
+    }
+
+    #[test]
+    fn test_drain_ready() {
+        let now = Instant::now();
+        let op_ready_at = |priority, after: Option<Instant>| -> QueueOperation {
+            let mut op = MockPendingOperation::new(priority);
+            op.next_attempt_after = after;
+            Box::new(op)
+        };
+        let ops = vec![
+            op_ready_at(0, Some(now + Duration::from_secs(10))),
+            op_ready_at(1, None),
+            op_ready_at(2, Some(now - Duration::from_secs(10))),
+            op_ready_at(3, Some(now + Duration::from_secs(5))),
+            op_ready_at(4, Some(now)),
+        ];
+
+        let (ready, pending) = drain_ready(ops, now);
+        let priorities =
+            |ops: &[QueueOperation]| ops.iter().map(|op| op.priority()).collect::<Vec<_>>();
+        assert_eq!(priorities(&ready), vec![1, 2, 4]);
+        assert_eq!(priorities(&pending), vec![3, 0]);
+    }
+
+    #[test]
+    fn test_self_check_sampler() {
+        let mut sampler = SelfCheckSampler::default();
+        let mut misconfigured = MockPendingOperation::new(0);
+        misconfigured.self_check_error = Some("signer not configured");
+
+        let result = block_on(sampler.check(&misconfigured)).unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "signer not configured");
+        // every operation type is only checked once
+        assert!(block_on(sampler.check(&misconfigured)).is_none());
+        assert!(block_on(sampler.check(&MockPendingOperation::new(1))).is_none());
+
+        let mut gas_payment = MockPendingOperation::new(2);
+        gas_payment.kind = OperationKind::GasPayment;
+        assert!(matches!(
+            block_on(sampler.check(&gas_payment)),
+            Some(Ok(()))
+        ));
+    }
+
+    #[test]
+    fn test_detect_nonce_conflicts() {
+        let op_from = |origin, nonce| {
+            let mut op = MockPendingOperation::new(nonce);
+            op.origin_domain_id = origin;
+            op
+        };
+        let stale = op_from(1, 7);
+        let fresh = op_from(1, 7);
+        let conflict = (stale.id, fresh.id);
+        let mut gas_payment = op_from(1, 7);
+        gas_payment.kind = OperationKind::GasPayment;
+        let ops: Vec<QueueOperation> = vec![
+            Box::new(stale),
+            // same nonce from another origin
+            Box::new(op_from(2, 7)),
+            // same origin, another nonce
+            Box::new(op_from(1, 8)),
+            Box::new(gas_payment),
+            Box::new(fresh),
+        ];
+        assert_eq!(detect_nonce_conflicts(&ops), vec![conflict]);
+        assert!(detect_nonce_conflicts(&ops[..4]).is_empty());
+    }
+
+    /// Records every event as a string.
+    #[derive(Debug, Default)]
+    struct RecordingObserver(Mutex<Vec<String>>);
+
+    impl OperationObserver for RecordingObserver {
+        fn on_prepare(&self, _id: H256, result: &PendingOperationResult) {
+            let event = format!("prepare: {}", result.outcome_label());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_submit(&self, _id: H256, outcome: Option<&TxOutcome>) {
+            let event = format!("submit: {}", outcome.is_some());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_confirm(&self, _id: H256, result: &PendingOperationResult) {
+            let event = format!("confirm: {}", result.outcome_label());
+            self.0.lock().unwrap().push(event);
+        }
+
+        fn on_drop(&self, _id: H256, stage: OperationStage, reason: &str) {
+            let event = format!("drop: {stage:?} {reason}");
+            self.0.lock().unwrap().push(event);
+        }
+    }
+
+    #[test]
+    fn test_operation_observers() {
+        let first = Arc::new(RecordingObserver::default());
+        let second = Arc::new(RecordingObserver::default());
+        let mut observers = OperationObservers::default();
+        observers.register(first.clone());
+        observers.register(Arc::new(NoopOperationObserver));
+        observers.register(second.clone());
+
+        let id = H256::random();
+        observers.on_prepare(id, &PendingOperationResult::Success);
+        let outcome = TxOutcome {
+            transaction_id: H512::random(),
+            executed: true,
+            gas_used: U256::zero(),
+            gas_price: FixedPointNumber::zero(),
+        };
+        observers.on_submit(id, Some(&outcome));
+        observers.on_confirm(id, &PendingOperationResult::NotReady);
+        observers.on_confirm(id, &PendingOperationResult::Success);
+        observers.on_drop(H256::random(), OperationStage::Submit, "dropped");
+
+        let expected = vec![
+            "prepare: success",
+            "submit: true",
+            "confirm: not_ready",
+            "confirm: success",
+            "drop: Submit dropped",
+        ];
+        assert_eq!(*first.0.lock().unwrap(), expected);
+        assert_eq!(*second.0.lock().unwrap(), expected);
+    }
+
+    #[test]
+    fn test_prepare_gate() {
+        let gate = MockPendingOperation::new(0);
+        let mut op = MockPendingOperation::new(1);
+        assert!(matches!(
+            block_on(check_prepare_gate(&op)),
+            PendingOperationResult::Success
+        ));
+
+        op.prepare_gate = Some(gate.id);
+        for _ in 0..3 {
+            assert!(matches!(
+                block_on(check_prepare_gate(&op)),
+                PendingOperationResult::NotReady
+            ));
+        }
+        // other operations being delivered doesn't resolve the gate
+        op.delivered_operations.insert(H256::random());
+        assert!(matches!(
+            block_on(check_prepare_gate(&op)),
+            PendingOperationResult::NotReady
+        ));
+
+        op.delivered_operations.insert(gate.id);
+        assert!(matches!(
+            block_on(check_prepare_gate(&op)),
+            PendingOperationResult::Success
+        ));
+    }
+
+    #[test]
+    fn test_in_flight_limiter() {
+        let ethereum: HyperlaneDomain = KnownHyperlaneDomain::Ethereum.into();
+        let arbitrum: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let limiter = InFlightLimiter::new(2).with_limit(arbitrum.clone(), 1);
+        let ids: Vec<_> = (0..3).map(|_| H256::random()).collect();
+
+        assert!(limiter.try_acquire(&ethereum, ids[0]));
+        assert!(limiter.try_acquire(&ethereum, ids[1]));
+        // acquiring a held slot again doesn't take another one
+        assert!(limiter.try_acquire(&ethereum, ids[1]));
+        assert_eq!(limiter.in_flight(&ethereum), 2);
+        assert!(!limiter.try_acquire(&ethereum, ids[2]));
+
+        // other destinations have their own slots
+        assert!(limiter.try_acquire(&arbitrum, ids[2]));
+        assert!(!limiter.try_acquire(&arbitrum, ids[0]));
+
+        // releasing a slot that isn't held doesn't free one
+        limiter.release(&ethereum, ids[2]);
+        assert!(!limiter.try_acquire(&ethereum, ids[2]));
+        limiter.release(&ethereum, ids[0]);
+        assert!(limiter.try_acquire(&ethereum, ids[2]));
+        assert_eq!(limiter.in_flight(&ethereum), 2);
+    }
+
+    #[test]
+    fn test_round_robin_drain() {
+        let op_from = |origin, priority| -> QueueOperation {
+            let mut op = MockPendingOperation::new(priority);
+            op.origin_domain_id = origin;
+            Box::new(op)
+        };
+        let now = Instant::now();
+        let mut ops: BinaryHeap<QueueOperation> = (0..100).map(|i| op_from(1, i)).collect();
+        ops.push(op_from(2, 500));
+        let mut not_ready = op_from(3, 0);
+        not_ready.set_next_attempt_at(now + Duration::from_secs(60));
+        ops.push(not_ready);
+
+        let drained = round_robin_drain(&mut ops, 3, now);
+        let drained: Vec<_> = drained
+            .iter()
+            .map(|op| (op.origin_domain_id(), op.priority()))
+            .collect();
+        // the flood from origin 1 doesn't starve the single op from origin 2,
+        // which origin goes first depends on the random ids
+        let expected = if drained[0].0 == 1 {
+            vec![(1, 0), (2, 500), (1, 1), (1, 2)]
+        } else {
+            vec![(2, 500), (1, 0), (1, 1), (1, 2)]
+        };
+        assert_eq!(drained, expected);
+        assert_eq!(ops.len(), 98);
+        assert_eq!(
+            ops.iter().filter(|op| op.origin_domain_id() == 3).count(),
+            1
+        );
+
+        // the next cycle carries on where this one left off
+        let drained = round_robin_drain(&mut ops, 3, now);
+        let priorities: Vec<_> = drained.iter().map(|op| op.priority()).collect();
+        assert_eq!(priorities, vec![3, 4, 5]);
+
+        // and drains the operation from origin 3 once it is due
+        let drained = round_robin_drain(&mut ops, 3, now + Duration::from_secs(60));
+        assert!(drained.iter().any(|op| op.origin_domain_id() == 3));
+    }
+
+    #[test]
+    fn test_round_robin_drain_keeps_nonce_order_of_retries() {
+        let now = Instant::now();
+        let retried = |nonce, retry_in| -> QueueOperation {
+            let mut op = MockPendingOperation::new(nonce);
+            op.set_next_attempt_at(now + retry_in);
+            Box::new(op)
+        };
+        // the higher nonce is due to be retried first
+        let mut ops: BinaryHeap<QueueOperation> = [
+            retried(1, Duration::from_secs(20)),
+            retried(2, Duration::from_secs(10)),
+        ]
+        .into_iter()
+        .collect();
+
+        let drained = round_robin_drain(&mut ops, 3, now + Duration::from_secs(30));
+        let nonces: Vec<_> = drained.iter().map(|op| op.priority()).collect();
+        assert_eq!(nonces, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_round_robin_drain_empty() {
+        let mut ops = BinaryHeap::new();
+        assert!(round_robin_drain(&mut ops, 3, Instant::now()).is_empty());
+        assert!(round_robin_drain(&mut ops, 0, Instant::now()).is_empty());
+    }
+
+    #[test]
+    fn test_drain_ready_empty() {
+        let (ready, pending) = drain_ready(vec![], Instant::now());
+        assert!(ready.is_empty());
+        assert!(pending.is_empty());
+    }
+
+    #[test]
+    fn test_normalized_app_context() {
+        let mut op = MockPendingOperation::new(0);
+        assert_eq!(op.normalized_app_context(), "unknown");
+
+        op.app_context = AppContext::from_str_lossy("  Warp-Route USDC\n");
+        assert_eq!(op.normalized_app_context(), "warp-route_usdc");
+        assert_eq!(op.get_operation_labels().1, "warp-route_usdc");
+    }
+
+    #[test]
+    fn test_app_context() {
+        for valid in ["helloworld", "usdc/ethereum-arbitrum", "warp_route.v2"] {
+            assert_eq!(AppContext::new(valid).unwrap().as_str(), valid);
+        }
+        let max_len = "x".repeat(MAX_APP_CONTEXT_LEN);
+        assert_eq!(
+            AppContext::new(max_len.clone()).unwrap().to_string(),
+            max_len
+        );
+
+        assert_eq!(AppContext::new(""), Err(InvalidAppContext::Empty));
+        assert_eq!(
+            AppContext::new("x".repeat(MAX_APP_CONTEXT_LEN + 1)),
+            Err(InvalidAppContext::TooLong)
+        );
+        assert_eq!(
+            AppContext::new("Warp"),
+            Err(InvalidAppContext::InvalidCharacter('W'))
+        );
+        assert_eq!(
+            AppContext::new("warp route"),
+            Err(InvalidAppContext::InvalidCharacter(' '))
+        );
+    }
+
+    #[test]
+    fn test_app_context_from_str_lossy() {
+        assert_eq!(AppContext::from_str_lossy(""), None);
+        assert_eq!(AppContext::from_str_lossy("   \n"), None);
+        assert_eq!(
+            AppContext::from_str_lossy("helloworld"),
+            Some(AppContext::new("helloworld").unwrap())
+        );
+        assert_eq!(
+            AppContext::from_str_lossy("  Warp Route: USDC\n")
+                .unwrap()
+                .as_str(),
+            "warp_route__usdc"
+        );
+        assert_eq!(
+            AppContext::from_str_lossy("Ünïcode").unwrap().as_str(),
+            "_n_code"
+        );
+        assert_eq!(
+            AppContext::from_str_lossy(&"X".repeat(MAX_APP_CONTEXT_LEN * 2))
+                .unwrap()
+                .as_str(),
+            "x".repeat(MAX_APP_CONTEXT_LEN)
+        );
+    }
+
+    #[test]
+    fn test_operation_snapshot_round_trip() {
+        let mut op = MockPendingOperation::new(3);
+        op.cost_estimate = Some(U256::from(21_000));
+        op.next_attempt_after = Some(Instant::now() + Duration::from_secs(60));
+
+        let snapshot = op.to_snapshot();
+        assert_eq!(snapshot.id, op.id);
+        assert_eq!(snapshot.priority, 3);
+        assert_eq!(
+            snapshot.destination_domain_id,
+            KnownHyperlaneDomain::Arbitrum as u32
+        );
+        let now_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64;
+        assert!(snapshot.next_attempt_after_ms.unwrap() > now_ms);
+
+        let json = serde_json::to_string(&snapshot).unwrap();
+        let parsed: OperationSnapshot = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, snapshot);
+    }
+
+    #[test]
+    fn test_attempt_history_is_bounded() {
+        let mut op = MockPendingOperation::new(0);
+        for attempt in 0..MAX_ATTEMPT_HISTORY + 2 {
+            let reason = if attempt % 2 == 0 {
+                "building metadata"
+            } else {
+                "gas payment requirement not met"
+            };
+            op.push_attempt(OperationStage::Prepare, format!("{attempt}: {reason}"));
+        }
+
+        let history = op.attempt_history();
+        assert_eq!(history.len(), MAX_ATTEMPT_HISTORY);
+        // the two oldest attempts were forgotten
+        assert_eq!(history[0].2, "2: building metadata");
+        assert_eq!(
+            history.last().unwrap().2,
+            format!(
+                "{}: gas payment requirement not met",
+                MAX_ATTEMPT_HISTORY + 1
+            )
+        );
+        assert!(history.windows(2).all(|pair| pair[0].0 <= pair[1].0));
+
+        let snapshot = op.to_snapshot();
+        assert_eq!(snapshot.attempt_history.len(), MAX_ATTEMPT_HISTORY);
+        assert_eq!(snapshot.attempt_history[0].1, history[0].2);
+    }
+
+    #[test]
+    fn test_operator_tags_in_snapshot() {
+        let mut op = MockPendingOperation::new(0);
+        op.set_operator_tag("customer".to_owned(), "acme".to_owned());
+        op.set_operator_tag("ticket".to_owned(), "x".repeat(MAX_OPERATOR_TAG_LEN + 10));
+        op.set_operator_tag("customer".to_owned(), "globex".to_owned());
+
+        let snapshot = op.to_snapshot();
+        assert_eq!(snapshot.operator_tags.len(), 2);
+        assert_eq!(snapshot.operator_tags["customer"], "globex");
+        assert_eq!(snapshot.operator_tags["ticket"].len(), MAX_OPERATOR_TAG_LEN);
+
+        // new tags are rejected once the limit is reached, but existing ones can still change
+        for i in 0..MAX_OPERATOR_TAGS {
+            op.set_operator_tag(format!("tag-{i}"), String::new());
+        }
+        assert_eq!(op.operator_tags().len(), MAX_OPERATOR_TAGS);
+        assert!(!op
+            .operator_tags()
+            .contains_key(&format!("tag-{}", MAX_OPERATOR_TAGS - 1)));
+        op.set_operator_tag("customer".to_owned(), "initech".to_owned());
+        assert_eq!(op.to_snapshot().operator_tags["customer"], "initech");
+    }
+
+    #[test]
+    fn test_oscillation_score() {
+        // prepared, then reverted on submission, over and over
+        let mut oscillating = MockPendingOperation::new(0);
+        for attempt in 0..10 {
+            if attempt % 2 == 0 {
+                oscillating.push_attempt(OperationStage::Submit, "transaction reverted".to_owned());
+            } else {
+                oscillating.push_attempt(
+                    OperationStage::Confirm,
+                    "transaction reverted or was reorged".to_owned(),
+                );
+            }
+        }
+        assert_eq!(oscillating.oscillation_score(), 1.);
+
+        // metadata and gas payments flapping while preparing never got the
+        // operation any further, however much the reasons alternate
+        let mut flapping = MockPendingOperation::new(0);
+        for attempt in 0..10 {
+            let reason = if attempt % 2 == 0 {
+                "could not fetch metadata"
+            } else {
+                "gas payment requirement not met"
+            };
+            flapping.push_attempt(OperationStage::Prepare, reason.to_owned());
+        }
+        assert_eq!(flapping.oscillation_score(), 0.);
+
+        let mut mixed = MockPendingOperation::new(0);
+        for _ in 0..3 {
+            mixed.push_attempt(
+                OperationStage::Prepare,
+                "could not fetch metadata".to_owned(),
+            );
+        }
+        mixed.push_attempt(OperationStage::Submit, "simulation reverted".to_owned());
+        assert_eq!(mixed.oscillation_score(), 0.25);
+
+        let mut short = MockPendingOperation::new(0);
+        short.push_attempt(OperationStage::Submit, "a".to_owned());
+        short.push_attempt(OperationStage::Submit, "b".to_owned());
+        assert_eq!(short.oscillation_score(), 0.);
+    }
+
+    #[test]
+    fn test_state_eq() {
+        let mut queued = MockPendingOperation::new(0);
+        queued.next_attempt_after = Some(Instant::now());
+        let mut prepared = MockPendingOperation::new(0);
+        prepared.id = queued.id;
+        prepared.next_attempt_after = queued.next_attempt_after;
+        assert!(queued.state_eq(&prepared));
+
+        // timings alone don't make for a different state
+        prepared.record_stage_timing(OperationStage::Prepare, Duration::from_millis(10));
+        assert!(queued.state_eq(&prepared));
+
+        prepared.record_stage_result(OperationStage::Prepare, PendingOperationResult::Success);
+        assert!(!queued.state_eq(&prepared));
+        let queued: QueueOperation = Box::new(queued);
+        let prepared: QueueOperation = Box::new(prepared);
+        assert!(queued.eq(&prepared));
+    }
+
+    #[test]
+    fn test_correlation_id() {
+        let mut op = MockPendingOperation::new(0);
+        assert_eq!(op.correlation_id(), None);
+        assert_eq!(op.to_snapshot().correlation_id, None);
+
+        let correlation_id = H256::random();
+        op.set_correlation_id(correlation_id);
+        assert_eq!(op.correlation_id(), Some(correlation_id));
+        assert_eq!(op.to_snapshot().correlation_id, Some(correlation_id));
+    }
+
+    #[test]
+    fn test_operation_kind() {
+        let op: QueueOperation = Box::new(MockPendingOperation::new(0));
+        assert_eq!(op.kind(), OperationKind::MessageDelivery);
+        assert_eq!(op.kind().to_string(), "message_delivery");
+        assert_eq!(OperationKind::Custom("rebalance").to_string(), "rebalance");
+    }
+
+    #[test]
+    fn test_log_verbosity() {
+        let op: QueueOperation = Box::new(MockPendingOperation::new(0));
+        assert_eq!(op.log_verbosity(), LogVerbosity::Normal);
+
+        let mut op = MockPendingOperation::new(0);
+        op.log_verbosity = LogVerbosity::Verbose;
+        assert_eq!(op.log_verbosity(), LogVerbosity::Verbose);
+    }
+
+    #[test]
+    fn test_stage_timings() {
+        let mut op = MockPendingOperation::new(0);
+        assert_eq!(op.stage_timings(), StageTimings::default());
+
+        op.record_stage_timing(OperationStage::Prepare, Duration::from_millis(10));
+        op.record_stage_timing(OperationStage::Submit, Duration::from_millis(200));
+        op.record_stage_timing(OperationStage::Confirm, Duration::from_millis(30));
+        // only the last call to a stage is kept
+        op.record_stage_timing(OperationStage::Prepare, Duration::from_millis(15));
+
+        let expected = StageTimings {
+            prepare: Some(Duration::from_millis(15)),
+            submit: Some(Duration::from_millis(200)),
+            confirm: Some(Duration::from_millis(30)),
+        };
+        assert_eq!(op.stage_timings(), expected);
+        assert_eq!(op.to_snapshot().stage_timings, expected);
+    }
+
+    #[test]
+    fn test_replay_event() {
+        let op = MockPendingOperation::new(3);
+        let event = op.replay_event(OperationStage::Confirm, &PendingOperationResult::Reprepare);
+        assert_eq!(
+            event["operation_id"],
+            serde_json::to_value(op.id()).unwrap()
+        );
+        assert_eq!(event["stage"], "confirm");
+        assert_eq!(event["result"], "reprepare");
+        assert_eq!(event["priority"], 3);
+    }
+
+    #[test]
+    fn test_recompute_priority() {
+        let mut op = MockPendingOperation::new(0);
+        op.recompute_priority(42);
+        assert_eq!(op.priority(), 42);
+    }
+
+    #[test]
+    fn test_deliveries_sort_ahead_of_gas_payments() {
+        let mut delivery = MockPendingOperation::new(0);
+        let mut gas_payment = MockPendingOperation::new(0);
+        gas_payment.kind = OperationKind::GasPayment;
+        let mut custom = MockPendingOperation::new(0);
+        custom.kind = OperationKind::Custom("rebalance");
+        for op in [&mut delivery, &mut gas_payment, &mut custom] {
+            op.recompute_priority(7);
+        }
+        assert_eq!(delivery.priority(), 7);
+
+        let (delivery, gas_payment, custom): (QueueOperation, QueueOperation, QueueOperation) =
+            (Box::new(delivery), Box::new(gas_payment), Box::new(custom));
+        assert_eq!(delivery.cmp(&gas_payment), Ordering::Less);
+        assert_eq!(gas_payment.cmp(&custom), Ordering::Less);
+
+        // even a very long queue of deliveries doesn't spill into gas payments
+        assert!(
+            default_priority_for_kind(OperationKind::MessageDelivery, u32::MAX)
+                < default_priority_for_kind(OperationKind::GasPayment, 0)
+        );
+    }
+
+    #[test]
+    fn test_aged_operation_outranks_newer_one() {
+        let now = Instant::now();
+        let mut old = MockPendingOperation::new(10);
+        old.created_at = Some(now);
+        let mut new = MockPendingOperation::new(5);
+        new.created_at = Some(now);
+        assert!(new.effective_priority(now) < old.effective_priority(now));
+
+        // once the older op has waited long enough it is boosted ahead
+        let later = now + PRIORITY_BOOST_INTERVAL * 5;
+        new.created_at = Some(later);
+        assert_eq!(old.effective_priority(later), new.effective_priority(later));
+        let even_later = now + PRIORITY_BOOST_INTERVAL * 6;
+        new.created_at = Some(even_later);
+        assert!(old.effective_priority(even_later) < new.effective_priority(even_later));
+
+        // and the ordering of operations from different origins reflects the
+        // boost they had when they were queued
+        let queued = |origin: KnownHyperlaneDomain, nonce: u32, age_in_intervals: u32| {
+            let mut op = MockPendingOperation::new(nonce);
+            op.origin_domain_id = origin as u32;
+            op.created_at = now.checked_sub(PRIORITY_BOOST_INTERVAL * age_in_intervals);
+            op.set_queued_priority(op.effective_priority(now));
+            Box::new(op) as QueueOperation
+        };
+        let old = queued(KnownHyperlaneDomain::Polygon, 10, 20);
+        let new = queued(KnownHyperlaneDomain::Ethereum, 5, 0);
+        assert_eq!(old.cmp(&new), Ordering::Less);
+
+        // but never reorders nonces from the same origin
+        let old = queued(KnownHyperlaneDomain::Ethereum, 10, 20);
+        assert_eq!(old.cmp(&new), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_rank_is_fixed_while_queued() {
+        let now = Instant::now();
+        let mut op = MockPendingOperation::new(10);
+        op.origin_domain_id = KnownHyperlaneDomain::Polygon as u32;
+        op.created_at = Some(now);
+        op.set_queued_priority(op.effective_priority(now));
+        let mut other = MockPendingOperation::new(5);
+        other.set_queued_priority(5);
+        let (op, other): (QueueOperation, QueueOperation) = (Box::new(op), Box::new(other));
+        let queued_order = op.cmp(&other);
+
+        // ageing doesn't change the order of already queued operations, which
+        // would break the heap invariant of the queue
+        assert!(op.effective_priority(now + PRIORITY_BOOST_INTERVAL * 20) < 5);
+        assert_eq!(op.cmp(&other), queued_order);
+    }
+
+    #[test]
+    fn test_past_soft_deadline_is_boosted_not_dropped() {
+        let now = Instant::now();
+        let mut op = MockPendingOperation::new(10);
+        op.soft_deadline = Some(now + Duration::from_secs(60));
+        op.hard_deadline = Some(now + Duration::from_secs(120));
+        assert_eq!(op.effective_priority(now), 10);
+
+        let past_soft_deadline = now + Duration::from_secs(90);
+        assert_eq!(op.effective_priority(past_soft_deadline), 0);
+        assert!(!op.is_expired(past_soft_deadline));
+    }
+
+    #[test]
+    fn test_past_hard_deadline_is_expired() {
+        let now = Instant::now();
+        let mut op = MockPendingOperation::new(10);
+        assert!(!op.is_expired(now));
+
+        op.hard_deadline = Some(now + Duration::from_secs(120));
+        assert!(!op.is_expired(now));
+        assert!(op.is_expired(now + Duration::from_secs(120)));
+    }
+
+    #[test]
+    fn test_run_lifecycle() {
+        let mut op = MockPendingOperation::new(0);
+        op.reorg_depth_required = 3;
+        let trace = block_on(run_lifecycle(&mut op));
+        assert_eq!(
+            trace,
+            vec![
+                (OperationStage::Prepare, PendingOperationResult::Success),
+                (OperationStage::Submit, PendingOperationResult::Confirm),
+                (OperationStage::Confirm, PendingOperationResult::NotReady),
+                (OperationStage::Confirm, PendingOperationResult::NotReady),
+                (OperationStage::Confirm, PendingOperationResult::Success),
+            ]
+        );
+        assert!(op.submission_outcome.is_some());
+    }
+
+    #[test]
+    fn test_sim_clock_drives_scheduling() {
+        let mut clock = SimClock::default();
+        let start = clock.now();
+        let policy = FixedRetryPolicy {
+            delay: Duration::from_secs(10),
+            max_retries: 3,
+        };
+        let mut soon = MockPendingOperation::new(0);
+        soon.next_attempt_after = policy.next_attempt_at(1, start);
+        let mut later = MockPendingOperation::new(0);
+        later.next_attempt_after = Some(start + Duration::from_secs(30));
+        later.hard_deadline = Some(start + Duration::from_secs(60));
+        let (soon_id, later_id) = (soon.id, later.id);
+        let mut pending: Vec<QueueOperation> = vec![Box::new(soon), Box::new(later)];
+
+        let mut ready_at = vec![];
+        for _ in 0..6 {
+            clock.advance(Duration::from_secs(5));
+            let (ready, still_pending) = drain_ready(pending, clock.now());
+            pending = still_pending;
+            for op in ready {
+                ready_at.push((op.id(), clock.now() - start));
+                assert!(!op.is_expired(clock.now()));
+            }
+        }
+        assert!(pending.is_empty());
+        assert_eq!(
+            ready_at,
+            vec![
+                (soon_id, Duration::from_secs(10)),
+                (later_id, Duration::from_secs(30)),
+            ]
+        );
+        assert_eq!(policy.next_attempt_at(4, clock.now()), None);
+    }
+
+    #[test]
+    fn test_circuit_breaker_trips_after_failures() {
+        let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let other_domain: HyperlaneDomain = KnownHyperlaneDomain::Ethereum.into();
+        let mut breaker = DomainCircuitBreaker::new(3, Duration::from_secs(60));
+        let now = Instant::now();
+
+        breaker.record_failure(&domain, now);
+        breaker.record_failure(&domain, now);
+        assert!(!breaker.is_open(&domain, now));
+        breaker.record_failure(&domain, now);
+        assert!(breaker.is_open(&domain, now));
+        assert!(!breaker.is_open(&other_domain, now));
+
+        // a success in between resets the count
+        breaker.record_success(&other_domain);
+        breaker.record_failure(&other_domain, now);
+        breaker.record_failure(&other_domain, now);
+        breaker.record_success(&other_domain);
+        breaker.record_failure(&other_domain, now);
+        assert!(!breaker.is_open(&other_domain, now));
+    }
+
+    #[test]
+    fn test_circuit_breaker_recovers_after_probe() {
+        let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let cooldown = Duration::from_secs(60);
+        let mut breaker = DomainCircuitBreaker::new(1, cooldown);
+        let now = Instant::now();
+        breaker.record_failure(&domain, now);
+        assert!(breaker.is_open(&domain, now));
+
+        // after the cooldown a probe is let through, and failing it trips the breaker again
+        let after_cooldown = now + cooldown;
+        assert!(!breaker.is_open(&domain, after_cooldown));
+        assert!(breaker.is_half_open(&domain));
+        breaker.record_failure(&domain, after_cooldown);
+        assert!(breaker.is_open(&domain, after_cooldown));
+
+        // a successful probe closes it
+        let after_second_cooldown = after_cooldown + cooldown;
+        assert!(!breaker.is_open(&domain, after_second_cooldown));
+        breaker.record_success(&domain);
+        assert!(!breaker.is_open(&domain, after_second_cooldown));
+    }
+
+    #[test]
+    fn test_circuit_breaker_lets_a_single_probe_through() {
+        let domain: HyperlaneDomain = KnownHyperlaneDomain::Arbitrum.into();
+        let cooldown = Duration::from_secs(60);
+        let mut breaker = DomainCircuitBreaker::new(1, cooldown);
+        let now = Instant::now();
+        breaker.record_failure(&domain, now);
+
+        let after_cooldown = now + cooldown;
+        assert!(!breaker.is_open(&domain, after_cooldown));
+        // the probe is in flight, so everything else is held back
+        assert!(breaker.is_open(&domain, after_cooldown));
+        assert!(breaker.is_open(&domain, after_cooldown + cooldown / 2));
+        assert!(breaker.is_half_open(&domain));
+
+        // a probe that is never resolved doesn't hold the breaker forever
+        let probe_timed_out = after_cooldown + cooldown;
+        assert!(!breaker.is_open(&domain, probe_timed_out));
+        assert!(breaker.is_open(&domain, probe_timed_out));
+
+        breaker.record_success(&domain);
+        assert!(!breaker.is_open(&domain, probe_timed_out));
+        assert!(!breaker.is_half_open(&domain));
+    }
+
+    #[test]
+    fn test_fixed_retry_policy() {
+        let policy = FixedRetryPolicy {
+            delay: Duration::from_secs(10),
+            max_retries: 3,
+        };
+        for retries in 1..=3 {
+            assert_eq!(policy.next_delay(retries), Some(Duration::from_secs(10)));
+        }
+        assert_eq!(policy.next_delay(4), None);
+    }
+
+    #[test]
+    fn test_exponential_retry_policy() {
+        let policy = ExponentialRetryPolicy {
+            base_delay: Duration::from_secs(10),
+            max_delay: Duration::from_secs(60),
+            max_retries: 40,
+        };
+        let delays: Vec<_> = (1..=5)
+            .map(|retries| policy.next_delay(retries).unwrap().as_secs())
+            .collect();
+        assert_eq!(delays, vec![10, 20, 40, 60, 60]);
+        // doesn't overflow for large retry counts
+        assert_eq!(policy.next_delay(40), Some(Duration::from_secs(60)));
+        assert_eq!(policy.next_delay(41), None);
+    }
+
+    #[test]
+    fn test_describe() {
+        let mut op = MockPendingOperation::new(42);
+        assert_eq!(
+            op.describe(),
+            "Operation #42 from ethereum to arbitrum, ready"
+        );
+
+        op.set_next_attempt_after(Duration::from_secs(120));
+        let description = op.describe();
+        assert!(description.contains("arbitrum"));
+        assert!(description.contains("next attempt in"));
+    }
+
+    #[test]
+    fn test_is_reorg_safe() {
+        let op = MockPendingOperation::new(0);
+        assert!(!op.is_reorg_safe(0));
+        assert!(op.is_reorg_safe(1));
+    }
+
+    #[test]
+    fn test_unknown_depth_is_only_safe_without_extra_depth() {
+        let mut op = MockPendingOperation::new(0);
+        assert_eq!(op.current_confirmation_depth(), None);
+        assert!(op.has_reorg_safe_depth());
+
+        op.reorg_depth_required = 2;
+        assert!(!op.has_reorg_safe_depth());
+    }
 }
@@ -0,0 +1,1004 @@
+#[cfg(feature = "async")]
+use std::sync::Arc;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, MutexGuard},
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+use sha3::{digest::Update, Digest, Keccak256};
+use tracing::warn;
+
+use super::pending_operation::instant_to_unix_ms;
+use crate::{
+    operation_state_hash, submission_result, DeadLetterStore, Decode, Encode,
+    HyperlaneProtocolError, OperationObserver, OperationStage, PendingOperation,
+    PendingOperationResult, QueueOperation, TxOutcome, H256, U256,
+};
+
+/// The scheduling state of an operation, encoded compactly for handing it
+/// between processes over IPC, e.g. from a scheduler to a submitter. See
+/// `PendingOperation::to_serializable`.
+///
+/// This doesn't carry everything needed to run the operation. Turning it back
+/// into a live `dyn PendingOperation` requires a factory for each kind of
+/// operation that can rebuild the rest, e.g. by reloading the message from
+/// the origin DB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerializableOperation {
+    /// The operation id
+    pub id: H256,
+    /// The domain the operation originates from
+    pub origin_domain_id: u32,
+    /// The domain the operation will take place on
+    pub destination_domain_id: u32,
+    /// The operation priority, lower is more important
+    pub priority: u32,
+    /// How many times the operation has been retried
+    pub retry_count: u32,
+    /// The estimated cost of submitting the operation, if known
+    pub cost_estimate: Option<U256>,
+    /// See `PendingOperation::correlation_id`
+    pub correlation_id: Option<H256>,
+}
+
+impl Encode for SerializableOperation {
+    fn write_to<W>(&self, writer: &mut W) -> std::io::Result<usize>
+    where
+        W: std::io::Write,
+    {
+        let mut written = 0;
+        written += self.id.write_to(writer)?;
+        written += self.origin_domain_id.write_to(writer)?;
+        written += self.destination_domain_id.write_to(writer)?;
+        written += self.priority.write_to(writer)?;
+        written += self.retry_count.write_to(writer)?;
+        written += self.cost_estimate.write_to(writer)?;
+        written += self.correlation_id.write_to(writer)?;
+        Ok(written)
+    }
+}
+
+impl Decode for SerializableOperation {
+    fn read_from<R>(reader: &mut R) -> Result<Self, HyperlaneProtocolError>
+    where
+        R: std::io::Read,
+    {
+        Ok(Self {
+            id: H256::read_from(reader)?,
+            origin_domain_id: u32::read_from(reader)?,
+            destination_domain_id: u32::read_from(reader)?,
+            priority: u32::read_from(reader)?,
+            retry_count: u32::read_from(reader)?,
+            cost_estimate: Option::<U256>::read_from(reader)?,
+            correlation_id: Option::<H256>::read_from(reader)?,
+        })
+    }
+}
+
+/// The version of the format written by `export_queue`.
+pub const QUEUE_EXPORT_VERSION: u8 = 1;
+
+/// Rebuilds live operations from their `SerializableOperation`, e.g. by
+/// reloading the message from the origin DB, when importing a queue with
+/// `import_queue`.
+pub trait OperationFactory {
+    /// Rebuild `operation`, whose kind is labelled `kind` (see the `Display`
+    /// impl of `OperationKind`). `None` if this factory can't build
+    /// operations of that kind.
+    fn reconstruct(&self, kind: &str, operation: &SerializableOperation) -> Option<QueueOperation>;
+}
+
+/// Export `ops`, e.g. a whole pending queue, so that another relayer process
+/// can take them over with `import_queue`, like in blue/green deployments.
+///
+/// The export is a `QUEUE_EXPORT_VERSION` byte and the number of operations,
+/// followed by each operation's kind label and `SerializableOperation`, and
+/// ends with the keccak256 checksum of everything before it.
+pub fn export_queue(ops: &[QueueOperation]) -> Vec<u8> {
+    let mut bytes = vec![QUEUE_EXPORT_VERSION];
+    bytes.extend_from_slice(&(ops.len() as u32).to_be_bytes());
+    for op in ops {
+        let kind = op.kind().to_string();
+        bytes.extend_from_slice(&(kind.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(kind.as_bytes());
+        bytes.extend_from_slice(&op.to_serializable().to_vec());
+    }
+    let checksum = Keccak256::new().chain(&bytes).finalize();
+    bytes.extend_from_slice(checksum.as_slice());
+    bytes
+}
+
+/// Import the operations exported by `export_queue`, rebuilding each of them
+/// with `factory`. Either every operation is imported or, if the export is
+/// corrupt, of an unknown version or holds an operation `factory` can't
+/// rebuild, none are.
+pub fn import_queue(
+    bytes: &[u8],
+    factory: &dyn OperationFactory,
+) -> Result<Vec<QueueOperation>, HyperlaneProtocolError> {
+    let invalid = |msg: String| {
+        HyperlaneProtocolError::IoError(std::io::Error::new(std::io::ErrorKind::InvalidData, msg))
+    };
+    if bytes.len() < 1 + 32 {
+        return Err(invalid("queue export is truncated".to_owned()));
+    }
+    let (body, checksum) = bytes.split_at(bytes.len() - 32);
+    if Keccak256::new().chain(body).finalize().as_slice() != checksum {
+        return Err(invalid("queue export checksum mismatch".to_owned()));
+    }
+    let (version, mut reader) = body.split_at(1);
+    if version[0] != QUEUE_EXPORT_VERSION {
+        return Err(invalid(format!(
+            "unsupported queue export version {}",
+            version[0]
+        )));
+    }
+
+    let count = u32::read_from(&mut reader)?;
+    let mut ops = vec![];
+    for _ in 0..count {
+        let mut kind = vec![0; u32::read_from(&mut reader)? as usize];
+        reader.read_exact(&mut kind)?;
+        let kind = String::from_utf8(kind)
+            .map_err(|_| invalid("queue export operation kind is not utf-8".to_owned()))?;
+        let operation = SerializableOperation::read_from(&mut reader)?;
+        let op = factory.reconstruct(&kind, &operation).ok_or_else(|| {
+            invalid(format!(
+                "can't rebuild operation {:?} of kind {kind}",
+                operation.id
+            ))
+        })?;
+        ops.push(op);
+    }
+    if !reader.is_empty() {
+        return Err(invalid("queue export has trailing bytes".to_owned()));
+    }
+    Ok(ops)
+}
+
+/// The record of an operation that was given up on, see
+/// `PendingOperationResult::DeadLetter`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DeadLetterRecord {
+    /// The operation id
+    pub id: H256,
+    /// The domain the operation originates from
+    pub origin_domain_id: u32,
+    /// The domain the operation would have taken place on
+    pub destination_domain_id: u32,
+    /// Why the operation was given up on
+    pub reason: String,
+    /// How many times the operation was retried
+    pub retry_count: u32,
+    /// The error from the most recent failed attempt, if any
+    pub last_error: Option<String>,
+}
+
+// Records are only ever read back by operators, so they are stored as JSON
+// rather than with a compact canonical encoding.
+impl Encode for DeadLetterRecord {
+    fn write_to<W>(&self, writer: &mut W) -> std::io::Result<usize>
+    where
+        W: std::io::Write,
+    {
+        let json = serde_json::to_vec(self)?;
+        writer.write_all(&json)?;
+        Ok(json.len())
+    }
+}
+
+impl Decode for DeadLetterRecord {
+    fn read_from<R>(reader: &mut R) -> Result<Self, HyperlaneProtocolError>
+    where
+        R: std::io::Read,
+    {
+        Ok(serde_json::from_reader(reader).map_err(std::io::Error::from)?)
+    }
+}
+
+/// Build the dead-letter record of `op` and write it to `store`, e.g. when
+/// handling `PendingOperationResult::DeadLetter`. The record is returned even
+/// if it could not be stored, so that the caller can still log it.
+pub fn dead_letter(
+    op: &dyn PendingOperation,
+    reason: String,
+    store: &dyn DeadLetterStore,
+) -> DeadLetterRecord {
+    let record = op.to_dead_letter(reason);
+    if let Err(err) = store.store_dead_letter(&record) {
+        warn!(?err, ?record, "Failed to store dead-letter record");
+    }
+    record
+}
+
+/// An entry of a `WriteAheadLog`, recording the state of an operation as it
+/// moves on to its next stage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteAheadLogEntry {
+    /// The stage the operation is queued for, or `None` if it has left the
+    /// submitter, e.g. because it was confirmed or dropped
+    pub stage: Option<OperationStage>,
+    /// The state of the operation
+    pub operation: SerializableOperation,
+}
+
+impl Encode for WriteAheadLogEntry {
+    fn write_to<W>(&self, writer: &mut W) -> std::io::Result<usize>
+    where
+        W: std::io::Write,
+    {
+        let stage: u8 = match self.stage {
+            None => 0,
+            Some(OperationStage::Prepare) => 1,
+            Some(OperationStage::Submit) => 2,
+            Some(OperationStage::Confirm) => 3,
+        };
+        writer.write_all(&[stage])?;
+        Ok(1 + self.operation.write_to(writer)?)
+    }
+}
+
+impl Decode for WriteAheadLogEntry {
+    fn read_from<R>(reader: &mut R) -> Result<Self, HyperlaneProtocolError>
+    where
+        R: std::io::Read,
+    {
+        let mut stage = [0; 1];
+        reader.read_exact(&mut stage)?;
+        let stage = match stage[0] {
+            0 => None,
+            1 => Some(OperationStage::Prepare),
+            2 => Some(OperationStage::Submit),
+            3 => Some(OperationStage::Confirm),
+            _ => {
+                return Err(HyperlaneProtocolError::IoError(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    "decoded operation stage invalid",
+                )))
+            }
+        };
+        Ok(Self {
+            stage,
+            operation: SerializableOperation::read_from(reader)?,
+        })
+    }
+}
+
+/// An append-only file of operation state transitions, for recovering the
+/// operations that were in flight after a crash.
+///
+/// Entries are length-prefixed and synced to disk before `append` returns,
+/// so a crash can at most leave the last entry partially written, which
+/// `replay` ignores. `replay` also compacts the log down to the entries it
+/// returns, so the log only grows with the transitions of a single run.
+#[derive(Debug)]
+pub struct WriteAheadLog {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The stage each operation was last recorded as queued for, so that
+    /// `record` only writes actual transitions
+    stages: Mutex<HashMap<H256, OperationStage>>,
+}
+
+impl WriteAheadLog {
+    /// Open the log at `path`, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self {
+            path: path.to_owned(),
+            file: Mutex::new(Self::open_file(path)?),
+            stages: Default::default(),
+        })
+    }
+
+    /// Durably append `entry` to the log.
+    pub fn append(&self, entry: &WriteAheadLogEntry) -> std::io::Result<()> {
+        let record = Self::to_record(entry)?;
+        let mut file = self.lock();
+        file.write_all(&record)?;
+        file.sync_data()
+    }
+
+    /// Append the state of `op` as it moves on to `stage`, see
+    /// `WriteAheadLogEntry::stage`, unless it was already recorded as queued
+    /// for `stage`, e.g. because it wasn't ready and was pushed back onto the
+    /// same queue. The write happens on a blocking thread, so it doesn't hold
+    /// up the async runtime while syncing to disk. Errors are logged rather
+    /// than returned, so that a failing disk doesn't stop operations from
+    /// being processed.
+    #[cfg(feature = "async")]
+    pub async fn record(
+        self: &Arc<Self>,
+        op: &dyn PendingOperation,
+        stage: Option<OperationStage>,
+    ) {
+        if !self.is_transition(op.id(), stage) {
+            return;
+        }
+        let entry = WriteAheadLogEntry {
+            stage,
+            operation: op.to_serializable(),
+        };
+        let log = self.clone();
+        let appended = tokio::task::spawn_blocking(move || {
+            let appended = log.append(&entry);
+            (appended, entry)
+        })
+        .await;
+        match appended {
+            Ok((Err(err), entry)) => {
+                warn!(?err, ?entry, "Failed to append to the write-ahead log");
+            }
+            Err(err) => warn!(?err, "Failed to append to the write-ahead log"),
+            Ok((Ok(()), _)) => {}
+        }
+    }
+
+    /// Whether moving operation `id` on to `stage` changes what the log holds
+    /// for it, remembering `stage` if so.
+    fn is_transition(&self, id: H256, stage: Option<OperationStage>) -> bool {
+        let mut stages = self.stages.lock().expect("write-ahead log lock poisoned");
+        match stage {
+            Some(stage) => stages.insert(id, stage) != Some(stage),
+            // operations that were never recorded don't need to be forgotten
+            None => stages.remove(&id).is_some(),
+        }
+    }
+
+    /// The latest entry of every operation that hasn't left the submitter,
+    /// by operation id. Reading stops at the first entry that is incomplete
+    /// or can't be decoded, e.g. one that was being written during a crash.
+    /// The log is then rewritten to hold only the returned entries.
+    pub fn replay(&self) -> std::io::Result<HashMap<H256, WriteAheadLogEntry>> {
+        let mut file = self.lock();
+        let mut contents = vec![];
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut contents)?;
+        let latest = Self::latest_entries(&contents);
+        *file = self.compact(&latest)?;
+        *self.stages.lock().expect("write-ahead log lock poisoned") = latest
+            .iter()
+            .filter_map(|(id, entry)| Some((*id, entry.stage?)))
+            .collect();
+        Ok(latest)
+    }
+
+    fn latest_entries(contents: &[u8]) -> HashMap<H256, WriteAheadLogEntry> {
+        let mut latest = HashMap::new();
+        let mut remaining = contents;
+        while !remaining.is_empty() {
+            if remaining.len() < 4 {
+                warn!("Ignoring partially written write-ahead log entry");
+                break;
+            }
+            let (len, rest) = remaining.split_at(4);
+            let len = u32::from_be_bytes(len.try_into().expect("slice is 4 bytes long")) as usize;
+            if rest.len() < len {
+                warn!("Ignoring partially written write-ahead log entry");
+                break;
+            }
+            let (mut payload, rest) = rest.split_at(len);
+            match WriteAheadLogEntry::read_from(&mut payload) {
+                Ok(entry) if entry.stage.is_some() => {
+                    latest.insert(entry.operation.id, entry);
+                }
+                Ok(entry) => {
+                    latest.remove(&entry.operation.id);
+                }
+                Err(err) => {
+                    warn!(
+                        ?err,
+                        "Failed to decode write-ahead log entry, ignoring the rest of the log"
+                    );
+                    break;
+                }
+            }
+            remaining = rest;
+        }
+        latest
+    }
+
+    /// Durably replace the log with one holding only `entries`, returning
+    /// the file to append to from then on. The new log is written next to
+    /// the old one and moved over it, so a crash midway leaves the old log
+    /// intact.
+    fn compact(&self, entries: &HashMap<H256, WriteAheadLogEntry>) -> std::io::Result<File> {
+        let compacted_path = self.path.with_extension("wal.compacted");
+        {
+            let mut compacted = File::create(&compacted_path)?;
+            for entry in entries.values() {
+                compacted.write_all(&Self::to_record(entry)?)?;
+            }
+            compacted.sync_all()?;
+        }
+        std::fs::rename(&compacted_path, &self.path)?;
+        Self::open_file(&self.path)
+    }
+
+    fn open_file(path: &Path) -> std::io::Result<File> {
+        OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)
+    }
+
+    /// `entry` prefixed by its length.
+    fn to_record(entry: &WriteAheadLogEntry) -> std::io::Result<Vec<u8>> {
+        let payload = entry.to_vec();
+        let len = u32::try_from(payload.len()).map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "write-ahead log entry too large",
+            )
+        })?;
+        let mut record = len.to_be_bytes().to_vec();
+        record.extend(payload);
+        Ok(record)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, File> {
+        self.file.lock().expect("write-ahead log lock poisoned")
+    }
+}
+
+/// A single submitter decision, as recorded in a [`ReplayLog`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayLogEntry {
+    /// Position of this entry in the log
+    pub sequence: u64,
+    /// Caller-supplied timestamp, in milliseconds since the unix epoch
+    pub timestamp_ms: u64,
+    /// The operation the decision was made for
+    pub operation_id: H256,
+    /// The lifecycle stage that was run
+    pub stage: OperationStage,
+    /// `operation_state_hash` of the operation before the stage was run
+    pub input_state_hash: H256,
+    /// What the stage returned
+    pub result: PendingOperationResult,
+}
+
+/// An append-only log of submitter decisions, which can be written out and
+/// replayed offline against the same decision logic when debugging an
+/// incident.
+///
+/// Timestamps are passed in by the caller rather than read from the clock,
+/// so that the same sequence of decisions always produces the same log.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayLog {
+    entries: Vec<ReplayLogEntry>,
+}
+
+impl ReplayLog {
+    /// Append a decision to the log. `input_state_hash` must be taken with
+    /// `operation_state_hash` before the stage is run.
+    pub fn record(
+        &mut self,
+        timestamp_ms: u64,
+        operation_id: H256,
+        stage: OperationStage,
+        input_state_hash: H256,
+        result: PendingOperationResult,
+    ) {
+        self.entries.push(ReplayLogEntry {
+            sequence: self.entries.len() as u64,
+            timestamp_ms,
+            operation_id,
+            stage,
+            input_state_hash,
+            result,
+        });
+    }
+
+    /// The recorded decisions, in the order they were made
+    pub fn entries(&self) -> &[ReplayLogEntry] {
+        &self.entries
+    }
+
+    /// Serialize the log as one JSON object per line
+    pub fn to_json_lines(&self) -> serde_json::Result<String> {
+        let mut lines = String::new();
+        for entry in &self.entries {
+            lines.push_str(&serde_json::to_string(entry)?);
+            lines.push('\n');
+        }
+        Ok(lines)
+    }
+
+    /// Parse a log previously written with `to_json_lines`
+    pub fn from_json_lines(lines: &str) -> serde_json::Result<Self> {
+        let entries = lines
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(serde_json::from_str)
+            .collect::<serde_json::Result<Vec<_>>>()?;
+        Ok(Self { entries })
+    }
+}
+
+/// An `OperationObserver` that records every prepare, submit and confirm
+/// decision of a submitter in a `ReplayLog`, timestamped with the wall clock.
+///
+/// Submissions are recorded as `Success`, or `Reprepare` if the transaction
+/// reverted, and failed submissions as `NotReady`.
+#[derive(Debug, Default)]
+pub struct ReplayLogRecorder {
+    log: Mutex<ReplayLog>,
+    /// `operation_state_hash` of each operation when its current stage started
+    input_state_hashes: Mutex<HashMap<H256, H256>>,
+}
+
+impl ReplayLogRecorder {
+    /// The decisions recorded so far
+    pub fn replay_log(&self) -> ReplayLog {
+        self.log.lock().expect("replay log lock poisoned").clone()
+    }
+
+    fn record(&self, id: H256, stage: OperationStage, result: PendingOperationResult) {
+        let input_state_hash = self
+            .input_state_hashes
+            .lock()
+            .expect("replay log lock poisoned")
+            .remove(&id)
+            .unwrap_or_default();
+        self.log.lock().expect("replay log lock poisoned").record(
+            instant_to_unix_ms(Instant::now()),
+            id,
+            stage,
+            input_state_hash,
+            result,
+        );
+    }
+}
+
+impl OperationObserver for ReplayLogRecorder {
+    fn on_stage_start(&self, op: &dyn PendingOperation, _stage: OperationStage) {
+        self.input_state_hashes
+            .lock()
+            .expect("replay log lock poisoned")
+            .insert(op.id(), operation_state_hash(op));
+    }
+
+    fn on_prepare(&self, id: H256, result: &PendingOperationResult) {
+        self.record(id, OperationStage::Prepare, result.clone());
+    }
+
+    fn on_submit(&self, id: H256, outcome: Option<&TxOutcome>) {
+        self.record(id, OperationStage::Submit, submission_result(outcome));
+    }
+
+    fn on_confirm(&self, id: H256, result: &PendingOperationResult) {
+        self.record(id, OperationStage::Confirm, result.clone());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+    use crate::{
+        AppContext, ChainResult, HyperlaneDomain, HyperlaneMessage, KnownHyperlaneDomain,
+        OperationKind, TryBatchAs,
+    };
+
+    /// An operation that only holds the state that is persisted.
+    #[derive(Debug)]
+    struct MockOperation {
+        id: H256,
+        kind: OperationKind,
+        priority: u32,
+        origin_domain_id: u32,
+        destination_domain: HyperlaneDomain,
+        retries: u32,
+        cost_estimate: Option<U256>,
+        correlation_id: Option<H256>,
+    }
+
+    impl MockOperation {
+        fn new(priority: u32) -> Self {
+            Self {
+                id: H256::random(),
+                kind: OperationKind::MessageDelivery,
+                priority,
+                origin_domain_id: KnownHyperlaneDomain::Ethereum as u32,
+                destination_domain: KnownHyperlaneDomain::Arbitrum.into(),
+                retries: 0,
+                cost_estimate: None,
+                correlation_id: None,
+            }
+        }
+    }
+
+    impl TryBatchAs<HyperlaneMessage> for MockOperation {}
+
+    #[async_trait::async_trait]
+    impl PendingOperation for MockOperation {
+        fn id(&self) -> H256 {
+            self.id
+        }
+
+        fn kind(&self) -> OperationKind {
+            self.kind
+        }
+
+        fn priority(&self) -> u32 {
+            self.priority
+        }
+
+        fn origin_domain_id(&self) -> u32 {
+            self.origin_domain_id
+        }
+
+        fn destination_domain(&self) -> &HyperlaneDomain {
+            &self.destination_domain
+        }
+
+        fn app_context(&self) -> Option<AppContext> {
+            None
+        }
+
+        fn correlation_id(&self) -> Option<H256> {
+            self.correlation_id
+        }
+
+        async fn prepare(&mut self) -> PendingOperationResult {
+            unimplemented!()
+        }
+
+        async fn submit(&mut self) -> ChainResult<Option<TxOutcome>> {
+            unimplemented!()
+        }
+
+        fn set_submission_outcome(&mut self, _outcome: TxOutcome) {}
+
+        fn get_tx_cost_estimate(&self) -> Option<U256> {
+            self.cost_estimate
+        }
+
+        async fn confirm(&mut self) -> PendingOperationResult {
+            unimplemented!()
+        }
+
+        fn set_operation_outcome(
+            &mut self,
+            _submission_outcome: TxOutcome,
+            _submission_estimated_cost: U256,
+        ) {
+        }
+
+        fn next_attempt_after(&self) -> Option<Instant> {
+            None
+        }
+
+        fn set_next_attempt_after(&mut self, _delay: Duration) {}
+
+        fn retry_count(&self) -> u32 {
+            self.retries
+        }
+
+        fn reset_attempts(&mut self) {
+            self.retries = 0;
+        }
+
+        fn set_retries(&mut self, retries: u32) {
+            self.retries = retries;
+        }
+    }
+
+    /// Run `future` to completion on a fresh runtime.
+    #[cfg(feature = "async")]
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn wal_entry(op: &MockOperation, stage: Option<OperationStage>) -> WriteAheadLogEntry {
+        WriteAheadLogEntry {
+            stage,
+            operation: op.to_serializable(),
+        }
+    }
+
+    #[test]
+    fn test_write_ahead_log_append_and_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("submitter.wal");
+        let (a, b) = (MockOperation::new(1), MockOperation::new(2));
+        {
+            let wal = WriteAheadLog::open(&path).unwrap();
+            wal.append(&wal_entry(&a, Some(OperationStage::Prepare)))
+                .unwrap();
+            wal.append(&wal_entry(&b, Some(OperationStage::Prepare)))
+                .unwrap();
+        }
+
+        // entries survive reopening the log
+        let replayed = WriteAheadLog::open(&path).unwrap().replay().unwrap();
+        assert_eq!(replayed.len(), 2);
+        assert_eq!(
+            replayed[&a.id],
+            wal_entry(&a, Some(OperationStage::Prepare))
+        );
+        assert_eq!(
+            replayed[&b.id],
+            wal_entry(&b, Some(OperationStage::Prepare))
+        );
+    }
+
+    #[test]
+    fn test_write_ahead_log_replay_ignores_partial_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("submitter.wal");
+        let wal = WriteAheadLog::open(&path).unwrap();
+        let (a, b) = (MockOperation::new(1), MockOperation::new(2));
+        wal.append(&wal_entry(&a, Some(OperationStage::Submit)))
+            .unwrap();
+
+        // simulate a crash halfway through appending an entry
+        let payload = wal_entry(&b, Some(OperationStage::Submit)).to_vec();
+        let mut record = (payload.len() as u32).to_be_bytes().to_vec();
+        record.extend(&payload[..payload.len() / 2]);
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&record)
+            .unwrap();
+
+        let replayed = wal.replay().unwrap();
+        assert_eq!(replayed.len(), 1);
+        assert_eq!(replayed[&a.id], wal_entry(&a, Some(OperationStage::Submit)));
+    }
+
+    #[test]
+    fn test_write_ahead_log_keeps_latest_entry_per_operation() {
+        let dir = tempfile::tempdir().unwrap();
+        let wal = WriteAheadLog::open(&dir.path().join("submitter.wal")).unwrap();
+        let (mut a, b) = (MockOperation::new(1), MockOperation::new(2));
+        wal.append(&wal_entry(&a, Some(OperationStage::Prepare)))
+            .unwrap();
+        wal.append(&wal_entry(&b, Some(OperationStage::Prepare)))
+            .unwrap();
+        a.retries = 3;
+        wal.append(&wal_entry(&a, Some(OperationStage::Confirm)))
+            .unwrap();
+        // `b` was confirmed, so there is nothing to recover for it
+        wal.append(&wal_entry(&b, None)).unwrap();
+
+        let replayed = wal.replay().unwrap();
+        assert_eq!(replayed.len(), 1);
+        let entry = &replayed[&a.id];
+        assert_eq!(entry.stage, Some(OperationStage::Confirm));
+        assert_eq!(entry.operation.retry_count, 3);
+    }
+
+    #[cfg(feature = "async")]
+    #[test]
+    fn test_write_ahead_log_records_only_transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("submitter.wal");
+        let wal = Arc::new(WriteAheadLog::open(&path).unwrap());
+        let op = MockOperation::new(1);
+        let log_len = || std::fs::metadata(&path).unwrap().len();
+
+        block_on(wal.record(&op, Some(OperationStage::Prepare)));
+        let prepared_len = log_len();
+        assert!(prepared_len > 0);
+        // pushed back onto the prepare queue because it wasn't ready
+        block_on(wal.record(&op, Some(OperationStage::Prepare)));
+        assert_eq!(log_len(), prepared_len);
+
+        block_on(wal.record(&op, Some(OperationStage::Submit)));
+        block_on(wal.record(&op, None));
+        let finished_len = log_len();
+        assert!(finished_len > prepared_len);
+        block_on(wal.record(&op, None));
+        assert_eq!(log_len(), finished_len);
+        assert!(wal.replay().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_write_ahead_log_compacts_on_replay() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("submitter.wal");
+        let wal = WriteAheadLog::open(&path).unwrap();
+        let (a, b) = (MockOperation::new(1), MockOperation::new(2));
+        for stage in [
+            OperationStage::Prepare,
+            OperationStage::Submit,
+            OperationStage::Confirm,
+        ] {
+            wal.append(&wal_entry(&a, Some(stage))).unwrap();
+            wal.append(&wal_entry(&b, Some(stage))).unwrap();
+        }
+        wal.append(&wal_entry(&b, None)).unwrap();
+
+        let replayed = wal.replay().unwrap();
+        let entry = wal_entry(&a, Some(OperationStage::Confirm));
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            WriteAheadLog::to_record(&entry).unwrap().len() as u64
+        );
+
+        // the compacted log replays the same and can still be appended to
+        wal.append(&wal_entry(&b, Some(OperationStage::Prepare)))
+            .unwrap();
+        let reopened = WriteAheadLog::open(&path).unwrap().replay().unwrap();
+        assert_eq!(reopened.len(), 2);
+        assert_eq!(reopened[&a.id], replayed[&a.id]);
+    }
+
+    #[test]
+    fn test_replay_log_round_trips() {
+        let mut log = ReplayLog::default();
+        let id = H256::random();
+        log.record(
+            1_000,
+            id,
+            OperationStage::Prepare,
+            H256::random(),
+            PendingOperationResult::Success,
+        );
+        log.record(
+            1_010,
+            id,
+            OperationStage::Confirm,
+            H256::random(),
+            PendingOperationResult::ConfirmAfter(2),
+        );
+        assert_eq!(log.entries()[1].sequence, 1);
+
+        // the log round-trips and serializes deterministically
+        let lines = log.to_json_lines().unwrap();
+        assert_eq!(lines.lines().count(), 2);
+        let parsed = ReplayLog::from_json_lines(&lines).unwrap();
+        assert_eq!(parsed, log);
+        assert_eq!(parsed.to_json_lines().unwrap(), lines);
+    }
+
+    #[test]
+    fn test_serializable_operation_round_trip() {
+        let mut op = MockOperation::new(4);
+        op.retries = 2;
+        op.cost_estimate = Some(U256::from(21_000));
+        op.correlation_id = Some(H256::random());
+
+        let serializable = op.to_serializable();
+        let encoded = serializable.to_vec();
+        let decoded = SerializableOperation::read_from(&mut &encoded[..]).unwrap();
+        assert_eq!(decoded, serializable);
+        assert_eq!(decoded.id, op.id);
+        assert_eq!(decoded.retry_count, 2);
+
+        // unset optional fields take a single byte each
+        let bare = MockOperation::new(4).to_serializable();
+        assert_eq!(bare.to_vec().len(), encoded.len() - 32 - 32);
+        let decoded = SerializableOperation::read_from(&mut &bare.to_vec()[..]).unwrap();
+        assert_eq!(decoded, bare);
+    }
+
+    /// Rebuilds message deliveries as mock operations.
+    struct MockOperationFactory;
+
+    impl OperationFactory for MockOperationFactory {
+        fn reconstruct(
+            &self,
+            kind: &str,
+            operation: &SerializableOperation,
+        ) -> Option<QueueOperation> {
+            if kind != OperationKind::MessageDelivery.to_string() {
+                return None;
+            }
+            let mut op = MockOperation::new(operation.priority);
+            op.id = operation.id;
+            op.origin_domain_id = operation.origin_domain_id;
+            op.retries = operation.retry_count;
+            op.cost_estimate = operation.cost_estimate;
+            op.correlation_id = operation.correlation_id;
+            Some(Box::new(op))
+        }
+    }
+
+    #[test]
+    fn test_queue_export_round_trip() {
+        let ops: Vec<QueueOperation> = (0..3)
+            .map(|priority| {
+                let mut op = MockOperation::new(priority);
+                op.retries = priority * 2;
+                op.cost_estimate = Some(U256::from(priority * 1000));
+                Box::new(op) as QueueOperation
+            })
+            .collect();
+
+        let exported = export_queue(&ops);
+        assert_eq!(exported[0], QUEUE_EXPORT_VERSION);
+        let imported = import_queue(&exported, &MockOperationFactory).unwrap();
+        let serializable = |ops: &[QueueOperation]| {
+            ops.iter()
+                .map(|op| op.to_serializable())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(serializable(&imported), serializable(&ops));
+
+        let empty = export_queue(&[]);
+        assert!(import_queue(&empty, &MockOperationFactory)
+            .unwrap()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_queue_import_is_all_or_nothing() {
+        let mut gas_payment = MockOperation::new(1);
+        gas_payment.kind = OperationKind::GasPayment;
+        let ops: Vec<QueueOperation> = vec![Box::new(MockOperation::new(0)), Box::new(gas_payment)];
+        let exported = export_queue(&ops);
+
+        // the factory can't rebuild gas payments
+        let err = import_queue(&exported, &MockOperationFactory).unwrap_err();
+        assert!(err.to_string().contains("of kind gas_payment"));
+
+        let mut corrupted = export_queue(&ops[..1]);
+        corrupted[10] ^= 1;
+        let err = import_queue(&corrupted, &MockOperationFactory).unwrap_err();
+        assert_eq!(err.to_string(), "queue export checksum mismatch");
+
+        // a future version, with a valid checksum
+        let mut future = export_queue(&ops[..1]);
+        future.truncate(future.len() - 32);
+        future[0] = QUEUE_EXPORT_VERSION + 1;
+        let checksum = Keccak256::new().chain(&future).finalize();
+        future.extend_from_slice(checksum.as_slice());
+        let err = import_queue(&future, &MockOperationFactory).unwrap_err();
+        assert_eq!(err.to_string(), "unsupported queue export version 2");
+
+        assert!(import_queue(&[QUEUE_EXPORT_VERSION], &MockOperationFactory).is_err());
+    }
+
+    #[test]
+    fn test_dead_letter_record_round_trip() {
+        let mut op = MockOperation::new(0);
+        op.retries = 7;
+        let record = op.to_dead_letter("retries exhausted".to_owned());
+        assert_eq!(record.id, op.id);
+        assert_eq!(record.retry_count, 7);
+
+        let json = serde_json::to_string(&record).unwrap();
+        let parsed: DeadLetterRecord = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed, record);
+
+        let decoded = DeadLetterRecord::read_from(&mut record.to_vec().as_slice()).unwrap();
+        assert_eq!(decoded, record);
+    }
+
+    #[derive(Debug, Default)]
+    struct MockDeadLetterStore(std::sync::Mutex<Vec<DeadLetterRecord>>);
+
+    impl DeadLetterStore for MockDeadLetterStore {
+        fn store_dead_letter(&self, record: &DeadLetterRecord) -> eyre::Result<()> {
+            self.0.lock().unwrap().push(record.clone());
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dead_letter_on_retries_exhausted() {
+        let store = MockDeadLetterStore::default();
+        let mut op = MockOperation::new(0);
+        op.set_retries(3);
+
+        let record = dead_letter(&op, "retries exhausted".to_owned(), &store);
+
+        assert_eq!(*store.0.lock().unwrap(), vec![record.clone()]);
+        assert_eq!(record.reason, "retries exhausted");
+        assert_eq!(record.retry_count, 3);
+        assert_eq!(record.origin_domain_id, op.origin_domain_id);
+    }
+}